@@ -1,11 +1,20 @@
+#[cfg(not(test))]
 use ic_cdk::api::caller;
 use ic_principal::Principal;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use candid::{CandidType};
+use std::thread::LocalKey;
+#[cfg(not(test))]
+use std::time::Duration;
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use once_cell::sync::Lazy;
-use std::sync::Mutex; // Import Mutex for mutable access
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct User {
     user_id: Principal,
     username: String,
@@ -18,6 +27,8 @@ pub struct User {
     is_admin: bool,
     created_at: u64,
     last_active: u64,
+    followers_count: u64,
+    following_count: u64,
 }
 
 #[derive(CandidType, Clone)]
@@ -27,7 +38,7 @@ pub struct AdminStats {
     recent_registrations: u64, // Last 24 hours
 }
 
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Post {
     post_id: u64,
     author_id: Principal,
@@ -41,9 +52,23 @@ pub struct Post {
     mentions: Vec<Principal>,
     media_urls: Vec<String>,
     is_deleted: bool,
+    deleted_at: Option<u64>,
+    // Reactions per kind, sorted by kind. `likes` is the count of the "like" reaction.
+    reactions: Vec<ReactionCount>,
+    // Set on quote posts: the post being quoted
+    quoted_post_id: Option<u64>,
 }
 
+// A timeline entry: a post, or a post reposted by `reposted_by`
 #[derive(CandidType, Clone)]
+pub struct FeedEntry {
+    post: Post,
+    reposted_by: Option<Principal>,
+    // When the post was created or reposted
+    entry_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Comment {
     comment_id: u64,
     post_id: u64,
@@ -53,1339 +78,8566 @@ pub struct Comment {
     updated_at: Option<u64>,
     likes: u64,
     is_deleted: bool,
+    deleted_at: Option<u64>,
+    // The comment this one replies to, None for top-level comments
+    parent_id: Option<u64>,
+    // 0 for top-level comments, at most MAX_COMMENT_DEPTH
+    depth: u32,
+    // Replies that have not been deleted
+    reply_count: u64,
+    // Reactions per kind, sorted by kind. `likes` is the count of the "like" reaction.
+    reactions: Vec<ReactionCount>,
 }
 
-#[derive(CandidType, Clone)]
-pub struct Follow {
-    follower_id: Principal,
-    following_id: Principal,
-    created_at: u64,
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReactionCount {
+    kind: String,
+    count: u64,
 }
 
-#[derive(CandidType, Clone)]
-pub struct PostStats {
-    total_posts: u64,
-    recent_posts: u64, // Last 24 hours
-    total_likes: u64,
+// Something users can react to
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReactionTarget {
+    Post(u64),
+    Comment(u64),
 }
 
-// Use Lazy to initialize the USERS HashMap wrapped in a Mutex
-pub type Users = HashMap<Principal, User>;
-static USERS: Lazy<Mutex<Users>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Posts storage - using post_id as key
-pub type Posts = HashMap<u64, Post>;
-static POSTS: Lazy<Mutex<Posts>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Post ID counter for generating unique post IDs
-static POST_ID_COUNTER: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
-
-// User likes tracking - maps user_id to set of post_ids they liked
-static USER_LIKES: Lazy<Mutex<HashMap<Principal, HashSet<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Comments storage - using comment_id as key
-pub type Comments = HashMap<u64, Comment>;
-static COMMENTS: Lazy<Mutex<Comments>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Comment ID counter for generating unique comment IDs
-static COMMENT_ID_COUNTER: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
-
-// Comment likes tracking - maps user_id to set of comment_ids they liked
-static USER_COMMENT_LIKES: Lazy<Mutex<HashMap<Principal, HashSet<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Follows storage - maps follower_id to set of following_ids
-static FOLLOWS: Lazy<Mutex<HashMap<Principal, HashSet<Principal>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Post shares tracking - maps user_id to set of post_ids they shared
-static USER_SHARES: Lazy<Mutex<HashMap<Principal, HashSet<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Predefined admin principals - Add your admin principals here
-static PREDEFINED_ADMINS: Lazy<HashSet<Principal>> = Lazy::new(|| {
-    let mut admins = HashSet::new();
-    // Add your admin principals here - replace with actual admin principals
-    // Example: admins.insert(Principal::from_text("your-admin-principal-here").unwrap());
+// A user's reaction to an item, as exported
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Reaction {
+    target: ReactionTarget,
+    kind: String,
+}
 
-    // For development, you can add a test admin principal
-    // Uncomment and replace with your actual principal when you know it
-    // admins.insert(Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap());
+// Earlier content of an edited post or comment
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Revision {
+    content: String,
+    // When this content was written, and when an edit replaced it
+    written_at: u64,
+    replaced_at: u64,
+    edited_by: Principal,
+}
 
-    // Add the original Sarv29 principal as admin for development
-    if let Ok(admin_principal) = Principal::from_text("6qa2u-gctfq-jppnt-e2ai3-mp4ng-qotip-hidii-kascv-srdfq-htjfx-nqe") {
-        admins.insert(admin_principal);
-    }
+// Admin settings for editing posts and comments
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EditPolicy {
+    // Whether anyone can read revisions. Authors and admins always can.
+    revisions_public: bool,
+    // How long after creation posts and comments may be edited, by admins too. Unlimited if None.
+    edit_window_ns: Option<u64>,
+}
 
-    // Add the Internet Identity anchor 10000 principal as admin
-    if let Ok(admin_principal) = Principal::from_text("4xgob-2qimn-cbswc-yywe2-spgmi-yg7az-xbu3r-vbi3p-vyf7y-myipw-cae") {
-        admins.insert(admin_principal);
+impl Default for EditPolicy {
+    fn default() -> Self {
+        EditPolicy { revisions_public: true, edit_window_ns: None }
     }
+}
 
-    admins
-});
+// A post the user saved for later, visible to that user only
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Bookmark {
+    post_id: u64,
+    // Name of the bookmark collection, if the bookmark is filed in one
+    collection: Option<String>,
+    created_at: u64,
+}
 
-// Additional admins that can be promoted by existing admins
-static PROMOTED_ADMINS: Lazy<Mutex<HashSet<Principal>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+#[derive(CandidType, Clone)]
+pub struct BookmarkCollection {
+    name: String,
+    created_at: u64,
+    bookmark_count: u64,
+}
 
-// Helper function to get current timestamp in nanoseconds
-fn current_time() -> u64 {
-    ic_cdk::api::time()
+// A top-level comment with its first replies, see get_post_comment_threads
+#[derive(CandidType, Clone)]
+pub struct CommentThread {
+    comment: Comment,
+    replies: Vec<Comment>,
 }
 
-// Helper function to check if a principal is an admin
-fn is_admin(principal: &Principal) -> bool {
-    PREDEFINED_ADMINS.contains(principal) ||
-    PROMOTED_ADMINS.lock().unwrap().contains(principal)
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Follow {
+    follower_id: Principal,
+    following_id: Principal,
+    created_at: u64,
 }
 
-// Helper function to check if caller is admin
-fn require_admin() -> Result<(), String> {
-    let caller_principal = caller();
-    if is_admin(&caller_principal) {
-        Ok(())
-    } else {
-        Err("Access denied: Admin privileges required".to_string())
-    }
+// Who can see an optional profile field
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Followers,
+    OnlyMe,
 }
 
-// Helper function to generate next post ID
-fn next_post_id() -> u64 {
-    let mut counter = POST_ID_COUNTER.lock().unwrap();
-    *counter += 1;
-    *counter
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PrivacySettings {
+    email: Visibility,
+    location: Visibility,
+    website: Visibility,
 }
 
-// Helper function to check if user exists
-fn user_exists(user_id: &Principal) -> bool {
-    let users = USERS.lock().unwrap();
-    users.contains_key(user_id)
+// Email stays private unless the user opts in, the rest is public as it was before
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        PrivacySettings {
+            email: Visibility::OnlyMe,
+            location: Visibility::Public,
+            website: Visibility::Public,
+        }
+    }
 }
 
-#[ic_cdk::update]
-fn create_user(
-    username: String,
-    full_name: Option<String>,
-    email: Option<String>,
-    bio: Option<String>,
-    profile_pic: Option<String>,
-    location: Option<String>,
-    website: Option<String>,
-) -> String {
-    let user_id = caller();
-    let current_timestamp = current_time();
+// Which notifications a user receives
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NotificationSettings {
+    likes: bool,
+    comments: bool,
+    follows: bool,
+    mentions: bool,
+    shares: bool,
+    // Only notify of actions by accounts the user follows
+    only_from_following: bool,
+    // No notifications about these posts
+    muted_posts: Vec<u64>,
+}
 
-    // Check if user already exists
-    {
-        let users = USERS.lock().unwrap();
-        if users.contains_key(&user_id) {
-            return "User already exists".to_string();
+// Every kind of notification is on until the user opts out
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        NotificationSettings {
+            likes: true,
+            comments: true,
+            follows: true,
+            mentions: true,
+            shares: true,
+            only_from_following: false,
+            muted_posts: Vec::new(),
         }
     }
+}
 
-    let user = User {
-        user_id,
-        username,
-        full_name,
-        email,
-        bio,
-        profile_pic,
-        location,
-        website,
-        is_admin: is_admin(&user_id), // Check if this user is a predefined admin
-        created_at: current_timestamp,
-        last_active: current_timestamp,
-    };
-
-    // Lock the USERS Mutex to insert the user
-    let mut users = USERS.lock().unwrap();
-    users.insert(user_id, user);
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct UserSettings {
+    privacy: PrivacySettings,
+    notifications: NotificationSettings,
+}
 
-    "User created successfully".to_string()
+// Settings layout up to schema version 7, before notification preferences
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UserSettingsV7 {
+    privacy: PrivacySettings,
 }
 
-#[ic_cdk::query]
-fn get_user(user_id: Principal) -> Option<User> {
-    let users = USERS.lock().unwrap();
-    users.get(&user_id).cloned()
+impl From<UserSettingsV7> for UserSettings {
+    fn from(settings: UserSettingsV7) -> Self {
+        UserSettings {
+            privacy: settings.privacy,
+            notifications: NotificationSettings::default(),
+        }
+    }
 }
 
-#[ic_cdk::update]
-fn update_user(
+// What other users get to see of a profile. Fields hidden from the viewer by the
+// owner's privacy settings are left empty.
+#[derive(CandidType, Clone)]
+pub struct PublicProfile {
+    user_id: Principal,
+    username: String,
     full_name: Option<String>,
-    email: Option<String>,
     bio: Option<String>,
     profile_pic: Option<String>,
+    email: Option<String>,
     location: Option<String>,
     website: Option<String>,
-) -> String {
-    let user_id = caller();
-
-    // Lock the USERS Mutex to update the user information
-    let mut users = USERS.lock().unwrap();
-    if let Some(user) = users.get_mut(&user_id) {
-        if let Some(name) = full_name {
-            user.full_name = Some(name);
-        }
-        if let Some(email) = email {
-            user.email = Some(email);
-        }
-        if let Some(bio) = bio {
-            user.bio = Some(bio);
-        }
-        if let Some(pic) = profile_pic {
-            user.profile_pic = Some(pic);
-        }
-        if let Some(loc) = location {
-            user.location = Some(loc);
-        }
-        if let Some(web) = website {
-            user.website = Some(web);
-        }
-        // Update last active timestamp
-        user.last_active = current_time();
-        return "User updated successfully".to_string();
-    }
-
-    "User not found".to_string()
+    created_at: u64,
+    followers_count: u64,
+    following_count: u64,
 }
 
-#[ic_cdk::update]
-fn delete_user() -> String {
-    let user_id = caller();
-
-    // Lock the USERS Mutex to remove the user
-    let mut users = USERS.lock().unwrap();
-    if users.remove(&user_id).is_some() {
-        return "User deleted successfully".to_string();
-    }
-
-    "User not found".to_string()
+// Parts of a data export, delivered in this order
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ExportSection {
+    Profile,
+    Posts,
+    Comments,
+    LikedPosts,
+    LikedComments,
+    Shares,
+    Following,
+    Followers,
+    Conversations,
+    Messages,
+    Reactions,
+    Bookmarks,
 }
 
-#[ic_cdk::query]
-fn get_all_users() -> Vec<User> {
-    let users = USERS.lock().unwrap();
-    users.values().cloned().collect()
+// One chunk of a user's data export. Each chunk fills the fields of a single section;
+// records keep their IDs and timestamps so an archive can be replayed elsewhere.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DataExport {
+    format_version: u8,
+    user_id: Principal,
+    section: ExportSection,
+    profile: Option<User>,
+    settings: Option<UserSettings>,
+    username_history: Vec<UsernameChange>,
+    posts: Vec<Post>,
+    comments: Vec<Comment>,
+    liked_post_ids: Vec<u64>,
+    liked_comment_ids: Vec<u64>,
+    shared_post_ids: Vec<u64>,
+    following: Vec<Follow>,
+    followers: Vec<Follow>,
+    conversations: Vec<Conversation>,
+    // Messages the user sent, deleted ones included
+    messages: Vec<Message>,
+    // Reactions of every kind, likes included
+    reactions: Vec<Reaction>,
+    bookmarks: Vec<Bookmark>,
+    next_cursor: Option<String>,
 }
 
-#[ic_cdk::query]
-fn debug_user_count() -> u64 {
-    let users = USERS.lock().unwrap();
-    users.len() as u64
+// The caller's deleted posts and comments that can still be restored
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Trash {
+    posts: Vec<Post>,
+    comments: Vec<Comment>,
 }
 
-#[ic_cdk::query]
-fn debug_user_exists(user_id: Principal) -> bool {
-    let users = USERS.lock().unwrap();
-    users.contains_key(&user_id)
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NotificationKind {
+    Like,
+    Comment,
+    Follow,
+    Mention,
+    Share,
+    CommentLike,
 }
 
-// ============ POST FUNCTIONS ============
-
-#[ic_cdk::update]
-fn create_post(content: String, hashtags: Vec<String>, media_urls: Vec<String>) -> Result<u64, String> {
-    let author_id = caller();
-
-    // Check if user exists
-    if !user_exists(&author_id) {
-        return Err("User must be registered to create posts".to_string());
-    }
-
-    // Validate content
-    if content.trim().is_empty() {
-        return Err("Post content cannot be empty".to_string());
-    }
-
-    if content.len() > 2000 {
-        return Err("Post content too long (max 2000 characters)".to_string());
+impl NotificationKind {
+    fn from_index(index: u8) -> Self {
+        match index {
+            0 => NotificationKind::Like,
+            1 => NotificationKind::Comment,
+            2 => NotificationKind::Follow,
+            3 => NotificationKind::Mention,
+            4 => NotificationKind::Share,
+            5 => NotificationKind::CommentLike,
+            _ => panic!("Unknown notification kind {}", index),
+        }
     }
+}
 
-    // Extract mentions from content (@username)
-    let mentions = extract_mentions(&content);
+// Something another user did that concerns the recipient
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Notification {
+    notification_id: u64,
+    recipient_id: Principal,
+    actor_id: Principal,
+    kind: NotificationKind,
+    post_id: Option<u64>,
+    comment_id: Option<u64>,
+    created_at: u64,
+    read: bool,
+}
 
-    let post_id = next_post_id();
-    let current_timestamp = current_time();
+// Similar notifications shown as one entry, e.g. "Alice and 12 others liked your post"
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NotificationGroup {
+    kind: NotificationKind,
+    post_id: Option<u64>,
+    // Latest comment for comment, comment like and mention notifications
+    comment_id: Option<u64>,
+    // Most recent actors first, at most NOTIFICATION_GROUP_ACTORS of them
+    actors: Vec<Principal>,
+    // Distinct actors among the group's newest NOTIFICATION_GROUP_SCAN notifications
+    actor_count: u64,
+    // Newest first, at most NOTIFICATION_GROUP_SCAN of them
+    notification_ids: Vec<u64>,
+    latest_at: u64,
+    unread: bool,
+}
 
-    let post = Post {
-        post_id,
-        author_id,
-        content: content.trim().to_string(),
-        created_at: current_timestamp,
-        updated_at: None,
-        likes: 0,
-        comments_count: 0,
-        shares_count: 0,
-        hashtags,
-        mentions,
-        media_urls,
-        is_deleted: false,
-    };
+// A private conversation, either between two users or a group
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Conversation {
+    conversation_id: u64,
+    // Current members, in the order they joined
+    participants: Vec<Principal>,
+    created_at: u64,
+    last_activity_at: u64,
+    // Newest message that has not been deleted
+    last_message_id: Option<u64>,
+    is_group: bool,
+    // Groups only
+    title: Option<String>,
+    owner_id: Option<Principal>,
+}
 
-    // Store the post
-    let mut posts = POSTS.lock().unwrap();
-    posts.insert(post_id, post);
+// Conversation layout at schema version 8, before group conversations
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ConversationV8 {
+    conversation_id: u64,
+    participants: Vec<Principal>,
+    created_at: u64,
+    last_activity_at: u64,
+    last_message_id: Option<u64>,
+}
 
-    // Update user's last active timestamp
-    {
-        let mut users = USERS.lock().unwrap();
-        if let Some(user) = users.get_mut(&author_id) {
-            user.last_active = current_timestamp;
+impl From<ConversationV8> for Conversation {
+    fn from(conversation: ConversationV8) -> Self {
+        Conversation {
+            conversation_id: conversation.conversation_id,
+            participants: conversation.participants,
+            created_at: conversation.created_at,
+            last_activity_at: conversation.last_activity_at,
+            last_message_id: conversation.last_message_id,
+            is_group: false,
+            title: None,
+            owner_id: None,
         }
     }
+}
 
-    Ok(post_id)
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Message {
+    message_id: u64,
+    conversation_id: u64,
+    sender_id: Principal,
+    content: String,
+    created_at: u64,
+    is_deleted: bool,
 }
 
-#[ic_cdk::query]
-fn get_post(post_id: u64) -> Option<Post> {
-    let posts = POSTS.lock().unwrap();
-    posts.get(&post_id).filter(|post| !post.is_deleted).cloned()
+// How far a participant has read a conversation
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ReadReceipt {
+    user_id: Principal,
+    last_read_message_id: u64,
+    read_at: u64,
 }
 
-#[ic_cdk::query]
-fn get_all_posts() -> Vec<Post> {
-    let posts = POSTS.lock().unwrap();
-    let mut post_list: Vec<Post> = posts.values()
-        .filter(|post| !post.is_deleted)
-        .cloned()
-        .collect();
+// A conversation as listed in the inbox
+#[derive(CandidType, Clone)]
+pub struct ConversationSummary {
+    conversation: Conversation,
+    last_message: Option<Message>,
+    unread_count: u64,
+}
 
-    // Sort by creation date (most recent first)
-    post_list.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+// A problem found by the integrity checker
+#[derive(CandidType, Deserialize, Clone)]
+pub enum IntegrityIssue {
+    // A stored counter that does not match the set it summarizes
+    CounterMismatch { record: String, id: String, field: String, stored: u64, actual: u64 },
+    // A set or index entry pointing at a record that no longer exists
+    OrphanedEntry { set: String, key: String },
+    // A set or index entry that should exist but does not
+    MissingEntry { set: String, key: String },
+    // A live comment on a post that no longer exists
+    OrphanedComment { comment_id: u64, post_id: u64 },
+}
 
-    post_list
+// One batch of an integrity check. With a repair, the issues listed were fixed.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntegrityReport {
+    issues: Vec<IntegrityIssue>,
+    checked: u64,
+    next_cursor: Option<String>,
 }
 
-#[ic_cdk::query]
-fn get_user_posts(user_id: Principal) -> Vec<Post> {
-    let posts = POSTS.lock().unwrap();
-    let mut user_posts: Vec<Post> = posts.values()
-        .filter(|post| post.author_id == user_id && !post.is_deleted)
-        .cloned()
-        .collect();
+// One username change, kept so admins can trace a handle back to its account
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UsernameChange {
+    user_id: Principal,
+    old_username: String,
+    new_username: String,
+    changed_at: u64,
+    // Made by an admin or a migration rather than the user, so it does not start their cooldown
+    system: bool,
+}
 
-    // Sort by creation date (most recent first)
-    user_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+// An old handle that still resolves to its former owner until it expires
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UsernameRedirect {
+    user_id: Principal,
+    expires_at: u64,
+}
 
-    user_posts
+#[derive(CandidType, Clone)]
+pub struct PostStats {
+    total_posts: u64,
+    recent_posts: u64, // Last 24 hours
+    total_likes: u64,
 }
 
-#[ic_cdk::update]
-fn update_post(post_id: u64, new_content: String) -> String {
-    let caller_id = caller();
+// Typed error returned by the `_v2` endpoints. The legacy endpoints keep their
+// string results and render these through `Display`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApiError {
+    NotFound { resource: String },
+    Unauthorized { reason: String },
+    NotRegistered,
+    Validation { field: String, reason: String },
+    AlreadyExists { resource: String },
+    Deleted { resource: String },
+    RateLimited { retry_after_ns: u64 },
+    Conflict { reason: String },
+}
 
-    // Validate content
-    if new_content.trim().is_empty() {
-        return "Post content cannot be empty".to_string();
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::NotFound { resource } => write!(f, "{} not found", resource),
+            ApiError::Unauthorized { reason } => write!(f, "Access denied: {}", reason),
+            ApiError::NotRegistered => write!(f, "User must be registered to perform this action"),
+            ApiError::Validation { reason, .. } => write!(f, "{}", reason),
+            ApiError::AlreadyExists { resource } => write!(f, "{} already exists", resource),
+            ApiError::Deleted { resource } => write!(f, "{} has been deleted", resource),
+            ApiError::RateLimited { retry_after_ns } => {
+                write!(f, "Too many requests, retry in {}s", retry_after_ns.div_ceil(1_000_000_000))
+            }
+            ApiError::Conflict { reason } => write!(f, "{}", reason),
+        }
     }
+}
 
-    if new_content.len() > 2000 {
-        return "Post content too long (max 2000 characters)".to_string();
-    }
+// One page of a list endpoint. Pass `next_cursor` back to fetch the following
+// page; it is `None` once the end of the list has been reached.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
 
-    let mut posts = POSTS.lock().unwrap();
-    if let Some(post) = posts.get_mut(&post_id) {
-        // Check if caller is the author or admin
-        if post.author_id != caller_id && !is_admin(&caller_id) {
-            return "Access denied: You can only edit your own posts".to_string();
-        }
+// ============ STABLE STORAGE ============
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Each collection lives in its own virtual memory. Never reuse or renumber these IDs,
+// existing data is located by them after an upgrade.
+const USERS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const POSTS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const POST_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(2);
+const USER_LIKES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const COMMENTS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const COMMENT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(5);
+const USER_COMMENT_LIKES_MEMORY_ID: MemoryId = MemoryId::new(6);
+// Follow edges without timestamps up to schema version 3, only read by the v3 -> v4 migration
+const LEGACY_FOLLOWS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const USER_SHARES_MEMORY_ID: MemoryId = MemoryId::new(8);
+const PROMOTED_ADMINS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const SCHEMA_VERSION_MEMORY_ID: MemoryId = MemoryId::new(10);
+const USERS_BY_CREATION_MEMORY_ID: MemoryId = MemoryId::new(11);
+const AUTHOR_POSTS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const POST_COMMENTS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const HASHTAG_POSTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const USERNAMES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const AUTHOR_COMMENTS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const FOLLOW_RECORDS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const FOLLOWERS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const USERNAME_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(19);
+const USERNAME_REDIRECTS_MEMORY_ID: MemoryId = MemoryId::new(20);
+const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(21);
+const POST_LIKERS_MEMORY_ID: MemoryId = MemoryId::new(22);
+const COMMENT_LIKERS_MEMORY_ID: MemoryId = MemoryId::new(23);
+const POST_SHARERS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const TRASHED_POSTS_MEMORY_ID: MemoryId = MemoryId::new(25);
+const TRASHED_COMMENTS_MEMORY_ID: MemoryId = MemoryId::new(26);
+const TRASH_RETENTION_MEMORY_ID: MemoryId = MemoryId::new(27);
+const NOTIFICATIONS_MEMORY_ID: MemoryId = MemoryId::new(28);
+const NOTIFICATION_RECIPIENTS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const NOTIFICATION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(30);
+const NOTIFICATION_GROUPS_MEMORY_ID: MemoryId = MemoryId::new(31);
+const NOTIFICATION_ACTORS_MEMORY_ID: MemoryId = MemoryId::new(32);
+const UNREAD_NOTIFICATIONS_MEMORY_ID: MemoryId = MemoryId::new(33);
+const CONVERSATIONS_MEMORY_ID: MemoryId = MemoryId::new(34);
+const CONVERSATION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(35);
+const MESSAGES_MEMORY_ID: MemoryId = MemoryId::new(36);
+const MESSAGE_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(37);
+const USER_CONVERSATIONS_MEMORY_ID: MemoryId = MemoryId::new(38);
+const DIRECT_CONVERSATIONS_MEMORY_ID: MemoryId = MemoryId::new(39);
+const READ_RECEIPTS_MEMORY_ID: MemoryId = MemoryId::new(40);
+const UNREAD_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(41);
+const CONVERSATION_INBOX_MEMORY_ID: MemoryId = MemoryId::new(42);
+const SENT_MESSAGES_MEMORY_ID: MemoryId = MemoryId::new(43);
+const MAX_GROUP_MEMBERS_MEMORY_ID: MemoryId = MemoryId::new(44);
+const POST_THREADS_MEMORY_ID: MemoryId = MemoryId::new(45);
+const COMMENT_REPLIES_MEMORY_ID: MemoryId = MemoryId::new(46);
+const USER_REACTIONS_MEMORY_ID: MemoryId = MemoryId::new(47);
+const REACTIONS_MEMORY_ID: MemoryId = MemoryId::new(48);
+const REACTION_KINDS_MEMORY_ID: MemoryId = MemoryId::new(49);
+const REPOSTED_AT_MEMORY_ID: MemoryId = MemoryId::new(50);
+const USER_REPOSTS_MEMORY_ID: MemoryId = MemoryId::new(51);
+const QUOTE_POSTS_MEMORY_ID: MemoryId = MemoryId::new(52);
+const BOOKMARKS_MEMORY_ID: MemoryId = MemoryId::new(53);
+const POST_BOOKMARKERS_MEMORY_ID: MemoryId = MemoryId::new(54);
+const BOOKMARK_COLLECTIONS_MEMORY_ID: MemoryId = MemoryId::new(55);
+const USER_BOOKMARKS_MEMORY_ID: MemoryId = MemoryId::new(56);
+const COLLECTION_BOOKMARKS_MEMORY_ID: MemoryId = MemoryId::new(57);
+const POST_REVISIONS_MEMORY_ID: MemoryId = MemoryId::new(58);
+const COMMENT_REVISIONS_MEMORY_ID: MemoryId = MemoryId::new(59);
+const EDIT_POLICY_MEMORY_ID: MemoryId = MemoryId::new(60);
+
+// Principal wrapper so principals can be used as stable map keys
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrincipalKey(Principal);
+
+impl PrincipalKey {
+    // Smallest possible key, used as the lower bound when scanning composite keys
+    fn min() -> Self {
+        PrincipalKey(Principal::management_canister())
+    }
 
-        if post.is_deleted {
-            return "Cannot edit deleted post".to_string();
-        }
+    // Largest possible key, used as the upper bound when scanning composite keys
+    fn max() -> Self {
+        PrincipalKey(Principal::from_slice(&[u8::MAX; Principal::MAX_LENGTH_IN_BYTES]))
+    }
+}
 
-        post.content = new_content.trim().to_string();
-        post.updated_at = Some(current_time());
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
 
-        return "Post updated successfully".to_string();
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
     }
 
-    "Post not found".to_string()
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
 }
 
-#[ic_cdk::update]
-fn delete_post(post_id: u64) -> String {
-    let caller_id = caller();
+// Hashtag index key, ordered by normalized tag and then by post ID
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HashtagKey {
+    tag: String,
+    post_id: u64,
+}
 
-    let mut posts = POSTS.lock().unwrap();
-    if let Some(post) = posts.get_mut(&post_id) {
-        // Check if caller is the author or admin
-        if post.author_id != caller_id && !is_admin(&caller_id) {
-            return "Access denied: You can only delete your own posts".to_string();
-        }
+impl Storable for HashtagKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.post_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.tag.as_bytes());
+        Cow::Owned(bytes)
+    }
 
-        if post.is_deleted {
-            return "Post already deleted".to_string();
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (post_id, tag) = bytes.split_at(8);
+        HashtagKey {
+            tag: String::from_utf8(tag.to_vec()).expect("Invalid hashtag key"),
+            post_id: u64::from_be_bytes(post_id.try_into().expect("Invalid hashtag key")),
         }
-
-        post.is_deleted = true;
-        return "Post deleted successfully".to_string();
     }
 
-    "Post not found".to_string()
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-#[ic_cdk::update]
-fn like_post(post_id: u64) -> String {
-    let caller_id = caller();
+// Notification group index key. Notifications of the same kind about the same target
+// form a group, whose entries are ordered by notification ID.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NotificationGroupKey {
+    recipient: PrincipalKey,
+    kind: NotificationKind,
+    // The post for likes, comments and shares, the comment for comment likes, the notification
+    // itself for mentions, 0 for follows
+    target: u64,
+    notification_id: u64,
+}
 
-    // Check if user exists
-    if !user_exists(&caller_id) {
-        return "User must be registered to like posts".to_string();
+impl Storable for NotificationGroupKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![self.kind as u8];
+        bytes.extend_from_slice(&self.target.to_be_bytes());
+        bytes.extend_from_slice(&self.notification_id.to_be_bytes());
+        bytes.extend_from_slice(self.recipient.0.as_slice());
+        Cow::Owned(bytes)
     }
 
-    // Check if post exists and is not deleted
-    {
-        let posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get(&post_id) {
-            if post.is_deleted {
-                return "Cannot like deleted post".to_string();
-            }
-        } else {
-            return "Post not found".to_string();
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (target, rest) = bytes[1..].split_at(8);
+        let (notification_id, recipient) = rest.split_at(8);
+        NotificationGroupKey {
+            recipient: PrincipalKey(Principal::from_slice(recipient)),
+            kind: NotificationKind::from_index(bytes[0]),
+            target: u64::from_be_bytes(target.try_into().expect("Invalid notification group key")),
+            notification_id: u64::from_be_bytes(notification_id.try_into().expect("Invalid notification group key")),
         }
     }
 
-    // Check if user already liked this post
-    {
-        let mut user_likes = USER_LIKES.lock().unwrap();
-        let user_liked_posts = user_likes.entry(caller_id).or_insert_with(HashSet::new);
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 46,
+        is_fixed_size: false,
+    };
+}
 
-        if user_liked_posts.contains(&post_id) {
-            return "You have already liked this post".to_string();
-        }
+// ============ SCHEMA VERSIONING ============
+
+// Layout version of the User, Post and Comment records in stable memory.
+// Bump it whenever one of these records changes shape, keep the previous struct
+// around as `<Record>V<n>` to decode old records, and add a step to MIGRATIONS.
+// New derived structures such as indexes also bump it, so their step can backfill them.
+const SCHEMA_VERSION: u8 = 12;
+
+// Version of the records in a heap-based release's upgrade snapshot
+const LEGACY_SNAPSHOT_VERSION: u8 = 1;
+
+// Migration steps, MIGRATIONS[n] upgrades the stored data from version n to n + 1.
+// Records are decoded into the latest struct whatever their stored version, so a
+// step that only needs to re-encode records can simply rewrite every record.
+const MIGRATIONS: [fn(); SCHEMA_VERSION as usize] = [
+    // v0 -> v1: records written before versioning are plain candid without a version tag
+    rewrite_all_records,
+    // v1 -> v2: users indexed by creation time
+    rebuild_indexes,
+    // v2 -> v3: secondary indexes for authors, comments, hashtags and usernames
+    rebuild_indexes,
+    // v3 -> v4: timestamped follow records, follower index and stored follow counts
+    migrate_follows,
+    // v4 -> v5: username index keys are Unicode-normalized
+    rebuild_indexes,
+    // v5 -> v6: reverse (item_id, user_id) sets for likes, comment likes and shares
+    rebuild_reverse_item_sets,
+    // v6 -> v7: deletion timestamps on posts and comments, and the trash indexes
+    migrate_trash,
+    // v7 -> v8: notification preferences in the user settings
+    rewrite_settings,
+    // v8 -> v9: group conversations
+    rewrite_conversations,
+    // v9 -> v10: reply threads on comments
+    rebuild_indexes,
+    // v10 -> v11: reactions, with existing likes as "like" reactions
+    migrate_reactions,
+    // v11 -> v12: quote posts, repost times and one share per user
+    migrate_reposts,
+];
+
+// Records are stored as a one-byte version tag followed by their candid encoding
+fn encode_record<T: CandidType>(record: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![SCHEMA_VERSION];
+    bytes.extend(Encode!(record).expect("Failed to encode record"));
+    Cow::Owned(bytes)
+}
 
-        user_liked_posts.insert(post_id);
+// Splits a stored record into its version and candid payload. Unversioned records
+// start directly with the candid magic and are reported as version 0.
+fn split_record_version(bytes: &[u8]) -> (u8, &[u8]) {
+    if bytes.starts_with(b"DIDL") {
+        return (0, bytes);
     }
-
-    // Increment like count
-    {
-        let mut posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get_mut(&post_id) {
-            post.likes += 1;
-        }
+    match bytes.split_first() {
+        Some((&version, payload)) => (version, payload),
+        None => panic!("Stored record is empty, expected a version tag"),
     }
-
-    "Post liked successfully".to_string()
 }
 
+// User layout up to schema version 3, before follow counts were stored
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UserV3 {
+    user_id: Principal,
+    username: String,
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+    is_admin: bool,
+    created_at: u64,
+    last_active: u64,
+}
+
+// Counts start at zero, the v3 -> v4 migration recomputes them
+impl From<UserV3> for User {
+    fn from(user: UserV3) -> Self {
+        User {
+            user_id: user.user_id,
+            username: user.username,
+            full_name: user.full_name,
+            email: user.email,
+            bio: user.bio,
+            profile_pic: user.profile_pic,
+            location: user.location,
+            website: user.website,
+            is_admin: user.is_admin,
+            created_at: user.created_at,
+            last_active: user.last_active,
+            followers_count: 0,
+            following_count: 0,
+        }
+    }
+}
+
+// Post layout up to schema version 6, before deletion timestamps were stored
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PostV6 {
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    comments_count: u64,
+    shares_count: u64,
+    hashtags: Vec<String>,
+    mentions: Vec<Principal>,
+    media_urls: Vec<String>,
+    is_deleted: bool,
+}
+
+// The v6 -> v7 migration stamps posts that were already deleted
+impl From<PostV6> for PostV10 {
+    fn from(post: PostV6) -> Self {
+        PostV10 {
+            post_id: post.post_id,
+            author_id: post.author_id,
+            content: post.content,
+            created_at: post.created_at,
+            updated_at: post.updated_at,
+            likes: post.likes,
+            comments_count: post.comments_count,
+            shares_count: post.shares_count,
+            hashtags: post.hashtags,
+            mentions: post.mentions,
+            media_urls: post.media_urls,
+            is_deleted: post.is_deleted,
+            deleted_at: None,
+        }
+    }
+}
+
+// Post layout up to schema version 10, before reactions
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PostV10 {
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    comments_count: u64,
+    shares_count: u64,
+    hashtags: Vec<String>,
+    mentions: Vec<Principal>,
+    media_urls: Vec<String>,
+    is_deleted: bool,
+    deleted_at: Option<u64>,
+}
+
+// The v10 -> v11 migration fills in the reaction counts
+impl From<PostV10> for PostV11 {
+    fn from(post: PostV10) -> Self {
+        PostV11 {
+            post_id: post.post_id,
+            author_id: post.author_id,
+            content: post.content,
+            created_at: post.created_at,
+            updated_at: post.updated_at,
+            likes: post.likes,
+            comments_count: post.comments_count,
+            shares_count: post.shares_count,
+            hashtags: post.hashtags,
+            mentions: post.mentions,
+            media_urls: post.media_urls,
+            is_deleted: post.is_deleted,
+            deleted_at: post.deleted_at,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+// Post layout at schema version 11, before quote posts
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PostV11 {
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    comments_count: u64,
+    shares_count: u64,
+    hashtags: Vec<String>,
+    mentions: Vec<Principal>,
+    media_urls: Vec<String>,
+    is_deleted: bool,
+    deleted_at: Option<u64>,
+    reactions: Vec<ReactionCount>,
+}
+
+impl From<PostV11> for Post {
+    fn from(post: PostV11) -> Self {
+        Post {
+            post_id: post.post_id,
+            author_id: post.author_id,
+            content: post.content,
+            created_at: post.created_at,
+            updated_at: post.updated_at,
+            likes: post.likes,
+            comments_count: post.comments_count,
+            shares_count: post.shares_count,
+            hashtags: post.hashtags,
+            mentions: post.mentions,
+            media_urls: post.media_urls,
+            is_deleted: post.is_deleted,
+            deleted_at: post.deleted_at,
+            reactions: post.reactions,
+            quoted_post_id: None,
+        }
+    }
+}
+
+// Comment layout up to schema version 6, before deletion timestamps were stored
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommentV6 {
+    comment_id: u64,
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    is_deleted: bool,
+}
+
+// The v6 -> v7 migration stamps comments that were already deleted
+impl From<CommentV6> for CommentV9 {
+    fn from(comment: CommentV6) -> Self {
+        CommentV9 {
+            comment_id: comment.comment_id,
+            post_id: comment.post_id,
+            author_id: comment.author_id,
+            content: comment.content,
+            created_at: comment.created_at,
+            updated_at: comment.updated_at,
+            likes: comment.likes,
+            is_deleted: comment.is_deleted,
+            deleted_at: None,
+        }
+    }
+}
+
+// Comment layout up to schema version 9, before threaded replies
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommentV9 {
+    comment_id: u64,
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    is_deleted: bool,
+    deleted_at: Option<u64>,
+}
+
+// Every comment written before threading is a top-level comment without replies
+impl From<CommentV9> for CommentV10 {
+    fn from(comment: CommentV9) -> Self {
+        CommentV10 {
+            comment_id: comment.comment_id,
+            post_id: comment.post_id,
+            author_id: comment.author_id,
+            content: comment.content,
+            created_at: comment.created_at,
+            updated_at: comment.updated_at,
+            likes: comment.likes,
+            is_deleted: comment.is_deleted,
+            deleted_at: comment.deleted_at,
+            parent_id: None,
+            depth: 0,
+            reply_count: 0,
+        }
+    }
+}
+
+// Comment layout at schema version 10, before reactions
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommentV10 {
+    comment_id: u64,
+    post_id: u64,
+    author_id: Principal,
+    content: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    likes: u64,
+    is_deleted: bool,
+    deleted_at: Option<u64>,
+    parent_id: Option<u64>,
+    depth: u32,
+    reply_count: u64,
+}
+
+// The v10 -> v11 migration fills in the reaction counts
+impl From<CommentV10> for Comment {
+    fn from(comment: CommentV10) -> Self {
+        Comment {
+            comment_id: comment.comment_id,
+            post_id: comment.post_id,
+            author_id: comment.author_id,
+            content: comment.content,
+            created_at: comment.created_at,
+            updated_at: comment.updated_at,
+            likes: comment.likes,
+            is_deleted: comment.is_deleted,
+            deleted_at: comment.deleted_at,
+            parent_id: comment.parent_id,
+            depth: comment.depth,
+            reply_count: comment.reply_count,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            0..=3 => Decode!(payload, UserV3).expect("Failed to decode user").into(),
+            4..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode user"),
+            _ => panic!("Unsupported user record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Post {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            0..=6 => {
+                let post = PostV10::from(Decode!(payload, PostV6).expect("Failed to decode post"));
+                PostV11::from(post).into()
+            }
+            7..=10 => PostV11::from(Decode!(payload, PostV10).expect("Failed to decode post")).into(),
+            11 => Decode!(payload, PostV11).expect("Failed to decode post").into(),
+            12..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode post"),
+            _ => panic!("Unsupported post record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Follow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            4..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode follow"),
+            _ => panic!("Unsupported follow record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UsernameChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            5..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode username change"),
+            _ => panic!("Unsupported username change record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UsernameRedirect {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            5..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode username redirect"),
+            _ => panic!("Unsupported username redirect record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ReactionTarget {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let (tag, item_id) = match self {
+            ReactionTarget::Post(post_id) => (0u8, post_id),
+            ReactionTarget::Comment(comment_id) => (1u8, comment_id),
+        };
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(&item_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let item_id = u64::from_be_bytes(bytes[1..9].try_into().expect("Invalid reaction target"));
+        match bytes[0] {
+            0 => ReactionTarget::Post(item_id),
+            _ => ReactionTarget::Comment(item_id),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 9,
+        is_fixed_size: true,
+    };
+}
+
+// Key of the reaction index: the item, the reaction kind and the user who reacted
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReactionKey {
+    target: ReactionTarget,
+    kind: String,
+    user: PrincipalKey,
+}
+
+impl Storable for ReactionKey {
+    // Target, kind length, kind, then the principal
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.target.to_bytes().into_owned();
+        bytes.push(self.kind.len() as u8);
+        bytes.extend_from_slice(self.kind.as_bytes());
+        bytes.extend_from_slice(self.user.0.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let target = ReactionTarget::from_bytes(Cow::Borrowed(&bytes[..9]));
+        let kind_end = 10 + bytes[9] as usize;
+        ReactionKey {
+            target,
+            kind: String::from_utf8(bytes[10..kind_end].to_vec()).expect("Invalid reaction kind"),
+            user: PrincipalKey(Principal::from_slice(&bytes[kind_end..])),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 9 + 1 + MAX_REACTION_KIND_LEN as u32 + 29,
+        is_fixed_size: false,
+    };
+}
+
+// Bookmark collection key, ordered by user and then by collection name
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CollectionKey {
+    user: PrincipalKey,
+    name: String,
+}
+
+impl Storable for CollectionKey {
+    // Principal length, principal, then the name
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.user.0.as_slice();
+        let mut bytes = vec![principal.len() as u8];
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(self.name.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let principal_end = 1 + bytes[0] as usize;
+        CollectionKey {
+            user: PrincipalKey(Principal::from_slice(&bytes[1..principal_end])),
+            name: String::from_utf8(bytes[principal_end..].to_vec()).expect("Invalid collection key"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Revision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            12..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode revision"),
+            _ => panic!("Unsupported revision record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for EditPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            12..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode edit policy"),
+            _ => panic!("Unsupported edit policy record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Bookmark {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            12..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode bookmark"),
+            _ => panic!("Unsupported bookmark record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UserSettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            5..=7 => Decode!(payload, UserSettingsV7).expect("Failed to decode settings").into(),
+            8..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode settings"),
+            _ => panic!("Unsupported settings record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Comment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            0..=6 => {
+                let comment = CommentV9::from(Decode!(payload, CommentV6).expect("Failed to decode comment"));
+                CommentV10::from(comment).into()
+            }
+            7..=9 => CommentV10::from(Decode!(payload, CommentV9).expect("Failed to decode comment")).into(),
+            10 => Decode!(payload, CommentV10).expect("Failed to decode comment").into(),
+            11..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode comment"),
+            _ => panic!("Unsupported comment record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            7..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode notification"),
+            _ => panic!("Unsupported notification record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Conversation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            8 => Decode!(payload, ConversationV8).expect("Failed to decode conversation").into(),
+            9..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode conversation"),
+            _ => panic!("Unsupported conversation record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Message {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            8..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode message"),
+            _ => panic!("Unsupported message record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ReadReceipt {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_record(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (version, payload) = split_record_version(&bytes);
+        match version {
+            8..=SCHEMA_VERSION => Decode!(payload, Self).expect("Failed to decode read receipt"),
+            _ => panic!("Unsupported read receipt record version {}", version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Users storage - using the user's principal as key
+pub type Users = StableBTreeMap<PrincipalKey, User, Memory>;
+
+// Posts storage - using post_id as key
+pub type Posts = StableBTreeMap<u64, Post, Memory>;
+
+// Comments storage - using comment_id as key
+pub type Comments = StableBTreeMap<u64, Comment, Memory>;
+
+// Set of (user_id, item_id) pairs, e.g. the posts a user liked
+pub type UserItemSet = StableBTreeMap<(PrincipalKey, u64), (), Memory>;
+
+// Set of (item_id, user_id) pairs, the reverse of a UserItemSet, e.g. the users who liked a post
+pub type ItemUserSet = StableBTreeMap<(u64, PrincipalKey), (), Memory>;
+
+// Follow records keyed by (follower_id, following_id)
+pub type Follows = StableBTreeMap<(PrincipalKey, PrincipalKey), Follow, Memory>;
+
+// Set of (following_id, follower_id) pairs, the reverse of Follows
+pub type FollowerSet = StableBTreeMap<(PrincipalKey, PrincipalKey), (), Memory>;
+
+// Set of (post_id, comment_id) pairs
+pub type PostItemSet = StableBTreeMap<(u64, u64), (), Memory>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static USERS: RefCell<Users> = RefCell::new(StableBTreeMap::init(get_memory(USERS_MEMORY_ID)));
+
+    static POSTS: RefCell<Posts> = RefCell::new(StableBTreeMap::init(get_memory(POSTS_MEMORY_ID)));
+
+    // Post ID counter for generating unique post IDs
+    static POST_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(POST_ID_COUNTER_MEMORY_ID), 0).expect("Failed to initialize post ID counter")
+    );
+
+    // User likes tracking - (user_id, post_id) for every post a user liked
+    static USER_LIKES: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(USER_LIKES_MEMORY_ID)));
+
+    static COMMENTS: RefCell<Comments> = RefCell::new(StableBTreeMap::init(get_memory(COMMENTS_MEMORY_ID)));
+
+    // Comment ID counter for generating unique comment IDs
+    static COMMENT_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(COMMENT_ID_COUNTER_MEMORY_ID), 0).expect("Failed to initialize comment ID counter")
+    );
+
+    // Comment likes tracking - (user_id, comment_id) for every comment a user liked
+    static USER_COMMENT_LIKES: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(USER_COMMENT_LIKES_MEMORY_ID)));
+
+    // Follows storage - the Follow record of every follow relationship
+    static FOLLOWS: RefCell<Follows> = RefCell::new(StableBTreeMap::init(get_memory(FOLLOW_RECORDS_MEMORY_ID)));
+
+    // Followers index - (following_id, follower_id) for every follow relationship
+    static FOLLOWERS: RefCell<FollowerSet> = RefCell::new(StableBTreeMap::init(get_memory(FOLLOWERS_MEMORY_ID)));
+
+    // Post shares tracking - (user_id, post_id) for every post a user shared
+    static USER_SHARES: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(USER_SHARES_MEMORY_ID)));
+
+    // Additional admins that can be promoted by existing admins
+    static PROMOTED_ADMINS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROMOTED_ADMINS_MEMORY_ID)));
+
+    // Schema version of the stored records, see SCHEMA_VERSION
+    static STORED_SCHEMA_VERSION: RefCell<StableCell<u8, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SCHEMA_VERSION_MEMORY_ID), 0).expect("Failed to initialize schema version")
+    );
+
+    // (created_at, user_id) of every user, the order user lists are paged in
+    static USERS_BY_CREATION: RefCell<StableBTreeMap<(u64, PrincipalKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USERS_BY_CREATION_MEMORY_ID)));
+
+    // Secondary indexes, kept in sync with the records by the index_* / unindex_* helpers.
+    // Deleted posts and comments are dropped from them, except from the author indexes,
+    // which list everything a user wrote for as long as the records are stored.
+
+    // (author_id, post_id) for every post a user wrote
+    static AUTHOR_POSTS: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(AUTHOR_POSTS_MEMORY_ID)));
+
+    // (author_id, comment_id) for every comment a user wrote
+    static AUTHOR_COMMENTS: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(AUTHOR_COMMENTS_MEMORY_ID)));
+
+    // (post_id, comment_id) for every comment on a post
+    static POST_COMMENTS: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(POST_COMMENTS_MEMORY_ID)));
+
+    // Normalized hashtag -> post IDs
+    static HASHTAG_POSTS: RefCell<StableBTreeMap<HashtagKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(HASHTAG_POSTS_MEMORY_ID)));
+
+    // Lowercase username -> user principal
+    static USERNAMES: RefCell<StableBTreeMap<String, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USERNAMES_MEMORY_ID)));
+
+    // Username changes keyed by (user_id, changed_at)
+    static USERNAME_HISTORY: RefCell<StableBTreeMap<(PrincipalKey, u64), UsernameChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USERNAME_HISTORY_MEMORY_ID)));
+
+    // Lowercase old username -> its former owner, for handles still in their grace period
+    static USERNAME_REDIRECTS: RefCell<StableBTreeMap<String, UsernameRedirect, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USERNAME_REDIRECTS_MEMORY_ID)));
+
+    // Per-user settings, users without an entry use the defaults
+    static SETTINGS: RefCell<StableBTreeMap<PrincipalKey, UserSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SETTINGS_MEMORY_ID)));
+
+    // Reverse sets of USER_LIKES, USER_COMMENT_LIKES and USER_SHARES, kept in sync by the
+    // *_user_item helpers. The like and share counters are derived from these.
+    static POST_LIKERS: RefCell<ItemUserSet> = RefCell::new(StableBTreeMap::init(get_memory(POST_LIKERS_MEMORY_ID)));
+    static COMMENT_LIKERS: RefCell<ItemUserSet> = RefCell::new(StableBTreeMap::init(get_memory(COMMENT_LIKERS_MEMORY_ID)));
+    static POST_SHARERS: RefCell<ItemUserSet> = RefCell::new(StableBTreeMap::init(get_memory(POST_SHARERS_MEMORY_ID)));
+
+    // Trash indexes - (deleted_at, post_id) / (deleted_at, comment_id) for every deleted
+    // post and comment that has not been purged yet, oldest deletion first
+    static TRASHED_POSTS: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(TRASHED_POSTS_MEMORY_ID)));
+    static TRASHED_COMMENTS: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(TRASHED_COMMENTS_MEMORY_ID)));
+
+    // How long deleted posts and comments are kept before the purge job removes them
+    static TRASH_RETENTION_NS: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(TRASH_RETENTION_MEMORY_ID), DEFAULT_TRASH_RETENTION_NS)
+            .expect("Failed to initialize trash retention")
+    );
+
+    // Notifications keyed by (recipient_id, notification_id)
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(PrincipalKey, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATIONS_MEMORY_ID)));
+
+    // notification_id -> recipient_id. IDs grow over time, so this lists notifications oldest first.
+    static NOTIFICATION_RECIPIENTS: RefCell<StableBTreeMap<u64, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATION_RECIPIENTS_MEMORY_ID)));
+
+    // Notification ID counter for generating unique notification IDs
+    static NOTIFICATION_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(NOTIFICATION_ID_COUNTER_MEMORY_ID), 0).expect("Failed to initialize notification ID counter")
+    );
+
+    // The notifications of every group, see NotificationGroupKey
+    static NOTIFICATION_GROUPS: RefCell<StableBTreeMap<NotificationGroupKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATION_GROUPS_MEMORY_ID)));
+
+    // (actor_id, notification_id) for every notification a user caused
+    static NOTIFICATION_ACTORS: RefCell<UserItemSet> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATION_ACTORS_MEMORY_ID)));
+
+    // Number of unread notifications per user, users without an entry have none
+    static UNREAD_NOTIFICATIONS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UNREAD_NOTIFICATIONS_MEMORY_ID)));
+
+    static CONVERSATIONS: RefCell<StableBTreeMap<u64, Conversation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONVERSATIONS_MEMORY_ID)));
+
+    // Conversation ID counter for generating unique conversation IDs
+    static CONVERSATION_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CONVERSATION_ID_COUNTER_MEMORY_ID), 0).expect("Failed to initialize conversation ID counter")
+    );
+
+    // Messages keyed by (conversation_id, message_id)
+    static MESSAGES: RefCell<StableBTreeMap<(u64, u64), Message, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MESSAGES_MEMORY_ID)));
+
+    // Message ID counter for generating unique message IDs
+    static MESSAGE_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(MESSAGE_ID_COUNTER_MEMORY_ID), 0).expect("Failed to initialize message ID counter")
+    );
+
+    // (user_id, conversation_id) for every conversation a user takes part in
+    static USER_CONVERSATIONS: RefCell<UserItemSet> = RefCell::new(StableBTreeMap::init(get_memory(USER_CONVERSATIONS_MEMORY_ID)));
+
+    // (user_id, last_activity_at, conversation_id) for every conversation a user takes part in,
+    // the order the inbox is paged in
+    static CONVERSATION_INBOX: RefCell<StableBTreeMap<(PrincipalKey, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONVERSATION_INBOX_MEMORY_ID)));
+
+    // (smaller principal, larger principal) -> the direct conversation between the two users
+    static DIRECT_CONVERSATIONS: RefCell<StableBTreeMap<(PrincipalKey, PrincipalKey), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DIRECT_CONVERSATIONS_MEMORY_ID)));
+
+    // Read receipts keyed by (conversation_id, user_id)
+    static READ_RECEIPTS: RefCell<StableBTreeMap<(u64, PrincipalKey), ReadReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(READ_RECEIPTS_MEMORY_ID)));
+
+    // (conversation_id, user_id) -> messages from others not yet read
+    static UNREAD_COUNTS: RefCell<StableBTreeMap<(u64, PrincipalKey), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UNREAD_COUNTS_MEMORY_ID)));
+
+    // (sender_id, message_id) -> conversation_id for every message a user sent
+    static SENT_MESSAGES: RefCell<StableBTreeMap<(PrincipalKey, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SENT_MESSAGES_MEMORY_ID)));
+
+    // Largest number of members a group conversation can have, owner included
+    static MAX_GROUP_MEMBERS: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(MAX_GROUP_MEMBERS_MEMORY_ID), DEFAULT_MAX_GROUP_MEMBERS)
+            .expect("Failed to initialize group member limit")
+    );
+
+    // Thread structure, kept for deleted comments too until they are purged so replies
+    // stay reachable under a "[deleted]" placeholder.
+    // (post_id, comment_id) for every top-level comment
+    static POST_THREADS: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(POST_THREADS_MEMORY_ID)));
+    // (parent_id, comment_id) for every reply
+    static COMMENT_REPLIES: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(COMMENT_REPLIES_MEMORY_ID)));
+
+    // (user_id, item) -> the user's reaction to the item, at most one per item
+    static USER_REACTIONS: RefCell<StableBTreeMap<(PrincipalKey, ReactionTarget), String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USER_REACTIONS_MEMORY_ID)));
+
+    // Reverse of USER_REACTIONS, grouped by item and kind. "like" reactions are also
+    // kept in USER_LIKES / USER_COMMENT_LIKES and their reverse sets.
+    static REACTIONS: RefCell<StableBTreeMap<ReactionKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REACTIONS_MEMORY_ID)));
+
+    // Reaction kinds users can currently pick from
+    static REACTION_KINDS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REACTION_KINDS_MEMORY_ID)));
+
+    // (user_id, post_id) -> when the user reposted the post, for each entry of USER_SHARES
+    static REPOSTED_AT: RefCell<StableBTreeMap<(PrincipalKey, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REPOSTED_AT_MEMORY_ID)));
+
+    // (user_id, reposted_at, post_id) for each repost, a user's reposts in time order
+    static USER_REPOSTS: RefCell<StableBTreeMap<(PrincipalKey, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USER_REPOSTS_MEMORY_ID)));
+
+    // (quoted_post_id, post_id) for every live quote post
+    static QUOTE_POSTS: RefCell<PostItemSet> = RefCell::new(StableBTreeMap::init(get_memory(QUOTE_POSTS_MEMORY_ID)));
+
+    // (user_id, post_id) -> bookmark
+    static BOOKMARKS: RefCell<StableBTreeMap<(PrincipalKey, u64), Bookmark, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BOOKMARKS_MEMORY_ID)));
+
+    // Reverse of BOOKMARKS, only used to drop the bookmarks of purged posts. Never
+    // exposed, bookmarks stay private to their owner.
+    static POST_BOOKMARKERS: RefCell<ItemUserSet> = RefCell::new(StableBTreeMap::init(get_memory(POST_BOOKMARKERS_MEMORY_ID)));
+
+    // (user_id, collection name) -> creation time
+    static BOOKMARK_COLLECTIONS: RefCell<StableBTreeMap<CollectionKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BOOKMARK_COLLECTIONS_MEMORY_ID)));
+
+    // (user_id, bookmarked_at, post_id) for each bookmark, a user's bookmarks in time order
+    static USER_BOOKMARKS: RefCell<StableBTreeMap<(PrincipalKey, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USER_BOOKMARKS_MEMORY_ID)));
+
+    // (collection, bookmarked_at, post_id) for each bookmark filed in a collection
+    static COLLECTION_BOOKMARKS: RefCell<StableBTreeMap<(CollectionKey, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COLLECTION_BOOKMARKS_MEMORY_ID)));
+
+    // (post_id, revision number) -> earlier content, numbered from 0 in edit order
+    static POST_REVISIONS: RefCell<StableBTreeMap<(u64, u64), Revision, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(POST_REVISIONS_MEMORY_ID)));
+
+    // (comment_id, revision number) -> earlier content
+    static COMMENT_REVISIONS: RefCell<StableBTreeMap<(u64, u64), Revision, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COMMENT_REVISIONS_MEMORY_ID)));
+
+    static EDIT_POLICY: RefCell<StableCell<EditPolicy, Memory>> = RefCell::new(
+        StableCell::init(get_memory(EDIT_POLICY_MEMORY_ID), EditPolicy::default())
+            .expect("Failed to initialize edit policy")
+    );
+}
+
+fn get_memory(memory_id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(memory_id))
+}
+
+// Predefined admin principals - Add your admin principals here
+static PREDEFINED_ADMINS: Lazy<HashSet<Principal>> = Lazy::new(|| {
+    let mut admins = HashSet::new();
+    // Add your admin principals here - replace with actual admin principals
+    // Example: admins.insert(Principal::from_text("your-admin-principal-here").unwrap());
+
+    // For development, you can add a test admin principal
+    // Uncomment and replace with your actual principal when you know it
+    // admins.insert(Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap());
+
+    // Add the original Sarv29 principal as admin for development
+    if let Ok(admin_principal) = Principal::from_text("6qa2u-gctfq-jppnt-e2ai3-mp4ng-qotip-hidii-kascv-srdfq-htjfx-nqe") {
+        admins.insert(admin_principal);
+    }
+
+    // Add the Internet Identity anchor 10000 principal as admin
+    if let Ok(admin_principal) = Principal::from_text("4xgob-2qimn-cbswc-yywe2-spgmi-yg7az-xbu3r-vbi3p-vyf7y-myipw-cae") {
+        admins.insert(admin_principal);
+    }
+
+    admins
+});
+
+// Helper function to get current timestamp in nanoseconds
+#[cfg(not(test))]
+fn current_time() -> u64 {
+    ic_cdk::api::time()
+}
+
+// The system API is unavailable to native unit tests, which drive the caller and clock themselves
+#[cfg(test)]
+use tests::{caller, current_time};
+
+// Helper function to check if a principal is an admin
+fn is_admin(principal: &Principal) -> bool {
+    PREDEFINED_ADMINS.contains(principal) ||
+    PROMOTED_ADMINS.with(|admins| admins.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Helper function to check if caller is admin
+fn require_admin() -> Result<(), ApiError> {
+    let caller_principal = caller();
+    if is_admin(&caller_principal) {
+        Ok(())
+    } else {
+        Err(ApiError::Unauthorized { reason: "Admin privileges required".to_string() })
+    }
+}
+
+// Helper function to check if a user has registered a profile
+fn require_registered(user_id: &Principal) -> Result<(), ApiError> {
+    if user_exists(user_id) {
+        Ok(())
+    } else {
+        Err(ApiError::NotRegistered)
+    }
+}
+
+// Helper function to validate post and comment content
+fn validate_content(content: &str, kind: &str, max_len: usize) -> Result<(), ApiError> {
+    if content.trim().is_empty() {
+        return Err(ApiError::Validation {
+            field: "content".to_string(),
+            reason: format!("{} content cannot be empty", kind),
+        });
+    }
+
+    if content.len() > max_len {
+        return Err(ApiError::Validation {
+            field: "content".to_string(),
+            reason: format!("{} content too long (max {} characters)", kind, max_len),
+        });
+    }
+
+    Ok(())
+}
+
+// Helper function to generate next post ID
+fn next_post_id() -> u64 {
+    POST_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let next_id = *counter.get() + 1;
+        counter.set(next_id).expect("Failed to update post ID counter");
+        next_id
+    })
+}
+
+// Helper function to check if user exists
+fn user_exists(user_id: &Principal) -> bool {
+    USERS.with(|users| users.borrow().contains_key(&PrincipalKey(*user_id)))
+}
+
+// Helper functions to read and write single records. Stable maps hand out copies,
+// so a modified record has to be saved back to persist the change.
+fn load_user(user_id: &Principal) -> Option<User> {
+    USERS.with(|users| users.borrow().get(&PrincipalKey(*user_id)))
+}
+
+fn save_user(user: User) {
+    USERS.with(|users| users.borrow_mut().insert(PrincipalKey(user.user_id), user));
+}
+
+fn load_post(post_id: u64) -> Option<Post> {
+    POSTS.with(|posts| posts.borrow().get(&post_id))
+}
+
+fn save_post(post: Post) {
+    POSTS.with(|posts| posts.borrow_mut().insert(post.post_id, post));
+}
+
+fn load_comment(comment_id: u64) -> Option<Comment> {
+    COMMENTS.with(|comments| comments.borrow().get(&comment_id))
+}
+
+fn save_comment(comment: Comment) {
+    COMMENTS.with(|comments| comments.borrow_mut().insert(comment.comment_id, comment));
+}
+
+// Helper function to load a post that exists and has not been deleted
+fn load_active_post(post_id: u64) -> Result<Post, ApiError> {
+    match load_post(post_id) {
+        Some(post) if post.is_deleted => Err(ApiError::Deleted { resource: "Post".to_string() }),
+        Some(post) => Ok(post),
+        None => Err(ApiError::NotFound { resource: "Post".to_string() }),
+    }
+}
+
+// Helper function to load a comment that exists and has not been deleted
+fn load_active_comment(comment_id: u64) -> Result<Comment, ApiError> {
+    match load_comment(comment_id) {
+        Some(comment) if comment.is_deleted => Err(ApiError::Deleted { resource: "Comment".to_string() }),
+        Some(comment) => Ok(comment),
+        None => Err(ApiError::NotFound { resource: "Comment".to_string() }),
+    }
+}
+
+// Helper function to list the item IDs a user has in a (user_id, item_id) set
+fn user_item_ids(set: &UserItemSet, user_id: Principal) -> Vec<u64> {
+    let key = PrincipalKey(user_id);
+    set.range((key, 0)..=(key, u64::MAX))
+        .map(|((_, item_id), _)| item_id)
+        .collect()
+}
+
+// Helper function to page through the item IDs a user has in a (user_id, item_id) set
+fn user_item_page(set: &UserItemSet, user_id: Principal, cursor: Option<String>, limit: u64) -> Result<Page<u64>, ApiError> {
+    let range = user_items_after(user_id, decode_cursor(cursor)?);
+    Ok(scan_page(set, range, PageOrder::OldestFirst, limit, |((_, item_id), ())| Some(item_id), |item_id| *item_id))
+}
+
+// Helper function to add an entry to a (user_id, item_id) set and its reverse set.
+// Returns false if the entry was already there.
+fn insert_user_item(
+    set: &'static LocalKey<RefCell<UserItemSet>>,
+    reverse: &'static LocalKey<RefCell<ItemUserSet>>,
+    user_id: Principal,
+    item_id: u64,
+) -> bool {
+    let existed = set.with(|set| set.borrow_mut().insert((PrincipalKey(user_id), item_id), ()).is_some());
+    reverse.with(|reverse| reverse.borrow_mut().insert((item_id, PrincipalKey(user_id)), ()));
+    !existed
+}
+
+// Helper function to remove an entry from a (user_id, item_id) set and its reverse set.
+// Returns false if there was no such entry.
+fn remove_user_item(
+    set: &'static LocalKey<RefCell<UserItemSet>>,
+    reverse: &'static LocalKey<RefCell<ItemUserSet>>,
+    user_id: Principal,
+    item_id: u64,
+) -> bool {
+    let existed = set.with(|set| set.borrow_mut().remove(&(PrincipalKey(user_id), item_id)).is_some());
+    reverse.with(|reverse| reverse.borrow_mut().remove(&(item_id, PrincipalKey(user_id))));
+    existed
+}
+
+// Helper function to list the users that have an item in a reverse (item_id, user_id) set
+fn item_user_ids(reverse: &'static LocalKey<RefCell<ItemUserSet>>, item_id: u64) -> Vec<Principal> {
+    reverse.with(|reverse| {
+        reverse.borrow().range((item_id, PrincipalKey::min())..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|((_, user), _)| user.0)
+            .collect()
+    })
+}
+
+// Helper function to remove every user's entry for an item from a set and its reverse set
+fn remove_item_users(
+    set: &'static LocalKey<RefCell<UserItemSet>>,
+    reverse: &'static LocalKey<RefCell<ItemUserSet>>,
+    item_id: u64,
+) -> Vec<Principal> {
+    let user_ids = item_user_ids(reverse, item_id);
+    for user_id in &user_ids {
+        remove_user_item(set, reverse, *user_id, item_id);
+    }
+    user_ids
+}
+
+// Helper function to list the follow records of the users a user is following
+fn following_of(follower_id: Principal) -> Vec<Follow> {
+    FOLLOWS.with(|follows| {
+        follows.borrow().range((PrincipalKey(follower_id), PrincipalKey::min())..)
+            .take_while(|((follower, _), _)| follower.0 == follower_id)
+            .map(|(_, follow)| follow)
+            .collect()
+    })
+}
+
+// Helper function to list the follow records of a user's followers
+fn followers_of(following_id: Principal) -> Vec<Follow> {
+    let follower_keys: Vec<PrincipalKey> = FOLLOWERS.with(|followers| {
+        followers.borrow().range((PrincipalKey(following_id), PrincipalKey::min())..)
+            .take_while(|((following, _), _)| following.0 == following_id)
+            .map(|((_, follower), _)| follower)
+            .collect()
+    });
+    FOLLOWS.with(|follows| {
+        let follows = follows.borrow();
+        follower_keys.into_iter()
+            .filter_map(|follower| follows.get(&(follower, PrincipalKey(following_id))))
+            .collect()
+    })
+}
+
+// Helper function to record a follow relationship and update both users' counts.
+// Returns false if the relationship already exists.
+fn add_follow(follower_id: Principal, following_id: Principal, created_at: u64) -> bool {
+    let key = (PrincipalKey(follower_id), PrincipalKey(following_id));
+    if FOLLOWS.with(|follows| follows.borrow().contains_key(&key)) {
+        return false;
+    }
+
+    let follow = Follow { follower_id, following_id, created_at };
+    FOLLOWS.with(|follows| follows.borrow_mut().insert(key, follow));
+    FOLLOWERS.with(|followers| followers.borrow_mut().insert((key.1, key.0), ()));
+
+    if let Some(mut user) = load_user(&follower_id) {
+        user.following_count += 1;
+        save_user(user);
+    }
+    if let Some(mut user) = load_user(&following_id) {
+        user.followers_count += 1;
+        save_user(user);
+    }
+    true
+}
+
+// Helper function to remove a follow relationship and update both users' counts.
+// Returns false if there was no such relationship.
+fn remove_follow(follower_id: Principal, following_id: Principal) -> bool {
+    let key = (PrincipalKey(follower_id), PrincipalKey(following_id));
+    if FOLLOWS.with(|follows| follows.borrow_mut().remove(&key)).is_none() {
+        return false;
+    }
+    FOLLOWERS.with(|followers| followers.borrow_mut().remove(&(key.1, key.0)));
+
+    if let Some(mut user) = load_user(&follower_id) {
+        user.following_count = user.following_count.saturating_sub(1);
+        save_user(user);
+    }
+    if let Some(mut user) = load_user(&following_id) {
+        user.followers_count = user.followers_count.saturating_sub(1);
+        save_user(user);
+    }
+    true
+}
+
+// ============ INDEXES ============
+
+fn normalize_hashtag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
+
+// Usernames compare case-insensitively on their canonical form
+fn normalize_username(username: &str) -> String {
+    canonical_username(username).to_lowercase()
+}
+
+// Registers a user in the username and creation-time indexes
+fn index_user(user: &User) {
+    index_username(user);
+    USERS_BY_CREATION.with(|users| users.borrow_mut().insert((user.created_at, PrincipalKey(user.user_id)), ()));
+}
+
+fn unindex_user(user: &User) {
+    unindex_username(user);
+    USERS_BY_CREATION.with(|users| users.borrow_mut().remove(&(user.created_at, PrincipalKey(user.user_id))));
+}
+
+fn index_post(post: &Post) {
+    AUTHOR_POSTS.with(|author_posts| {
+        author_posts.borrow_mut().insert((PrincipalKey(post.author_id), post.post_id), ())
+    });
+    if post.is_deleted {
+        return;
+    }
+    HASHTAG_POSTS.with(|hashtag_posts| {
+        let mut hashtag_posts = hashtag_posts.borrow_mut();
+        for tag in &post.hashtags {
+            hashtag_posts.insert(HashtagKey { tag: normalize_hashtag(tag), post_id: post.post_id }, ());
+        }
+    });
+    if let Some(quoted_post_id) = post.quoted_post_id {
+        QUOTE_POSTS.with(|quotes| quotes.borrow_mut().insert((quoted_post_id, post.post_id), ()));
+    }
+}
+
+// Drops a deleted post from the live indexes, it stays in AUTHOR_POSTS
+fn unindex_post(post: &Post) {
+    HASHTAG_POSTS.with(|hashtag_posts| {
+        let mut hashtag_posts = hashtag_posts.borrow_mut();
+        for tag in &post.hashtags {
+            hashtag_posts.remove(&HashtagKey { tag: normalize_hashtag(tag), post_id: post.post_id });
+        }
+    });
+    if let Some(quoted_post_id) = post.quoted_post_id {
+        QUOTE_POSTS.with(|quotes| quotes.borrow_mut().remove(&(quoted_post_id, post.post_id)));
+    }
+}
+
+// Drops every index and trash entry of a post whose record is removed for good
+fn forget_post(post: &Post) {
+    unindex_post(post);
+    AUTHOR_POSTS.with(|author_posts| {
+        author_posts.borrow_mut().remove(&(PrincipalKey(post.author_id), post.post_id))
+    });
+    if let Some(deleted_at) = post.deleted_at {
+        TRASHED_POSTS.with(|trash| trash.borrow_mut().remove(&(deleted_at, post.post_id)));
+    }
+}
+
+fn index_comment(comment: &Comment) {
+    AUTHOR_COMMENTS.with(|author_comments| {
+        author_comments.borrow_mut().insert((PrincipalKey(comment.author_id), comment.comment_id), ())
+    });
+    match comment.parent_id {
+        Some(parent_id) => COMMENT_REPLIES.with(|replies| replies.borrow_mut().insert((parent_id, comment.comment_id), ())),
+        None => POST_THREADS.with(|threads| threads.borrow_mut().insert((comment.post_id, comment.comment_id), ())),
+    };
+    if comment.is_deleted {
+        return;
+    }
+    POST_COMMENTS.with(|post_comments| {
+        post_comments.borrow_mut().insert((comment.post_id, comment.comment_id), ())
+    });
+}
+
+// Drops a deleted comment from the live indexes, it stays in AUTHOR_COMMENTS and its thread
+fn unindex_comment(comment: &Comment) {
+    POST_COMMENTS.with(|post_comments| {
+        post_comments.borrow_mut().remove(&(comment.post_id, comment.comment_id))
+    });
+}
+
+// Drops every index and trash entry of a comment whose record is removed for good
+fn forget_comment(comment: &Comment) {
+    unindex_comment(comment);
+    AUTHOR_COMMENTS.with(|author_comments| {
+        author_comments.borrow_mut().remove(&(PrincipalKey(comment.author_id), comment.comment_id))
+    });
+    match comment.parent_id {
+        Some(parent_id) => COMMENT_REPLIES.with(|replies| replies.borrow_mut().remove(&(parent_id, comment.comment_id))),
+        None => POST_THREADS.with(|threads| threads.borrow_mut().remove(&(comment.post_id, comment.comment_id))),
+    };
+    if let Some(deleted_at) = comment.deleted_at {
+        TRASHED_COMMENTS.with(|trash| trash.borrow_mut().remove(&(deleted_at, comment.comment_id)));
+    }
+}
+
+fn index_username(user: &User) {
+    USERNAMES.with(|usernames| {
+        usernames.borrow_mut().insert(normalize_username(&user.username), PrincipalKey(user.user_id))
+    });
+}
+
+// Only drops the entry if it still points at this user
+fn unindex_username(user: &User) {
+    USERNAMES.with(|usernames| {
+        let mut usernames = usernames.borrow_mut();
+        let key = normalize_username(&user.username);
+        if usernames.get(&key) == Some(PrincipalKey(user.user_id)) {
+            usernames.remove(&key);
+        }
+    });
+}
+
+// IDs of the posts written by a user, deleted ones included
+fn post_ids_by_author(author_id: Principal) -> Vec<u64> {
+    AUTHOR_POSTS.with(|author_posts| user_item_ids(&author_posts.borrow(), author_id))
+}
+
+// IDs of the comments written by a user, deleted ones included
+fn comment_ids_by_author(author_id: Principal) -> Vec<u64> {
+    AUTHOR_COMMENTS.with(|author_comments| user_item_ids(&author_comments.borrow(), author_id))
+}
+
+// IDs of the live comments on a post
+fn comment_ids_for_post(post_id: u64) -> Vec<u64> {
+    POST_COMMENTS.with(|post_comments| {
+        post_comments.borrow().range((post_id, 0)..=(post_id, u64::MAX))
+            .map(|((_, comment_id), _)| comment_id)
+            .collect()
+    })
+}
+
+// IDs of the live posts tagged with a hashtag, matched case-insensitively
+fn post_ids_by_hashtag(hashtag: &str) -> Vec<u64> {
+    let tag = normalize_hashtag(hashtag);
+    let start = HashtagKey { tag: tag.clone(), post_id: 0 };
+    let end = HashtagKey { tag, post_id: u64::MAX };
+    HASHTAG_POSTS.with(|hashtag_posts| {
+        hashtag_posts.borrow().range(start..=end)
+            .map(|(key, _)| key.post_id)
+            .collect()
+    })
+}
+
+fn find_user_by_username(username: &str) -> Option<Principal> {
+    USERNAMES.with(|usernames| usernames.borrow().get(&normalize_username(username)).map(|key| key.0))
+}
+
+// Like find_user_by_username, but an old handle in its grace period resolves to its former owner
+fn resolve_username(username: &str) -> Option<Principal> {
+    find_user_by_username(username).or_else(|| active_redirect(&normalize_username(username)).map(|redirect| redirect.user_id))
+}
+
+// Loads the posts behind a list of indexed IDs, skipping deleted ones
+fn load_posts(post_ids: Vec<u64>) -> Vec<Post> {
+    post_ids.into_iter()
+        .filter_map(load_post)
+        .filter(|post| !post.is_deleted)
+        .collect()
+}
+
+// Loads the comments behind a list of indexed IDs, skipping deleted ones
+fn load_comments(comment_ids: Vec<u64>) -> Vec<Comment> {
+    comment_ids.into_iter()
+        .filter_map(load_comment)
+        .filter(|comment| !comment.is_deleted)
+        .collect()
+}
+
+// Helper function to collect the posts matching a filter
+fn collect_posts(filter: impl Fn(&Post) -> bool) -> Vec<Post> {
+    POSTS.with(|posts| {
+        posts.borrow().iter()
+            .map(|(_, post)| post)
+            .filter(|post| filter(post))
+            .collect()
+    })
+}
+
+// Helper function to collect the users matching a filter
+fn collect_users(filter: impl Fn(&User) -> bool) -> Vec<User> {
+    USERS.with(|users| {
+        users.borrow().iter()
+            .map(|(_, user)| user)
+            .filter(|user| filter(user))
+            .collect()
+    })
+}
+
+// Helper function to remove every entry of a stable map
+fn clear_map<K: Storable + Ord + Clone, V: Storable>(map: &RefCell<StableBTreeMap<K, V, Memory>>) {
+    let mut map = map.borrow_mut();
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+// Drops every index entry and rebuilds the indexes from the stored records
+fn rebuild_indexes() {
+    AUTHOR_POSTS.with(clear_map);
+    AUTHOR_COMMENTS.with(clear_map);
+    POST_COMMENTS.with(clear_map);
+    POST_THREADS.with(clear_map);
+    COMMENT_REPLIES.with(clear_map);
+    HASHTAG_POSTS.with(clear_map);
+    QUOTE_POSTS.with(clear_map);
+    USERNAMES.with(clear_map);
+    USERS_BY_CREATION.with(clear_map);
+
+    for post in collect_posts(|_| true) {
+        index_post(&post);
+    }
+    let all_comments: Vec<Comment> = COMMENTS.with(|comments| {
+        comments.borrow().iter().map(|(_, comment)| comment).collect()
+    });
+    for comment in all_comments {
+        index_comment(&comment);
+    }
+    for user in resolve_username_collisions(collect_users(|_| true)) {
+        index_user(&user);
+    }
+}
+
+// Records from before usernames were unique, or from before they were normalized, can
+// share a handle. The oldest account keeps it and the others get the first free "<name>_<n>",
+// recorded in their username history as a system rename.
+fn resolve_username_collisions(mut users: Vec<User>) -> Vec<User> {
+    users.sort_by_key(|user| (user.created_at, PrincipalKey(user.user_id)));
+
+    let mut holders: HashMap<String, Principal> = HashMap::new();
+    for user in &users {
+        holders.entry(normalize_username(&user.username)).or_insert(user.user_id);
+    }
+
+    let now = current_time();
+    for user in users.iter_mut() {
+        if holders.get(&normalize_username(&user.username)) == Some(&user.user_id) {
+            continue;
+        }
+
+        let new_username = (2u64..)
+            .map(|n| {
+                let suffix = format!("_{}", n);
+                let base: String = user.username.chars().take(USERNAME_MAX_LEN - suffix.len()).collect();
+                base + &suffix
+            })
+            .find(|candidate| !holders.contains_key(&normalize_username(candidate)))
+            .expect("Ran out of username suffixes");
+        holders.insert(normalize_username(&new_username), user.user_id);
+
+        record_username_change(user, &new_username, now, true);
+        user.username = new_username;
+        save_user(user.clone());
+    }
+    users
+}
+
+// ============ USERNAMES ============
+
+const USERNAME_MIN_LEN: usize = 3;
+const USERNAME_MAX_LEN: usize = 30;
+
+// Handles that could pass for the platform itself or clash with app routes.
+// Only admins can give these out.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "support", "help", "system", "moderator", "mod",
+    "staff", "official", "security", "api", "settings", "me", "null", "undefined", "anonymous",
+];
+
+// Canonical form of a username as typed, trimmed and NFKC-normalized so that
+// look-alike forms such as full-width letters collapse onto the same name
+fn canonical_username(username: &str) -> String {
+    username.trim().nfkc().collect()
+}
+
+// Helper function to check a canonical username against the length and character rules
+fn validate_username_format(username: &str) -> Result<(), ApiError> {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&length) {
+        return Err(ApiError::Validation {
+            field: "username".to_string(),
+            reason: format!("Username must be between {} and {} characters", USERNAME_MIN_LEN, USERNAME_MAX_LEN),
+        });
+    }
+
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ApiError::Validation {
+            field: "username".to_string(),
+            reason: "Username can only contain letters, digits and underscores".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Helper function to check that a username can be taken by a user: well-formed, not
+// reserved (unless allowed) and not held by anyone else. Returns the canonical form to store.
+fn check_username(username: &str, user_id: Principal, allow_reserved: bool) -> Result<String, ApiError> {
+    let canonical = canonical_username(username);
+    validate_username_format(&canonical)?;
+
+    let key = normalize_username(&canonical);
+    if !allow_reserved && RESERVED_USERNAMES.contains(&key.as_str()) {
+        return Err(ApiError::Validation {
+            field: "username".to_string(),
+            reason: "Username is reserved".to_string(),
+        });
+    }
+
+    if find_user_by_username(&key).is_some_and(|holder| holder != user_id) {
+        return Err(ApiError::AlreadyExists { resource: "Username".to_string() });
+    }
+
+    // Old handles stay with their former owner until the grace period ends
+    if active_redirect(&key).is_some_and(|redirect| redirect.user_id != user_id) {
+        return Err(ApiError::AlreadyExists { resource: "Username".to_string() });
+    }
+
+    Ok(canonical)
+}
+
+// Minimum time between two username changes by the same user
+const USERNAME_CHANGE_COOLDOWN_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// How long an old username keeps resolving to its former owner
+const USERNAME_REDIRECT_GRACE_NS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+
+fn active_redirect(key: &str) -> Option<UsernameRedirect> {
+    USERNAME_REDIRECTS.with(|redirects| redirects.borrow().get(&key.to_string()))
+        .filter(|redirect| redirect.expires_at > current_time())
+}
+
+fn username_history(user_id: Principal) -> Vec<UsernameChange> {
+    let key = PrincipalKey(user_id);
+    USERNAME_HISTORY.with(|history| {
+        history.borrow().range((key, 0)..=(key, u64::MAX))
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
+
+// Helper function to drop the redirects still pointing at a user's old handles
+fn remove_username_redirects(user_id: Principal) {
+    for change in username_history(user_id) {
+        let key = normalize_username(&change.old_username);
+        USERNAME_REDIRECTS.with(|redirects| {
+            let mut redirects = redirects.borrow_mut();
+            if redirects.get(&key).is_some_and(|redirect| redirect.user_id == user_id) {
+                redirects.remove(&key);
+            }
+        });
+    }
+}
+
+#[ic_cdk::update]
+fn change_username(new_username: String) -> Result<(), ApiError> {
+    let user_id = caller();
+    let mut user = load_user(&user_id).ok_or(ApiError::NotRegistered)?;
+
+    let new_username = check_username(&new_username, user_id, false)?;
+    if new_username == user.username {
+        return Err(ApiError::Validation {
+            field: "username".to_string(),
+            reason: "This is already your username".to_string(),
+        });
+    }
+
+    // Enforce the cooldown since the user's last own change
+    let now = current_time();
+    if let Some(last_change) = username_history(user_id).iter().rev().find(|change| !change.system) {
+        let allowed_at = last_change.changed_at.saturating_add(USERNAME_CHANGE_COOLDOWN_NS);
+        if now < allowed_at {
+            return Err(ApiError::RateLimited { retry_after_ns: allowed_at - now });
+        }
+    }
+
+    rename_user(&mut user, new_username, now, false);
+    user.last_active = now;
+    save_user(user);
+
+    Ok(())
+}
+
+// Helper function to move a user to a new, already checked username. The old handle keeps
+// redirecting to them for the grace period. The caller saves the user.
+fn rename_user(user: &mut User, new_username: String, now: u64, system: bool) {
+    let old_key = normalize_username(&user.username);
+    let new_key = normalize_username(&new_username);
+    USERNAME_REDIRECTS.with(|redirects| {
+        let mut redirects = redirects.borrow_mut();
+        // Taking back one of your own old handles, or one whose grace period is over
+        redirects.remove(&new_key);
+        // A change of letter case keeps the same handle, nothing to redirect
+        if old_key != new_key {
+            redirects.insert(old_key, UsernameRedirect {
+                user_id: user.user_id,
+                expires_at: now.saturating_add(USERNAME_REDIRECT_GRACE_NS),
+            });
+        }
+    });
+
+    record_username_change(user, &new_username, now, system);
+
+    unindex_username(user);
+    user.username = new_username;
+    index_username(user);
+}
+
+fn record_username_change(user: &User, new_username: &str, now: u64, system: bool) {
+    USERNAME_HISTORY.with(|history| {
+        history.borrow_mut().insert((PrincipalKey(user.user_id), now), UsernameChange {
+            user_id: user.user_id,
+            old_username: user.username.clone(),
+            new_username: new_username.to_string(),
+            changed_at: now,
+            system,
+        })
+    });
+}
+
+#[ic_cdk::query]
+fn admin_get_username_history(user_id: Principal) -> Result<Vec<UsernameChange>, ApiError> {
+    require_admin()?;
+
+    Ok(username_history(user_id))
+}
+
+// Ok if the caller could register or switch to this username, otherwise the reason it is not available
+#[ic_cdk::query]
+fn check_username_available(username: String) -> Result<(), ApiError> {
+    check_username(&username, caller(), false).map(|_| ())
+}
+
+// ============ PRIVACY ============
+
+const MAX_MUTED_POSTS: usize = 1000;
+
+fn load_settings(user_id: Principal) -> UserSettings {
+    SETTINGS.with(|settings| settings.borrow().get(&PrincipalKey(user_id))).unwrap_or_default()
+}
+
+// Builds the view of a profile that a given caller is allowed to see.
+// Owners and admins see every field.
+fn public_profile(user: User, viewer: Principal) -> PublicProfile {
+    let privacy = load_settings(user.user_id).privacy;
+    let sees_all = viewer == user.user_id || is_admin(&viewer);
+    let is_follower = || FOLLOWS.with(|follows| {
+        follows.borrow().contains_key(&(PrincipalKey(viewer), PrincipalKey(user.user_id)))
+    });
+    let visible = |visibility: Visibility| match visibility {
+        Visibility::Public => true,
+        Visibility::Followers => sees_all || is_follower(),
+        Visibility::OnlyMe => sees_all,
+    };
+
+    PublicProfile {
+        user_id: user.user_id,
+        username: user.username,
+        full_name: user.full_name,
+        bio: user.bio,
+        profile_pic: user.profile_pic,
+        email: user.email.filter(|_| visible(privacy.email)),
+        location: user.location.filter(|_| visible(privacy.location)),
+        website: user.website.filter(|_| visible(privacy.website)),
+        created_at: user.created_at,
+        followers_count: user.followers_count,
+        following_count: user.following_count,
+    }
+}
+
+fn public_profiles(users: Vec<User>) -> Vec<PublicProfile> {
+    let viewer = caller();
+    users.into_iter().map(|user| public_profile(user, viewer)).collect()
+}
+
+fn public_profile_page(page: Page<User>) -> Page<PublicProfile> {
+    Page { items: public_profiles(page.items), next_cursor: page.next_cursor }
+}
+
+// The caller's full profile record, including private fields
+#[ic_cdk::query]
+fn get_my_profile() -> Option<User> {
+    load_user(&caller())
+}
+
+#[ic_cdk::query]
+fn get_settings() -> Result<UserSettings, ApiError> {
+    let user_id = caller();
+    require_registered(&user_id)?;
+
+    Ok(load_settings(user_id))
+}
+
+#[ic_cdk::update]
+fn update_settings(mut new_settings: UserSettings) -> Result<(), ApiError> {
+    let user_id = caller();
+    require_registered(&user_id)?;
+
+    let muted_posts = &mut new_settings.notifications.muted_posts;
+    muted_posts.sort_unstable();
+    muted_posts.dedup();
+    if muted_posts.len() > MAX_MUTED_POSTS {
+        return Err(ApiError::Validation {
+            field: "muted_posts".to_string(),
+            reason: format!("At most {} posts can be muted", MAX_MUTED_POSTS),
+        });
+    }
+
+    SETTINGS.with(|settings| settings.borrow_mut().insert(PrincipalKey(user_id), new_settings));
+    Ok(())
+}
+
+// ============ PAGINATION ============
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 200;
+
+// Sort key that can be handed out to clients as an opaque cursor
+trait PageKey: Ord + Sized {
+    fn encode(&self) -> String;
+    fn decode(cursor: &str) -> Option<Self>;
+}
+
+impl PageKey for u64 {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        cursor.parse().ok()
+    }
+}
+
+impl PageKey for PrincipalKey {
+    fn encode(&self) -> String {
+        self.0.to_text()
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        Principal::from_text(cursor).ok().map(PrincipalKey)
+    }
+}
+
+impl PageKey for ReactionTarget {
+    fn encode(&self) -> String {
+        match self {
+            ReactionTarget::Post(post_id) => format!("post-{}", post_id),
+            ReactionTarget::Comment(comment_id) => format!("comment-{}", comment_id),
+        }
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        match cursor.split_once('-')? {
+            ("post", post_id) => post_id.parse().ok().map(ReactionTarget::Post),
+            ("comment", comment_id) => comment_id.parse().ok().map(ReactionTarget::Comment),
+            _ => None,
+        }
+    }
+}
+
+// The kind goes last since it may itself contain ':'
+impl PageKey for ReactionKey {
+    fn encode(&self) -> String {
+        format!("{}:{}:{}", self.target.encode(), self.user.encode(), self.kind)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let mut parts = cursor.splitn(3, ':');
+        let target = ReactionTarget::decode(parts.next()?)?;
+        let user = PrincipalKey::decode(parts.next()?)?;
+        Some(ReactionKey { target, kind: parts.next()?.to_string(), user })
+    }
+}
+
+// Composite keys such as (created_at, id), joined with ':'
+impl<A: PageKey, B: PageKey> PageKey for (A, B) {
+    fn encode(&self) -> String {
+        format!("{}:{}", self.0.encode(), self.1.encode())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let (a, b) = cursor.split_once(':')?;
+        Some((A::decode(a)?, B::decode(b)?))
+    }
+}
+
+fn invalid_cursor() -> ApiError {
+    ApiError::Validation {
+        field: "cursor".to_string(),
+        reason: "Invalid cursor".to_string(),
+    }
+}
+
+// A requested limit of 0 means the default
+fn page_limit(limit: u64, default_limit: usize, max_limit: usize) -> usize {
+    match limit as usize {
+        0 => default_limit,
+        limit => limit.min(max_limit),
+    }
+}
+
+fn decode_cursor<K: PageKey>(cursor: Option<String>) -> Result<Option<K>, ApiError> {
+    cursor.map(|cursor| K::decode(&cursor).ok_or_else(invalid_cursor)).transpose()
+}
+
+// Entries of a stable map and the cursor of the next batch
+type EntryBatch<K, V> = (Vec<(K, V)>, Option<String>);
+
+// Helper function to read up to `limit` entries of a stable map, starting after the
+// key in the cursor. Also returns the cursor of the next batch, if there may be one.
+fn entries_after<K: PageKey + Storable + Clone, V: Storable>(
+    map: &StableBTreeMap<K, V, Memory>,
+    cursor: Option<String>,
+    limit: usize,
+) -> Result<EntryBatch<K, V>, ApiError> {
+    let start = match cursor {
+        Some(cursor) => std::ops::Bound::Excluded(K::decode(&cursor).ok_or_else(invalid_cursor)?),
+        None => std::ops::Bound::Unbounded,
+    };
+    let entries: Vec<(K, V)> = map.range((start, std::ops::Bound::Unbounded)).take(limit).collect();
+
+    let next_cursor = if entries.len() == limit {
+        entries.last().map(|(key, _)| key.encode())
+    } else {
+        None
+    };
+    Ok((entries, next_cursor))
+}
+
+// Multi-part listings such as the data export walk through a fixed list of phases.
+// Their cursors are "<phase index>" or "<phase index>/<cursor within the phase>".
+fn parse_phase_cursor(cursor: Option<String>, phase_count: usize) -> Result<(usize, Option<String>), ApiError> {
+    let Some(cursor) = cursor else {
+        return Ok((0, None));
+    };
+    let (phase, inner) = match cursor.split_once('/') {
+        Some((phase, inner)) => (phase, Some(inner.to_string())),
+        None => (cursor.as_str(), None),
+    };
+    let phase: usize = phase.parse().map_err(|_| invalid_cursor())?;
+    if phase >= phase_count {
+        return Err(invalid_cursor());
+    }
+    Ok((phase, inner))
+}
+
+// Continues within the current phase, or moves on to the next one
+fn next_phase_cursor(phase: usize, phase_count: usize, inner: Option<String>) -> Option<String> {
+    match inner {
+        Some(inner) => Some(format!("{}/{}", phase, inner)),
+        None if phase + 1 < phase_count => Some((phase + 1).to_string()),
+        None => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PageOrder {
+    NewestFirst,
+    OldestFirst,
+}
+
+// Bounds of a range of stable map keys
+type KeyRange<K> = (std::ops::Bound<K>, std::ops::Bound<K>);
+
+// Helper function to read one page straight from an ordered stable map, walking the key
+// range in page order. `load` turns an entry into an item or skips it, and `key` must
+// follow the map's order, so items created between two requests never shift the position
+// of the next page. Stops after `limit` + 1 items, so the cost of a page doesn't grow with
+// the size of the map.
+fn scan_page<K: Storable + Ord + Clone, V: Storable, T, P: PageKey>(
+    map: &StableBTreeMap<K, V, Memory>,
+    range: KeyRange<K>,
+    order: PageOrder,
+    limit: u64,
+    load: impl FnMut((K, V)) -> Option<T>,
+    key: impl Fn(&T) -> P,
+) -> Page<T> {
+    let limit = page_limit(limit, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT);
+
+    let entries = map.range(range);
+    let items: Vec<T> = match order {
+        PageOrder::NewestFirst => entries.rev().filter_map(load).take(limit + 1).collect(),
+        PageOrder::OldestFirst => entries.filter_map(load).take(limit + 1).collect(),
+    };
+    finish_page(items, limit, key)
+}
+
+// Cuts up to `limit` + 1 items read in page order down to a page. The extra item only
+// tells that there is a next page.
+fn finish_page<T, P: PageKey>(mut items: Vec<T>, limit: usize, key: impl Fn(&T) -> P) -> Page<T> {
+    let mut next_cursor = None;
+    if items.len() > limit {
+        items.truncate(limit);
+        next_cursor = items.last().map(|item| key(item).encode());
+    }
+    Page { items, next_cursor }
+}
+
+// Post IDs are handed out in creation order, so POSTS is ordered like post_page_key.
+// Range of post IDs before a (created_at, post_id) cursor.
+fn post_ids_before(after: Option<(u64, u64)>) -> KeyRange<u64> {
+    let end = after.map_or(std::ops::Bound::Unbounded, |(_, post_id)| std::ops::Bound::Excluded(post_id));
+    (std::ops::Bound::Unbounded, end)
+}
+
+// Range of IDs after an ID cursor
+fn ids_after(after: Option<u64>) -> KeyRange<u64> {
+    (after.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Excluded), std::ops::Bound::Unbounded)
+}
+
+// Range of a user's entries in a (user_id, item_id) set before an item ID cursor
+fn user_items_before(user_id: Principal, before: Option<u64>) -> KeyRange<(PrincipalKey, u64)> {
+    let key = PrincipalKey(user_id);
+    let end = before.map_or(std::ops::Bound::Included((key, u64::MAX)), |item_id| std::ops::Bound::Excluded((key, item_id)));
+    (std::ops::Bound::Included((key, 0)), end)
+}
+
+// Range of a user's entries in a (user_id, item_id) set after an item ID cursor
+fn user_items_after(user_id: Principal, after: Option<u64>) -> KeyRange<(PrincipalKey, u64)> {
+    let key = PrincipalKey(user_id);
+    let start = after.map_or(std::ops::Bound::Included((key, 0)), |item_id| std::ops::Bound::Excluded((key, item_id)));
+    (start, std::ops::Bound::Included((key, u64::MAX)))
+}
+
+// Range of USERS_BY_CREATION before a user_page_key cursor
+fn users_before(after: Option<(u64, PrincipalKey)>) -> KeyRange<(u64, PrincipalKey)> {
+    (std::ops::Bound::Unbounded, after.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Excluded))
+}
+
+// Range of a user's entries in a (user_id, principal) set after a principal cursor
+fn user_principals_after(user_id: Principal, after: Option<PrincipalKey>) -> KeyRange<(PrincipalKey, PrincipalKey)> {
+    let key = PrincipalKey(user_id);
+    let start = after.map_or(std::ops::Bound::Included((key, PrincipalKey::min())), |other| {
+        std::ops::Bound::Excluded((key, other))
+    });
+    (start, std::ops::Bound::Included((key, PrincipalKey::max())))
+}
+
+fn post_page_key(post: &Post) -> (u64, u64) {
+    (post.created_at, post.post_id)
+}
+
+fn comment_page_key(comment: &Comment) -> (u64, u64) {
+    (comment.created_at, comment.comment_id)
+}
+
+fn user_page_key(user: &User) -> (u64, PrincipalKey) {
+    (user.created_at, PrincipalKey(user.user_id))
+}
+
+// Helper function to page through users newest first, keeping those matching a filter
+fn scan_users(filter: impl Fn(&User) -> bool, cursor: Option<String>, limit: u64) -> Result<Page<User>, ApiError> {
+    let after = decode_cursor(cursor)?;
+    Ok(USERS_BY_CREATION.with(|users| {
+        let load = |((_, user_key), ()): ((u64, PrincipalKey), ())| load_user(&user_key.0).filter(|user| filter(user));
+        scan_page(&users.borrow(), users_before(after), PageOrder::NewestFirst, limit, load, user_page_key)
+    }))
+}
+
+// Helper function to page through the live posts of several authors newest first.
+// Merges the authors' AUTHOR_POSTS ranges by post ID, which follows creation order,
+// so a page reads about `limit` posts however much the authors have written.
+fn scan_author_posts(authors: &[Principal], cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let after: Option<(u64, u64)> = decode_cursor(cursor)?;
+    let before = after.map(|(_, post_id)| post_id);
+    let limit = page_limit(limit, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT);
+
+    Ok(AUTHOR_POSTS.with(|author_posts| {
+        let author_posts = author_posts.borrow();
+        let mut streams: Vec<_> = authors.iter()
+            .map(|author| author_posts.range(user_items_before(*author, before)).rev())
+            .collect();
+
+        // Newest unread post ID of each author
+        let mut heads = std::collections::BinaryHeap::new();
+        for (index, stream) in streams.iter_mut().enumerate() {
+            if let Some(((_, post_id), ())) = stream.next() {
+                heads.push((post_id, index));
+            }
+        }
+
+        let mut items = Vec::new();
+        while items.len() <= limit {
+            let Some((post_id, index)) = heads.pop() else {
+                break;
+            };
+            if let Some(((_, next_id), ())) = streams[index].next() {
+                heads.push((next_id, index));
+            }
+            if let Some(post) = load_post(post_id).filter(|post| !post.is_deleted) {
+                items.push(post);
+            }
+        }
+        finish_page(items, limit, post_page_key)
+    }))
+}
+
+fn feed_entry_key(entry: &FeedEntry) -> (u64, (u64, PrincipalKey)) {
+    let user = entry.reposted_by.unwrap_or(entry.post.author_id);
+    (entry.entry_at, (entry.post.post_id, PrincipalKey(user)))
+}
+
+// Post IDs are handed out in creation order, so the posts that come before a
+// (created_at, post_id) key take up a prefix of the IDs. Binary searches for its end.
+fn post_ids_end(before: (u64, u64)) -> u64 {
+    POSTS.with(|posts| {
+        let posts = posts.borrow();
+        let mut low = 0;
+        let mut high = posts.last_key_value().map_or(0, |(post_id, _)| post_id + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match posts.range(..=mid).next_back() {
+                Some((post_id, post)) if post_page_key(&post) >= before => high = post_id,
+                _ => low = mid + 1,
+            }
+        }
+        low
+    })
+}
+
+// Helper function to page through the timelines of several users newest first: the live
+// posts they wrote and the posts they reposted, at the time they reposted them. Merges the
+// users' AUTHOR_POSTS and USER_REPOSTS ranges by time, so a page reads about `limit`
+// entries however long the timelines are.
+fn scan_feed(users: &[Principal], cursor: Option<String>, limit: u64) -> Result<Page<FeedEntry>, ApiError> {
+    let after: Option<(u64, (u64, PrincipalKey))> = decode_cursor(cursor)?;
+    let limit = page_limit(limit, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT);
+    let before_cursor = |entry: &FeedEntry| after.is_none_or(|after| feed_entry_key(entry) < after);
+    let posts_end = after.map(|(entry_at, (post_id, _))| post_ids_end((entry_at, post_id)));
+
+    AUTHOR_POSTS.with(|author_posts| {
+        USER_REPOSTS.with(|user_reposts| {
+            let author_posts = author_posts.borrow();
+            let user_reposts = user_reposts.borrow();
+
+            let mut streams: Vec<Box<dyn Iterator<Item = FeedEntry> + '_>> = Vec::new();
+            for user_id in users {
+                let user_key = PrincipalKey(*user_id);
+                let end = posts_end.map_or(std::ops::Bound::Included((user_key, u64::MAX)), |end| std::ops::Bound::Excluded((user_key, end)));
+                let posts = author_posts.range((std::ops::Bound::Included((user_key, 0)), end)).rev()
+                    .filter_map(|((_, post_id), ())| load_post(post_id))
+                    .filter(|post| !post.is_deleted)
+                    .map(|post| FeedEntry { entry_at: post.created_at, post, reposted_by: None })
+                    .filter(before_cursor);
+                streams.push(Box::new(posts));
+
+                let end = after.map_or((user_key, u64::MAX, u64::MAX), |(entry_at, (post_id, _))| (user_key, entry_at, post_id));
+                let reposts = user_reposts.range((user_key, 0, 0)..=end).rev()
+                    .filter_map(|((_, reposted_at, post_id), ())| {
+                        let post = load_post(post_id).filter(|post| !post.is_deleted)?;
+                        Some(FeedEntry { post, reposted_by: Some(*user_id), entry_at: reposted_at })
+                    })
+                    .filter(before_cursor);
+                streams.push(Box::new(reposts));
+            }
+
+            // Newest unread entry of each stream
+            let mut heads: Vec<Option<FeedEntry>> = streams.iter_mut().map(|stream| stream.next()).collect();
+            let mut order = std::collections::BinaryHeap::new();
+            for (index, head) in heads.iter().enumerate() {
+                if let Some(entry) = head {
+                    order.push((feed_entry_key(entry), index));
+                }
+            }
+
+            let mut items = Vec::new();
+            while items.len() <= limit {
+                let Some((_, index)) = order.pop() else {
+                    break;
+                };
+                let next = streams[index].next();
+                if let Some(entry) = &next {
+                    order.push((feed_entry_key(entry), index));
+                }
+                if let Some(entry) = std::mem::replace(&mut heads[index], next) {
+                    items.push(entry);
+                }
+            }
+            Ok(finish_page(items, limit, feed_entry_key))
+        })
+    })
+}
+
+// Authors shown in a user's feed: everyone they follow and themselves
+fn feed_authors(user_id: Principal) -> Vec<Principal> {
+    let mut authors: Vec<Principal> = following_of(user_id).into_iter().map(|follow| follow.following_id).collect();
+    authors.push(user_id);
+    authors
+}
+
+// Helper function to page through posts newest first, keeping those matching a filter
+fn scan_posts(filter: impl Fn(&Post) -> bool, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let after = decode_cursor(cursor)?;
+    Ok(POSTS.with(|posts| {
+        let load = |(_, post): (u64, Post)| Some(post).filter(|post| filter(post));
+        scan_page(&posts.borrow(), post_ids_before(after), PageOrder::NewestFirst, limit, load, post_page_key)
+    }))
+}
+
+#[ic_cdk::update]
+fn create_user(
+    username: String,
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> String {
+    match create_user_v2(username, full_name, email, bio, profile_pic, location, website) {
+        Ok(_) => "User created successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn create_user_v2(
+    username: String,
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> Result<(), ApiError> {
+    let user_id = caller();
+    let current_timestamp = current_time();
+
+    // Check if user already exists
+    if user_exists(&user_id) {
+        return Err(ApiError::AlreadyExists { resource: "User".to_string() });
+    }
+
+    let username = check_username(&username, user_id, false)?;
+
+    let user = User {
+        user_id,
+        username,
+        full_name,
+        email,
+        bio,
+        profile_pic,
+        location,
+        website,
+        is_admin: is_admin(&user_id), // Check if this user is a predefined admin
+        created_at: current_timestamp,
+        last_active: current_timestamp,
+        followers_count: 0,
+        following_count: 0,
+    };
+
+    index_user(&user);
+    save_user(user);
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_user(user_id: Principal) -> Option<PublicProfile> {
+    load_user(&user_id).map(|user| public_profile(user, caller()))
+}
+
+// Resolves a handle such as the one in `/u/alice` (a leading '@' is accepted),
+// following the redirect of a recently renamed account
+#[ic_cdk::query]
+fn get_user_by_username(username: String) -> Option<PublicProfile> {
+    resolve_username(username.trim().trim_start_matches('@'))
+        .and_then(|user_id| load_user(&user_id))
+        .map(|user| public_profile(user, caller()))
+}
+
+#[ic_cdk::update]
+fn update_user(
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> String {
+    match update_user_v2(full_name, email, bio, profile_pic, location, website) {
+        Ok(_) => "User updated successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn update_user_v2(
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> Result<(), ApiError> {
+    let user_id = caller();
+
+    let mut user = load_user(&user_id).ok_or(ApiError::NotRegistered)?;
+    if let Some(name) = full_name {
+        user.full_name = Some(name);
+    }
+    if let Some(email) = email {
+        user.email = Some(email);
+    }
+    if let Some(bio) = bio {
+        user.bio = Some(bio);
+    }
+    if let Some(pic) = profile_pic {
+        user.profile_pic = Some(pic);
+    }
+    if let Some(loc) = location {
+        user.location = Some(loc);
+    }
+    if let Some(web) = website {
+        user.website = Some(web);
+    }
+    // Update last active timestamp
+    user.last_active = current_time();
+    save_user(user);
+
+    Ok(())
+}
+
+// Helper function to delete a user together with everything they left behind: their
+// posts and comments are deleted, their likes, shares and follows are withdrawn with
+// the counters on other users' content adjusted, and their settings and handles are
+// released. Returns the removed profile, if there was one.
+fn delete_user_cascade(user_id: Principal) -> Option<User> {
+    // Delete all posts by this user, along with their comments, likes and shares
+    for post in load_posts(post_ids_by_author(user_id)) {
+        delete_post_cascade(post);
+    }
+
+    // Delete all comments by this user. Each one is loaded in turn, as updating a
+    // parent's reply count may change a later comment in the list.
+    for comment_id in comment_ids_by_author(user_id) {
+        let Some(mut comment) = load_comment(comment_id).filter(|comment| !comment.is_deleted) else {
+            continue;
+        };
+        move_comment_to_trash(&mut comment, current_time());
+        let post_id = comment.post_id;
+        let parent_id = comment.parent_id;
+        save_comment(comment);
+        if let Some(parent_id) = parent_id {
+            recount_replies(parent_id);
+        }
+
+        if let Some(mut post) = load_post(post_id) {
+            post.comments_count = post.comments_count.saturating_sub(1);
+            save_post(post);
+        }
+    }
+
+    // Withdraw the user's reactions, likes included, and shares
+    for target in user_reaction_targets(user_id) {
+        clear_reaction(user_id, target);
+    }
+    let shared_post_ids = USER_SHARES.with(|user_shares| user_item_ids(&user_shares.borrow(), user_id));
+    for post_id in shared_post_ids {
+        remove_repost(user_id, post_id);
+    }
+
+    remove_bookmarks(user_id);
+
+    // Remove user from following relationships, both the users they follow and the users following them
+    for follow in following_of(user_id) {
+        remove_follow(follow.follower_id, follow.following_id);
+    }
+    for follow in followers_of(user_id) {
+        remove_follow(follow.follower_id, follow.following_id);
+    }
+
+    // Release handles and drop per-user records
+    remove_username_redirects(user_id);
+    let changes = username_history(user_id);
+    USERNAME_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for change in changes {
+            history.remove(&(PrincipalKey(user_id), change.changed_at));
+        }
+    });
+    SETTINGS.with(|settings| settings.borrow_mut().remove(&PrincipalKey(user_id)));
+    remove_user_notifications(user_id);
+    leave_all_conversations(user_id);
+    PROMOTED_ADMINS.with(|admins| admins.borrow_mut().remove(&PrincipalKey(user_id)));
+
+    // Finally, delete the user
+    let removed = USERS.with(|users| users.borrow_mut().remove(&PrincipalKey(user_id)));
+    if let Some(user) = &removed {
+        unindex_user(user);
+    }
+    removed
+}
+
+#[ic_cdk::update]
+fn delete_user() -> String {
+    match delete_user_v2() {
+        Ok(_) => "User deleted successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_user_v2() -> Result<(), ApiError> {
+    let user_id = caller();
+
+    require_registered(&user_id)?;
+    delete_user_cascade(user_id);
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_all_users() -> Vec<PublicProfile> {
+    public_profiles(collect_users(|_| true))
+}
+
+#[ic_cdk::query]
+fn get_all_users_paged(cursor: Option<String>, limit: u64) -> Result<Page<PublicProfile>, ApiError> {
+    scan_users(|_| true, cursor, limit).map(public_profile_page)
+}
+
+#[ic_cdk::query]
+fn debug_user_count() -> u64 {
+    USERS.with(|users| users.borrow().len())
+}
+
+#[ic_cdk::query]
+fn debug_user_exists(user_id: Principal) -> bool {
+    user_exists(&user_id)
+}
+
+// ============ DATA EXPORT ============
+
+// Bumped whenever the layout of DataExport changes
+const EXPORT_FORMAT_VERSION: u8 = 6;
+
+const EXPORT_SECTIONS: [ExportSection; 12] = [
+    ExportSection::Profile,
+    ExportSection::Posts,
+    ExportSection::Comments,
+    ExportSection::LikedPosts,
+    ExportSection::LikedComments,
+    ExportSection::Shares,
+    ExportSection::Following,
+    ExportSection::Followers,
+    ExportSection::Conversations,
+    ExportSection::Messages,
+    ExportSection::Reactions,
+    ExportSection::Bookmarks,
+];
+
+// Everything stored about the caller, one section page at a time. Start without a
+// cursor and keep passing `next_cursor` back until it comes back empty. Posts and
+// comments include deleted ones.
+#[ic_cdk::query]
+fn export_my_data(cursor: Option<String>, limit: u64) -> Result<DataExport, ApiError> {
+    let user_id = caller();
+    let user = load_user(&user_id).ok_or(ApiError::NotRegistered)?;
+    let (section_index, inner_cursor) = parse_phase_cursor(cursor, EXPORT_SECTIONS.len())?;
+    let section = EXPORT_SECTIONS[section_index];
+
+    let mut export = DataExport {
+        format_version: EXPORT_FORMAT_VERSION,
+        user_id,
+        section,
+        profile: None,
+        settings: None,
+        username_history: Vec::new(),
+        posts: Vec::new(),
+        comments: Vec::new(),
+        liked_post_ids: Vec::new(),
+        liked_comment_ids: Vec::new(),
+        shared_post_ids: Vec::new(),
+        following: Vec::new(),
+        followers: Vec::new(),
+        conversations: Vec::new(),
+        messages: Vec::new(),
+        reactions: Vec::new(),
+        bookmarks: Vec::new(),
+        next_cursor: None,
+    };
+
+    // Each section walks the caller's range of a user-keyed map, the author indexes
+    // include deleted posts and comments
+    let next_inner_cursor = match section {
+        ExportSection::Profile => {
+            export.profile = Some(user);
+            export.settings = Some(load_settings(user_id));
+            export.username_history = username_history(user_id);
+            None
+        }
+        ExportSection::Posts => {
+            let range = user_items_after(user_id, decode_cursor(inner_cursor)?);
+            let page = AUTHOR_POSTS.with(|author_posts| {
+                let load = |((_, post_id), ()): ((PrincipalKey, u64), ())| load_post(post_id);
+                scan_page(&author_posts.borrow(), range, PageOrder::OldestFirst, limit, load, |post| post.post_id)
+            });
+            export.posts = page.items;
+            page.next_cursor
+        }
+        ExportSection::Comments => {
+            let range = user_items_after(user_id, decode_cursor(inner_cursor)?);
+            let page = AUTHOR_COMMENTS.with(|author_comments| {
+                let load = |((_, comment_id), ()): ((PrincipalKey, u64), ())| load_comment(comment_id);
+                scan_page(&author_comments.borrow(), range, PageOrder::OldestFirst, limit, load, |comment| comment.comment_id)
+            });
+            export.comments = page.items;
+            page.next_cursor
+        }
+        ExportSection::LikedPosts => {
+            let page = USER_LIKES.with(|user_likes| user_item_page(&user_likes.borrow(), user_id, inner_cursor, limit))?;
+            export.liked_post_ids = page.items;
+            page.next_cursor
+        }
+        ExportSection::LikedComments => {
+            let page = USER_COMMENT_LIKES.with(|user_comment_likes| {
+                user_item_page(&user_comment_likes.borrow(), user_id, inner_cursor, limit)
+            })?;
+            export.liked_comment_ids = page.items;
+            page.next_cursor
+        }
+        ExportSection::Shares => {
+            let page = USER_SHARES.with(|user_shares| user_item_page(&user_shares.borrow(), user_id, inner_cursor, limit))?;
+            export.shared_post_ids = page.items;
+            page.next_cursor
+        }
+        ExportSection::Following => {
+            let page = get_following_paged(user_id, inner_cursor, limit)?;
+            export.following = page.items;
+            page.next_cursor
+        }
+        ExportSection::Followers => {
+            let page = get_followers_paged(user_id, inner_cursor, limit)?;
+            export.followers = page.items;
+            page.next_cursor
+        }
+        ExportSection::Conversations => {
+            let range = user_items_after(user_id, decode_cursor(inner_cursor)?);
+            let page = USER_CONVERSATIONS.with(|user_conversations| {
+                let load = |((_, conversation_id), ()): ((PrincipalKey, u64), ())| load_conversation(conversation_id);
+                scan_page(&user_conversations.borrow(), range, PageOrder::OldestFirst, limit, load, |conversation| {
+                    conversation.conversation_id
+                })
+            });
+            export.conversations = page.items;
+            page.next_cursor
+        }
+        ExportSection::Messages => {
+            let range = user_items_after(user_id, decode_cursor(inner_cursor)?);
+            let page = SENT_MESSAGES.with(|sent| {
+                let load = |((_, message_id), conversation_id): ((PrincipalKey, u64), u64)| load_message(conversation_id, message_id);
+                scan_page(&sent.borrow(), range, PageOrder::OldestFirst, limit, load, |message| message.message_id)
+            });
+            export.messages = page.items;
+            page.next_cursor
+        }
+        ExportSection::Reactions => {
+            let key = PrincipalKey(user_id);
+            let start = match decode_cursor(inner_cursor)? {
+                Some(target) => std::ops::Bound::Excluded((key, target)),
+                None => std::ops::Bound::Included((key, ReactionTarget::Post(0))),
+            };
+            let range = (start, std::ops::Bound::Included((key, ReactionTarget::Comment(u64::MAX))));
+            let page = USER_REACTIONS.with(|reactions| {
+                let load = |((_, target), kind): ((PrincipalKey, ReactionTarget), String)| Some(Reaction { target, kind });
+                scan_page(&reactions.borrow(), range, PageOrder::OldestFirst, limit, load, |reaction| reaction.target)
+            });
+            export.reactions = page.items;
+            page.next_cursor
+        }
+        ExportSection::Bookmarks => {
+            let range = user_items_after(user_id, decode_cursor(inner_cursor)?);
+            let page = BOOKMARKS.with(|bookmarks| {
+                let load = |(_, bookmark): ((PrincipalKey, u64), Bookmark)| Some(bookmark);
+                scan_page(&bookmarks.borrow(), range, PageOrder::OldestFirst, limit, load, |bookmark| bookmark.post_id)
+            });
+            export.bookmarks = page.items;
+            page.next_cursor
+        }
+    };
+
+    export.next_cursor = next_phase_cursor(section_index, EXPORT_SECTIONS.len(), next_inner_cursor);
+
+    Ok(export)
+}
+
+// ============ POST FUNCTIONS ============
+
+#[ic_cdk::update]
+fn create_post(content: String, hashtags: Vec<String>, media_urls: Vec<String>) -> Result<u64, String> {
+    create_post_v2(content, hashtags, media_urls).map_err(|e| e.to_string())
+}
+
+#[ic_cdk::update]
+fn create_post_v2(content: String, hashtags: Vec<String>, media_urls: Vec<String>) -> Result<u64, ApiError> {
+    insert_post(caller(), content, hashtags, media_urls, None)
+}
+
+// Creates a post, or a quote post when `quoted_post_id` is set
+fn insert_post(
+    author_id: Principal,
+    content: String,
+    hashtags: Vec<String>,
+    media_urls: Vec<String>,
+    quoted_post_id: Option<u64>,
+) -> Result<u64, ApiError> {
+    // Check if user exists
+    require_registered(&author_id)?;
+
+    // A quote must point at a post that is still visible
+    let quoted_post = quoted_post_id.map(load_active_post).transpose()?;
+
+    // Validate content
+    validate_content(&content, "Post", 2000)?;
+
+    // Extract mentions from content (@username)
+    let mentions = extract_mentions(&content);
+
+    let post_id = next_post_id();
+    let current_timestamp = current_time();
+
+    let post = Post {
+        post_id,
+        author_id,
+        content: content.trim().to_string(),
+        created_at: current_timestamp,
+        updated_at: None,
+        likes: 0,
+        comments_count: 0,
+        shares_count: 0,
+        hashtags,
+        mentions,
+        media_urls,
+        is_deleted: false,
+        deleted_at: None,
+        reactions: Vec::new(),
+        quoted_post_id,
+    };
+
+    // Store the post
+    index_post(&post);
+    notify_mentions(&post.mentions, author_id, post_id, None);
+    save_post(post);
+
+    // Quoting counts as sharing for the quoted post's author
+    if let Some(quoted_post) = quoted_post {
+        notify(quoted_post.author_id, author_id, NotificationKind::Share, Some(quoted_post.post_id), None);
+    }
+
+    // Update user's last active timestamp
+    if let Some(mut user) = load_user(&author_id) {
+        user.last_active = current_timestamp;
+        save_user(user);
+    }
+
+    Ok(post_id)
+}
+
+#[ic_cdk::query]
+fn get_post(post_id: u64) -> Option<Post> {
+    load_post(post_id).filter(|post| !post.is_deleted)
+}
+
+#[ic_cdk::query]
+fn get_all_posts() -> Vec<Post> {
+    let mut post_list: Vec<Post> = POSTS.with(|posts| {
+        posts.borrow().iter()
+            .map(|(_, post)| post)
+            .filter(|post| !post.is_deleted)
+            .collect()
+    });
+
+    // Sort by creation date (most recent first)
+    post_list.sort_by_key(|post| std::cmp::Reverse(post.created_at));
+
+    post_list
+}
+
+#[ic_cdk::query]
+fn get_user_posts(user_id: Principal) -> Vec<Post> {
+    let mut user_posts = load_posts(post_ids_by_author(user_id));
+
+    // Sort by creation date (most recent first)
+    user_posts.sort_by_key(|post| std::cmp::Reverse(post.created_at));
+
+    user_posts
+}
+
+#[ic_cdk::query]
+fn get_all_posts_paged(cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    scan_posts(|post| !post.is_deleted, cursor, limit)
+}
+
+#[ic_cdk::query]
+fn get_user_posts_paged(user_id: Principal, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    scan_author_posts(&[user_id], cursor, limit)
+}
+
+#[ic_cdk::update]
+fn update_post(post_id: u64, new_content: String) -> String {
+    match update_post_v2(post_id, new_content) {
+        Ok(_) => "Post updated successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn update_post_v2(post_id: u64, new_content: String) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    // Validate content
+    validate_content(&new_content, "Post", 2000)?;
+
+    let mut post = load_post(post_id).ok_or(ApiError::NotFound { resource: "Post".to_string() })?;
+
+    // Check if caller is the author or admin
+    if post.author_id != caller_id && !is_admin(&caller_id) {
+        return Err(ApiError::Unauthorized { reason: "You can only edit your own posts".to_string() });
+    }
+
+    if post.is_deleted {
+        return Err(ApiError::Deleted { resource: "Post".to_string() });
+    }
+
+    check_edit_window(post.created_at, "post")?;
+
+    let now = current_time();
+    let revision = Revision {
+        content: std::mem::replace(&mut post.content, new_content.trim().to_string()),
+        written_at: post.updated_at.unwrap_or(post.created_at),
+        replaced_at: now,
+        edited_by: caller_id,
+    };
+    add_revision(&POST_REVISIONS, post_id, revision);
+
+    post.updated_at = Some(now);
+    save_post(post);
+
+    Ok(())
+}
+
+// Hides a post's comments and withdraws the likes on them, as well as the post's own
+// likes and shares. Leaves the post record itself to the caller. The comments share
+// the post's deletion time, which is how restore_post finds them again.
+fn cascade_post_deletion(post_id: u64, deleted_at: u64) {
+    for mut comment in load_comments(comment_ids_for_post(post_id)) {
+        remove_all_reactions(ReactionTarget::Comment(comment.comment_id));
+        comment.likes = 0;
+        comment.reactions.clear();
+        move_comment_to_trash(&mut comment, deleted_at);
+        save_comment(comment);
+    }
+    recount_thread_replies(post_id);
+
+    remove_all_reactions(ReactionTarget::Post(post_id));
+    remove_all_reposts(post_id);
+}
+
+// Soft-deletes a post together with its comments, likes and shares
+fn delete_post_cascade(mut post: Post) {
+    let deleted_at = current_time();
+    cascade_post_deletion(post.post_id, deleted_at);
+
+    move_post_to_trash(&mut post, deleted_at);
+    post.likes = 0;
+    post.reactions.clear();
+    post.shares_count = 0;
+    post.comments_count = 0;
+    save_post(post);
+}
+
+#[ic_cdk::update]
+fn delete_post(post_id: u64) -> String {
+    match delete_post_v2(post_id) {
+        Ok(_) => "Post deleted successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_post_v2(post_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    let post = load_post(post_id).ok_or(ApiError::NotFound { resource: "Post".to_string() })?;
+
+    // Check if caller is the author or admin
+    if post.author_id != caller_id && !is_admin(&caller_id) {
+        return Err(ApiError::Unauthorized { reason: "You can only delete your own posts".to_string() });
+    }
+
+    if post.is_deleted {
+        return Err(ApiError::Deleted { resource: "Post".to_string() });
+    }
+
+    delete_post_cascade(post);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn like_post(post_id: u64) -> String {
+    match like_post_v2(post_id) {
+        Ok(_) => "Post liked successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn like_post_v2(post_id: u64) -> Result<(), ApiError> {
+    add_reaction(caller(), ReactionTarget::Post(post_id), LIKE_REACTION, "Like")
+}
+
+#[ic_cdk::update]
+fn unlike_post(post_id: u64) -> String {
+    match unlike_post_v2(post_id) {
+        Ok(_) => "Post unliked successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn unlike_post_v2(post_id: u64) -> Result<(), ApiError> {
+    withdraw_reaction(caller(), ReactionTarget::Post(post_id), Some(LIKE_REACTION), "Like")
+}
+
+#[ic_cdk::query]
+fn has_user_liked_post(post_id: u64) -> bool {
+    let caller_id = caller();
+    USER_LIKES.with(|user_likes| user_likes.borrow().contains_key(&(PrincipalKey(caller_id), post_id)))
+}
+
+#[ic_cdk::query]
+fn get_user_liked_posts() -> Vec<u64> {
+    let caller_id = caller();
+    USER_LIKES.with(|user_likes| user_item_ids(&user_likes.borrow(), caller_id))
+}
+
+// ============ ADMIN-ONLY FUNCTIONS ============
+
+#[ic_cdk::query]
+fn is_caller_admin() -> bool {
+    is_admin(&caller())
+}
+
+#[ic_cdk::update]
+fn admin_delete_user(target_user_id: Principal) -> String {
+    match admin_delete_user_v2(target_user_id) {
+        Ok(_) => format!("User {} and all their data deleted successfully by admin", target_user_id),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn admin_delete_user_v2(target_user_id: Principal) -> Result<(), ApiError> {
+    require_admin()?;
+
+    // Leftover data is cleaned up even if the profile itself is already gone
+    if delete_user_cascade(target_user_id).is_none() {
+        return Err(ApiError::NotFound { resource: "User".to_string() });
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn admin_promote_user(target_user_id: Principal) -> String {
+    match admin_promote_user_v2(target_user_id) {
+        Ok(_) => format!("User {} promoted to admin successfully", target_user_id),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn admin_promote_user_v2(target_user_id: Principal) -> Result<(), ApiError> {
+    require_admin()?;
+
+    // Add to promoted admins
+    PROMOTED_ADMINS.with(|admins| admins.borrow_mut().insert(PrincipalKey(target_user_id), ()));
+
+    // Update user's admin status
+    let mut user = load_user(&target_user_id).ok_or(ApiError::NotFound { resource: "User".to_string() })?;
+    user.is_admin = true;
+    save_user(user);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn admin_demote_user(target_user_id: Principal) -> String {
+    match admin_demote_user_v2(target_user_id) {
+        Ok(_) => format!("User {} demoted from admin successfully", target_user_id),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn admin_demote_user_v2(target_user_id: Principal) -> Result<(), ApiError> {
+    require_admin()?;
+
+    // Check if user is a predefined admin (cannot be demoted)
+    if PREDEFINED_ADMINS.contains(&target_user_id) {
+        return Err(ApiError::Unauthorized { reason: "Cannot demote predefined admin".to_string() });
+    }
+
+    // Remove from promoted admins
+    PROMOTED_ADMINS.with(|admins| admins.borrow_mut().remove(&PrincipalKey(target_user_id)));
+
+    // Update user's admin status
+    let mut user = load_user(&target_user_id).ok_or(ApiError::NotFound { resource: "User".to_string() })?;
+    user.is_admin = false;
+    save_user(user);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn admin_create_follow_relationship(follower: Principal, following: Principal) -> String {
+    match admin_create_follow_relationship_v2(follower, following) {
+        Ok(_) => format!("Follow relationship created: {} now follows {}", follower, following),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn admin_create_follow_relationship_v2(follower: Principal, following: Principal) -> Result<(), ApiError> {
+    require_admin()?;
+
+    // Check if both users exist
+    if !user_exists(&follower) {
+        return Err(ApiError::NotFound { resource: "Follower user".to_string() });
+    }
+    if !user_exists(&following) {
+        return Err(ApiError::NotFound { resource: "Following user".to_string() });
+    }
+
+    // Can't follow yourself
+    if follower == following {
+        return Err(ApiError::Validation {
+            field: "following".to_string(),
+            reason: "User cannot follow themselves".to_string(),
+        });
+    }
+
+    if !add_follow(follower, following, current_time()) {
+        return Err(ApiError::AlreadyExists { resource: "Follow relationship".to_string() });
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_get_stats() -> Result<AdminStats, String> {
+    admin_get_stats_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_stats_v2() -> Result<AdminStats, ApiError> {
+    require_admin()?;
+
+    USERS.with(|users| {
+        let users = users.borrow();
+        let current_timestamp = current_time();
+        let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000); // 24 hours in nanoseconds
+
+        let total_users = users.len();
+        let total_admins = users.iter().filter(|(_, user)| user.is_admin).count() as u64;
+        let recent_registrations = users.iter()
+            .filter(|(_, user)| user.created_at > twenty_four_hours_ago)
+            .count() as u64;
+
+        Ok(AdminStats {
+            total_users,
+            total_admins,
+            recent_registrations,
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_post_stats() -> Result<PostStats, String> {
+    admin_get_post_stats_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_post_stats_v2() -> Result<PostStats, ApiError> {
+    require_admin()?;
+
+    let current_timestamp = current_time();
+    let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000);
+
+    let mut total_posts = 0;
+    let mut recent_posts = 0;
+    let mut total_likes = 0;
+    POSTS.with(|posts| {
+        for (_, post) in posts.borrow().iter().filter(|(_, post)| !post.is_deleted) {
+            total_posts += 1;
+            if post.created_at > twenty_four_hours_ago {
+                recent_posts += 1;
+            }
+            total_likes += post.likes;
+        }
+    });
+
+    Ok(PostStats {
+        total_posts,
+        recent_posts,
+        total_likes,
+    })
+}
+
+#[ic_cdk::update]
+fn admin_delete_post(post_id: u64) -> String {
+    match admin_delete_post_v2(post_id) {
+        Ok(_) => format!("Post {} deleted successfully by admin", post_id),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn admin_delete_post_v2(post_id: u64) -> Result<(), ApiError> {
+    require_admin()?;
+
+    let post = load_active_post(post_id)?;
+    delete_post_cascade(post);
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_get_all_posts() -> Result<Vec<Post>, String> {
+    admin_get_all_posts_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_all_posts_v2() -> Result<Vec<Post>, ApiError> {
+    require_admin()?;
+
+    let mut post_list: Vec<Post> = POSTS.with(|posts| {
+        posts.borrow().iter().map(|(_, post)| post).collect()
+    });
+
+    // Sort by creation date (most recent first)
+    post_list.sort_by_key(|post| std::cmp::Reverse(post.created_at));
+
+    Ok(post_list)
+}
+
+#[ic_cdk::query]
+fn admin_get_all_posts_paged(cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    require_admin()?;
+
+    scan_posts(|_| true, cursor, limit)
+}
+
+#[ic_cdk::query]
+fn admin_get_post_ids() -> Result<Vec<u64>, String> {
+    admin_get_post_ids_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_post_ids_v2() -> Result<Vec<u64>, ApiError> {
+    require_admin()?;
+
+    let post_ids: Vec<u64> = POSTS.with(|posts| {
+        posts.borrow().iter().map(|(post_id, _)| post_id).collect()
+    });
+    Ok(post_ids)
+}
+
+#[ic_cdk::query]
+fn admin_get_post_ids_paged(cursor: Option<String>, limit: u64) -> Result<Page<u64>, ApiError> {
+    require_admin()?;
+
+    let after = decode_cursor(cursor)?;
+    Ok(POSTS.with(|posts| {
+        scan_page(&posts.borrow(), ids_after(after), PageOrder::OldestFirst, limit, |(post_id, _)| Some(post_id), |post_id| *post_id)
+    }))
+}
+
+#[ic_cdk::update]
+fn admin_clear_all_posts() -> Result<String, String> {
+    match admin_clear_all_posts_v2() {
+        Ok(count) => Ok(format!("Cleared {} posts successfully", count)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Returns the number of posts removed
+#[ic_cdk::update]
+fn admin_clear_all_posts_v2() -> Result<u64, ApiError> {
+    require_admin()?;
+
+    let removed_posts: Vec<Post> = POSTS.with(|posts| {
+        let mut posts = posts.borrow_mut();
+        let post_ids: Vec<u64> = posts.iter().map(|(post_id, _)| post_id).collect();
+        post_ids.iter().filter_map(|post_id| posts.remove(post_id)).collect()
+    });
+
+    // Drop the index entries of the removed posts, hide their comments and withdraw their
+    // likes and shares. Revisions and bookmarks go as when a post is purged.
+    for post in &removed_posts {
+        forget_post(post);
+        cascade_post_deletion(post.post_id, current_time());
+        remove_revisions(&POST_REVISIONS, post.post_id);
+        for user_id in item_user_ids(&POST_BOOKMARKERS, post.post_id) {
+            delete_bookmark(user_id, post.post_id);
+        }
+    }
+    Ok(removed_posts.len() as u64)
+}
+
+#[ic_cdk::update]
+fn admin_clear_profile_pics() -> Result<String, String> {
+    match admin_clear_profile_pics_v2() {
+        Ok(count) => Ok(format!("Cleared {} profile pictures successfully", count)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Returns the number of profile pictures removed
+#[ic_cdk::update]
+fn admin_clear_profile_pics_v2() -> Result<u64, ApiError> {
+    require_admin()?;
+
+    let count = USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let users_with_pics: Vec<User> = users.iter()
+            .map(|(_, user)| user)
+            .filter(|user| user.profile_pic.is_some())
+            .collect();
+        let count = users_with_pics.len() as u64;
+        for mut user in users_with_pics {
+            user.profile_pic = None;
+            users.insert(PrincipalKey(user.user_id), user);
+        }
+        count
+    });
+    Ok(count)
+}
+
+#[ic_cdk::query]
+fn admin_get_all_users_detailed() -> Result<Vec<User>, String> {
+    admin_get_all_users_detailed_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_all_users_detailed_v2() -> Result<Vec<User>, ApiError> {
+    require_admin()?;
+
+    Ok(USERS.with(|users| users.borrow().iter().map(|(_, user)| user).collect()))
+}
+
+#[ic_cdk::query]
+fn admin_search_users(query: String) -> Result<Vec<User>, String> {
+    admin_search_users_v2(query).map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_search_users_v2(query: String) -> Result<Vec<User>, ApiError> {
+    require_admin()?;
+
+    let query_lower = query.to_lowercase();
+    let filtered_users: Vec<User> = USERS.with(|users| {
+        users.borrow().iter()
+            .map(|(_, user)| user)
+            .filter(|user| {
+                user.username.to_lowercase().contains(&query_lower) ||
+                user.full_name.as_ref().is_some_and(|name| name.to_lowercase().contains(&query_lower)) ||
+                user.email.as_ref().is_some_and(|email| email.to_lowercase().contains(&query_lower))
+            })
+            .collect()
+    });
+    Ok(filtered_users)
+}
+
+#[ic_cdk::query]
+fn admin_search_users_paged(query: String, cursor: Option<String>, limit: u64) -> Result<Page<User>, ApiError> {
+    require_admin()?;
+
+    let query_lower = query.to_lowercase();
+    let matches = |user: &User| {
+        user.username.to_lowercase().contains(&query_lower) ||
+        user.full_name.as_ref().is_some_and(|name| name.to_lowercase().contains(&query_lower)) ||
+        user.email.as_ref().is_some_and(|email| email.to_lowercase().contains(&query_lower))
+    };
+    scan_users(matches, cursor, limit)
+}
+
+// Admin function to manually create or update a user with specific principal
+#[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
+fn admin_create_user_with_principal(
+    target_principal: Principal,
+    username: String,
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> String {
+    match admin_create_user_with_principal_v2(target_principal, username, full_name, email, bio, profile_pic, location, website) {
+        Ok(_) => format!("User created/updated successfully for principal: {}", target_principal),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
+fn admin_create_user_with_principal_v2(
+    target_principal: Principal,
+    username: String,
+    full_name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+    profile_pic: Option<String>,
+    location: Option<String>,
+    website: Option<String>,
+) -> Result<(), ApiError> {
+    require_admin()?;
+
+    // Admins may hand out reserved names, e.g. for official accounts
+    let username = check_username(&username, target_principal, true)?;
+    let current_timestamp = current_time();
+
+    // An existing user keeps their account history and counts, a new name goes
+    // through the same path as change_username without starting their cooldown
+    if let Some(mut user) = load_user(&target_principal) {
+        if username != user.username {
+            rename_user(&mut user, username, current_timestamp, true);
+        }
+        user.full_name = full_name;
+        user.email = email;
+        user.bio = bio;
+        user.profile_pic = profile_pic;
+        user.location = location;
+        user.website = website;
+        user.is_admin = is_admin(&target_principal);
+        user.last_active = current_timestamp;
+        save_user(user);
+        return Ok(());
+    }
+
+    let user = User {
+        user_id: target_principal,
+        username,
+        full_name,
+        email,
+        bio,
+        profile_pic,
+        location,
+        website,
+        is_admin: is_admin(&target_principal),
+        created_at: current_timestamp,
+        last_active: current_timestamp,
+        followers_count: 0,
+        following_count: 0,
+    };
+
+    index_user(&user);
+    save_user(user);
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_get_recent_users(limit: u64) -> Result<Vec<User>, String> {
+    admin_get_recent_users_v2(limit).map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_recent_users_v2(limit: u64) -> Result<Vec<User>, ApiError> {
+    require_admin()?;
+
+    let mut user_list: Vec<User> = USERS.with(|users| {
+        users.borrow().iter().map(|(_, user)| user).collect()
+    });
+
+    // Sort by creation date (most recent first)
+    user_list.sort_by_key(|user| std::cmp::Reverse(user.created_at));
+
+    // Take only the requested number
+    user_list.truncate(limit as usize);
+
+    Ok(user_list)
+}
+
+#[ic_cdk::query]
+fn admin_get_all_users() -> Result<Vec<User>, String> {
+    admin_get_all_users_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_all_users_v2() -> Result<Vec<User>, ApiError> {
+    require_admin()?;
+
+    let user_list: Vec<User> = USERS.with(|users| {
+        users.borrow().iter().map(|(_, user)| user).collect()
+    });
+    Ok(user_list)
+}
+
+// Covers admin_get_all_users, admin_get_all_users_detailed and admin_get_recent_users
+#[ic_cdk::query]
+fn admin_get_all_users_paged(cursor: Option<String>, limit: u64) -> Result<Page<User>, ApiError> {
+    require_admin()?;
+
+    scan_users(|_| true, cursor, limit)
+}
+
+#[ic_cdk::query]
+fn admin_get_schema_version() -> Result<u8, String> {
+    admin_get_schema_version_v2().map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_schema_version_v2() -> Result<u8, ApiError> {
+    require_admin()?;
+
+    Ok(stored_schema_version())
+}
+
+// ============ INTEGRITY ============
+
+const DEFAULT_INTEGRITY_BATCH: usize = 500;
+const MAX_INTEGRITY_BATCH: usize = 5000;
+
+// Sets and indexes are checked before the counters derived from them, so a repair
+// run removes orphaned entries first and then recounts from what is left
+#[derive(Clone, Copy)]
+enum IntegrityPhase {
+    PostLikes,
+    PostLikers,
+    CommentLikes,
+    CommentLikers,
+    UserReactions,
+    ReactionIndex,
+    PostShares,
+    PostSharers,
+    FollowRecords,
+    FollowerEntries,
+    Comments,
+    Posts,
+    Users,
+}
+
+const INTEGRITY_PHASES: [IntegrityPhase; 13] = [
+    IntegrityPhase::PostLikes,
+    IntegrityPhase::PostLikers,
+    IntegrityPhase::CommentLikes,
+    IntegrityPhase::CommentLikers,
+    IntegrityPhase::UserReactions,
+    IntegrityPhase::ReactionIndex,
+    IntegrityPhase::PostShares,
+    IntegrityPhase::PostSharers,
+    IntegrityPhase::FollowRecords,
+    IntegrityPhase::FollowerEntries,
+    IntegrityPhase::Comments,
+    IntegrityPhase::Posts,
+    IntegrityPhase::Users,
+];
+
+fn post_exists(post_id: u64) -> bool {
+    POSTS.with(|posts| posts.borrow().contains_key(&post_id))
+}
+
+fn comment_exists(comment_id: u64) -> bool {
+    COMMENTS.with(|comments| comments.borrow().contains_key(&comment_id))
+}
+
+fn reaction_target_exists(target: ReactionTarget) -> bool {
+    match target {
+        ReactionTarget::Post(post_id) => post_exists(post_id),
+        ReactionTarget::Comment(comment_id) => comment_exists(comment_id),
+    }
+}
+
+// Like set entries must be backed by a "like" reaction to an existing item
+fn post_like_valid(user_id: Principal, post_id: u64) -> bool {
+    post_exists(post_id) && user_reaction(user_id, ReactionTarget::Post(post_id)).as_deref() == Some(LIKE_REACTION)
+}
+
+fn comment_like_valid(user_id: Principal, comment_id: u64) -> bool {
+    comment_exists(comment_id) && user_reaction(user_id, ReactionTarget::Comment(comment_id)).as_deref() == Some(LIKE_REACTION)
+}
+
+fn unlink_post_like(user_id: Principal, post_id: u64) {
+    unstore_reaction(user_id, ReactionTarget::Post(post_id), LIKE_REACTION);
+}
+
+fn unlink_comment_like(user_id: Principal, comment_id: u64) {
+    unstore_reaction(user_id, ReactionTarget::Comment(comment_id), LIKE_REACTION);
+}
+
+// Share entries must be backed by a repost time of an existing post
+fn share_valid(user_id: Principal, post_id: u64) -> bool {
+    post_exists(post_id) && REPOSTED_AT.with(|reposted_at| reposted_at.borrow().contains_key(&(PrincipalKey(user_id), post_id)))
+}
+
+fn unlink_share(user_id: Principal, post_id: u64) {
+    remove_user_item(&USER_SHARES, &POST_SHARERS, user_id, post_id);
+    unindex_repost(user_id, post_id);
+}
+
+fn counter_mismatch(record: &str, id: String, field: &str, stored: u64, actual: u64) -> IntegrityIssue {
+    IntegrityIssue::CounterMismatch {
+        record: record.to_string(),
+        id,
+        field: field.to_string(),
+        stored,
+        actual,
+    }
+}
+
+// Reports every kind whose stored reaction count differs from the reaction index
+fn report_reaction_mismatches(record: &str, id: u64, stored: &[ReactionCount], actual: &[ReactionCount], report: &mut IntegrityReport) {
+    let count_of = |counts: &[ReactionCount], kind: &str| {
+        counts.iter().find(|count| count.kind == kind).map_or(0, |count| count.count)
+    };
+    let mut kinds: Vec<&str> = stored.iter().chain(actual).map(|count| count.kind.as_str()).collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+
+    for kind in kinds {
+        let (stored_count, actual_count) = (count_of(stored, kind), count_of(actual, kind));
+        if stored_count != actual_count {
+            let field = format!("reactions.{}", kind);
+            report.issues.push(counter_mismatch(record, id.to_string(), &field, stored_count, actual_count));
+        }
+    }
+}
+
+// Checks one batch of a (user_id, item_id) set: entries of deleted users, or failing
+// `entry_valid`, are orphaned and removed with `unlink`. Every entry must be mirrored in
+// the reverse set.
+#[allow(clippy::too_many_arguments)]
+fn check_user_items(
+    set_name: &str,
+    set: &'static LocalKey<RefCell<UserItemSet>>,
+    reverse: &'static LocalKey<RefCell<ItemUserSet>>,
+    entry_valid: fn(Principal, u64) -> bool,
+    unlink: fn(Principal, u64),
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = set.with(|set| entries_after(&set.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for ((user, item_id), _) in entries {
+        let key = format!("{}:{}", user.0, item_id);
+        if !user_exists(&user.0) || !entry_valid(user.0, item_id) {
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: set_name.to_string(), key });
+            if repair {
+                unlink(user.0, item_id);
+            }
+        } else if !reverse.with(|reverse| reverse.borrow().contains_key(&(item_id, user))) {
+            report.issues.push(IntegrityIssue::MissingEntry { set: format!("{} (reverse)", set_name), key });
+            if repair {
+                reverse.with(|reverse| reverse.borrow_mut().insert((item_id, user), ()));
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+// Checks one batch of a reverse (item_id, user_id) set against its forward set
+#[allow(clippy::too_many_arguments)]
+fn check_item_users(
+    set_name: &str,
+    set: &'static LocalKey<RefCell<UserItemSet>>,
+    reverse: &'static LocalKey<RefCell<ItemUserSet>>,
+    unlink: fn(Principal, u64),
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = reverse.with(|reverse| entries_after(&reverse.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for ((item_id, user), _) in entries {
+        if !set.with(|set| set.borrow().contains_key(&(user, item_id))) {
+            let key = format!("{}:{}", item_id, user.0);
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: format!("{} (reverse)", set_name), key });
+            if repair {
+                unlink(user.0, item_id);
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+// Checks one batch of USER_REACTIONS: reactions of deleted users or to deleted items are
+// orphaned, and every reaction must be in the reaction index and, for likes, the like sets
+fn check_user_reactions(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = USER_REACTIONS.with(|reactions| entries_after(&reactions.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for ((user, target), kind) in entries {
+        let key = format!("{}:{}:{}", user.0, target.encode(), kind);
+        let liked = || match target {
+            ReactionTarget::Post(post_id) => USER_LIKES.with(|likes| likes.borrow().contains_key(&(user, post_id))),
+            ReactionTarget::Comment(comment_id) => {
+                USER_COMMENT_LIKES.with(|likes| likes.borrow().contains_key(&(user, comment_id)))
+            }
+        };
+
+        if !user_exists(&user.0) || !reaction_target_exists(target) {
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: "user_reactions".to_string(), key });
+            if repair {
+                unstore_reaction(user.0, target, &kind);
+            }
+            continue;
+        }
+
+        let indexed = REACTIONS.with(|reactions| reactions.borrow().contains_key(&reaction_key(target, &kind, user)));
+        if !indexed || (kind == LIKE_REACTION && !liked()) {
+            let set = if indexed { "like sets" } else { "reactions" };
+            report.issues.push(IntegrityIssue::MissingEntry { set: set.to_string(), key });
+            if repair {
+                store_reaction(user.0, target, &kind);
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+// Checks one batch of the reaction index against the reaction each user has stored
+fn check_reaction_index(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = REACTIONS.with(|reactions| entries_after(&reactions.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for (key, ()) in entries {
+        if user_reaction(key.user.0, key.target).as_deref() != Some(key.kind.as_str()) {
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: "reactions".to_string(), key: key.encode() });
+            if repair {
+                unstore_reaction(key.user.0, key.target, &key.kind);
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+fn check_follow_records(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = FOLLOWS.with(|follows| entries_after(&follows.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for ((follower, following), _) in entries {
+        let key = format!("{}:{}", follower.0, following.0);
+        if !user_exists(&follower.0) || !user_exists(&following.0) {
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: "follows".to_string(), key });
+            if repair {
+                remove_follow(follower.0, following.0);
+            }
+        } else if !FOLLOWERS.with(|followers| followers.borrow().contains_key(&(following, follower))) {
+            report.issues.push(IntegrityIssue::MissingEntry { set: "followers".to_string(), key });
+            if repair {
+                FOLLOWERS.with(|followers| followers.borrow_mut().insert((following, follower), ()));
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+fn check_follower_entries(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = FOLLOWERS.with(|followers| entries_after(&followers.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for ((following, follower), _) in entries {
+        if !FOLLOWS.with(|follows| follows.borrow().contains_key(&(follower, following))) {
+            let key = format!("{}:{}", following.0, follower.0);
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: "followers".to_string(), key });
+            if repair {
+                FOLLOWERS.with(|followers| followers.borrow_mut().remove(&(following, follower)));
+            }
+        }
+    }
+    Ok(next_cursor)
+}
+
+fn check_comments(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = COMMENTS.with(|comments| entries_after(&comments.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for (comment_id, mut comment) in entries {
+        let mut changed = false;
+
+        if !comment.is_deleted && !post_exists(comment.post_id) {
+            report.issues.push(IntegrityIssue::OrphanedComment { comment_id, post_id: comment.post_id });
+            if repair {
+                move_comment_to_trash(&mut comment, current_time());
+                changed = true;
+            }
+        }
+
+        let by_author = AUTHOR_COMMENTS.with(|author_comments| {
+            author_comments.borrow().contains_key(&(PrincipalKey(comment.author_id), comment_id))
+        });
+        if !by_author {
+            let key = format!("{}:{}", comment.author_id, comment_id);
+            report.issues.push(IntegrityIssue::MissingEntry { set: "author_comments".to_string(), key });
+            if repair {
+                AUTHOR_COMMENTS.with(|author_comments| {
+                    author_comments.borrow_mut().insert((PrincipalKey(comment.author_id), comment_id), ())
+                });
+            }
+        }
+
+        let indexed = POST_COMMENTS.with(|post_comments| {
+            post_comments.borrow().contains_key(&(comment.post_id, comment_id))
+        });
+        let key = format!("{}:{}", comment.post_id, comment_id);
+        if !comment.is_deleted && !indexed {
+            report.issues.push(IntegrityIssue::MissingEntry { set: "post_comments".to_string(), key });
+            if repair {
+                index_comment(&comment);
+            }
+        } else if comment.is_deleted && indexed && !changed {
+            report.issues.push(IntegrityIssue::OrphanedEntry { set: "post_comments".to_string(), key });
+            if repair {
+                unindex_comment(&comment);
+            }
+        }
+
+        let actual_likes = item_user_ids(&COMMENT_LIKERS, comment_id).len() as u64;
+        if comment.likes != actual_likes {
+            report.issues.push(counter_mismatch("comment", comment_id.to_string(), "likes", comment.likes, actual_likes));
+            comment.likes = actual_likes;
+            changed = true;
+        }
+
+        let actual_reactions = count_reactions(ReactionTarget::Comment(comment_id));
+        if comment.reactions != actual_reactions {
+            report_reaction_mismatches("comment", comment_id, &comment.reactions, &actual_reactions, report);
+            comment.reactions = actual_reactions;
+            changed = true;
+        }
+
+        let actual_replies = live_reply_count(comment_id);
+        if comment.reply_count != actual_replies {
+            report.issues.push(counter_mismatch("comment", comment_id.to_string(), "reply_count", comment.reply_count, actual_replies));
+            comment.reply_count = actual_replies;
+            changed = true;
+        }
+
+        if repair && changed {
+            save_comment(comment);
+        }
+    }
+    Ok(next_cursor)
+}
+
+fn check_posts(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = POSTS.with(|posts| entries_after(&posts.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for (post_id, mut post) in entries {
+        let mut changed = false;
+
+        // The author index keeps deleted posts too
+        let indexed = AUTHOR_POSTS.with(|author_posts| {
+            author_posts.borrow().contains_key(&(PrincipalKey(post.author_id), post_id))
+        });
+        if !indexed {
+            let key = format!("{}:{}", post.author_id, post_id);
+            report.issues.push(IntegrityIssue::MissingEntry { set: "author_posts".to_string(), key });
+            if repair {
+                index_post(&post);
+            }
+        }
+
+        let actual_likes = item_user_ids(&POST_LIKERS, post_id).len() as u64;
+        if post.likes != actual_likes {
+            report.issues.push(counter_mismatch("post", post_id.to_string(), "likes", post.likes, actual_likes));
+            post.likes = actual_likes;
+            changed = true;
+        }
+
+        let actual_reactions = count_reactions(ReactionTarget::Post(post_id));
+        if post.reactions != actual_reactions {
+            report_reaction_mismatches("post", post_id, &post.reactions, &actual_reactions, report);
+            post.reactions = actual_reactions;
+            changed = true;
+        }
+
+        let actual_shares = item_user_ids(&POST_SHARERS, post_id).len() as u64;
+        if post.shares_count != actual_shares {
+            report.issues.push(counter_mismatch("post", post_id.to_string(), "shares_count", post.shares_count, actual_shares));
+            post.shares_count = actual_shares;
+            changed = true;
+        }
+
+        let actual_comments = comment_ids_for_post(post_id).len() as u64;
+        if post.comments_count != actual_comments {
+            report.issues.push(counter_mismatch("post", post_id.to_string(), "comments_count", post.comments_count, actual_comments));
+            post.comments_count = actual_comments;
+            changed = true;
+        }
+
+        if repair && changed {
+            save_post(post);
+        }
+    }
+    Ok(next_cursor)
+}
+
+fn check_users(
+    cursor: Option<String>,
+    limit: usize,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<Option<String>, ApiError> {
+    let (entries, next_cursor) = USERS.with(|users| entries_after(&users.borrow(), cursor, limit))?;
+    report.checked += entries.len() as u64;
+
+    for (user_key, mut user) in entries {
+        let mut changed = false;
+
+        let actual_followers = followers_of(user_key.0).len() as u64;
+        if user.followers_count != actual_followers {
+            report.issues.push(counter_mismatch("user", user_key.0.to_text(), "followers_count", user.followers_count, actual_followers));
+            user.followers_count = actual_followers;
+            changed = true;
+        }
+
+        let actual_following = following_of(user_key.0).len() as u64;
+        if user.following_count != actual_following {
+            report.issues.push(counter_mismatch("user", user_key.0.to_text(), "following_count", user.following_count, actual_following));
+            user.following_count = actual_following;
+            changed = true;
+        }
+
+        if repair && changed {
+            save_user(user);
+        }
+    }
+    Ok(next_cursor)
+}
+
+// Runs one batch of the integrity check, fixing what it finds if `repair` is set
+fn run_integrity_batch(cursor: Option<String>, limit: u64, repair: bool) -> Result<IntegrityReport, ApiError> {
+    require_admin()?;
+
+    let (phase_index, inner_cursor) = parse_phase_cursor(cursor, INTEGRITY_PHASES.len())?;
+    let limit = page_limit(limit, DEFAULT_INTEGRITY_BATCH, MAX_INTEGRITY_BATCH);
+    let mut report = IntegrityReport { issues: Vec::new(), checked: 0, next_cursor: None };
+
+    let next_inner_cursor = match INTEGRITY_PHASES[phase_index] {
+        IntegrityPhase::PostLikes => check_user_items("user_likes", &USER_LIKES, &POST_LIKERS, post_like_valid, unlink_post_like, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::PostLikers => check_item_users("user_likes", &USER_LIKES, &POST_LIKERS, unlink_post_like, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::CommentLikes => check_user_items("user_comment_likes", &USER_COMMENT_LIKES, &COMMENT_LIKERS, comment_like_valid, unlink_comment_like, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::CommentLikers => check_item_users("user_comment_likes", &USER_COMMENT_LIKES, &COMMENT_LIKERS, unlink_comment_like, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::UserReactions => check_user_reactions(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::ReactionIndex => check_reaction_index(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::PostShares => check_user_items("user_shares", &USER_SHARES, &POST_SHARERS, share_valid, unlink_share, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::PostSharers => check_item_users("user_shares", &USER_SHARES, &POST_SHARERS, unlink_share, inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::FollowRecords => check_follow_records(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::FollowerEntries => check_follower_entries(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::Comments => check_comments(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::Posts => check_posts(inner_cursor, limit, repair, &mut report)?,
+        IntegrityPhase::Users => check_users(inner_cursor, limit, repair, &mut report)?,
+    };
+
+    report.next_cursor = next_phase_cursor(phase_index, INTEGRITY_PHASES.len(), next_inner_cursor);
+    Ok(report)
+}
+
+// Reports counters that disagree with the sets they summarize and entries pointing at
+// records that no longer exist, one batch at a time. Start without a cursor and keep
+// passing `next_cursor` back until it comes back empty.
+#[ic_cdk::query]
+fn verify_integrity(cursor: Option<String>, limit: u64) -> Result<IntegrityReport, ApiError> {
+    run_integrity_batch(cursor, limit, false)
+}
+
+// Same walk as verify_integrity, but fixes every issue it reports: orphaned entries are
+// removed, missing index entries added and counters recomputed from their sets
+#[ic_cdk::update]
+fn repair_integrity(cursor: Option<String>, limit: u64) -> Result<IntegrityReport, ApiError> {
+    run_integrity_batch(cursor, limit, true)
+}
+
+// ============ HELPER FUNCTIONS ============
+
+fn extract_mentions(content: &str) -> Vec<Principal> {
+    let mut mentions = Vec::new();
+    let words: Vec<&str> = content.split_whitespace().collect();
+
+    for word in words {
+        if word.starts_with('@') && word.len() > 1 {
+            // Drop trailing punctuation such as "@alice,"
+            let username: String = word[1..].chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+            // Find user by username and get their principal
+            if let Some(user_id) = resolve_username(&username) {
+                mentions.push(user_id);
+            }
+        }
+    }
+
+    mentions
+}
+
+fn next_comment_id() -> u64 {
+    COMMENT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let next_id = *counter.get() + 1;
+        counter.set(next_id).expect("Failed to update comment ID counter");
+        next_id
+    })
+}
+
+// ============ SEARCH FUNCTIONS ============
+
+#[ic_cdk::query]
+fn search_users(query: String) -> Vec<PublicProfile> {
+    let query_lower = query.to_lowercase();
+
+    let filtered_users = collect_users(|user| {
+        user.username.to_lowercase().contains(&query_lower) ||
+        user.full_name.as_ref().is_some_and(|name| name.to_lowercase().contains(&query_lower)) ||
+        user.bio.as_ref().is_some_and(|bio| bio.to_lowercase().contains(&query_lower))
+    });
+    public_profiles(filtered_users)
+}
+
+#[ic_cdk::query]
+fn search_users_paged(query: String, cursor: Option<String>, limit: u64) -> Result<Page<PublicProfile>, ApiError> {
+    let query_lower = query.to_lowercase();
+    let matches = |user: &User| {
+        user.username.to_lowercase().contains(&query_lower) ||
+        user.full_name.as_ref().is_some_and(|name| name.to_lowercase().contains(&query_lower)) ||
+        user.bio.as_ref().is_some_and(|bio| bio.to_lowercase().contains(&query_lower))
+    };
+    scan_users(matches, cursor, limit).map(public_profile_page)
+}
+
+#[ic_cdk::query]
+fn search_posts(query: String) -> Vec<Post> {
+    let query_lower = query.to_lowercase();
+
+    let mut filtered_posts: Vec<Post> = POSTS.with(|posts| {
+        posts.borrow().iter()
+            .map(|(_, post)| post)
+            .filter(|post| {
+                !post.is_deleted && (
+                    post.content.to_lowercase().contains(&query_lower) ||
+                    post.hashtags.iter().any(|tag| tag.to_lowercase().contains(&query_lower))
+                )
+            })
+            .collect()
+    });
+
+    // Sort by creation date (most recent first)
+    filtered_posts.sort_by_key(|post| std::cmp::Reverse(post.created_at));
+
+    filtered_posts
+}
+
+#[ic_cdk::query]
+fn search_posts_paged(query: String, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let query_lower = query.to_lowercase();
+    let matches = |post: &Post| {
+        !post.is_deleted && (
+            post.content.to_lowercase().contains(&query_lower) ||
+            post.hashtags.iter().any(|tag| tag.to_lowercase().contains(&query_lower))
+        )
+    };
+    scan_posts(matches, cursor, limit)
+}
+
+#[ic_cdk::query]
+fn get_posts_by_hashtag(hashtag: String) -> Vec<Post> {
+    let mut filtered_posts = load_posts(post_ids_by_hashtag(&hashtag));
+
+    // Sort by creation date (most recent first)
+    filtered_posts.sort_by_key(|post| std::cmp::Reverse(post.created_at));
+
+    filtered_posts
+}
+
+#[ic_cdk::query]
+fn get_posts_by_hashtag_paged(hashtag: String, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let after: Option<(u64, u64)> = decode_cursor(cursor)?;
+    let tag = normalize_hashtag(&hashtag);
+    let start = HashtagKey { tag: tag.clone(), post_id: 0 };
+    let end = match after {
+        Some((_, post_id)) => std::ops::Bound::Excluded(HashtagKey { tag, post_id }),
+        None => std::ops::Bound::Included(HashtagKey { tag, post_id: u64::MAX }),
+    };
+    Ok(HASHTAG_POSTS.with(|hashtag_posts| {
+        let load = |(key, ()): (HashtagKey, ())| load_post(key.post_id).filter(|post| !post.is_deleted);
+        scan_page(&hashtag_posts.borrow(), (std::ops::Bound::Included(start), end), PageOrder::NewestFirst, limit, load, post_page_key)
+    }))
+}
+
+#[ic_cdk::query]
+fn get_trending_posts() -> Vec<Post> {
+    let current_timestamp = current_time();
+    let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000);
+
+    let mut recent_posts: Vec<Post> = POSTS.with(|posts| {
+        posts.borrow().iter()
+            .map(|(_, post)| post)
+            .filter(|post| !post.is_deleted && post.created_at > twenty_four_hours_ago)
+            .collect()
+    });
+
+    // Sort by engagement (likes + comments + shares)
+    recent_posts.sort_by(|a, b| {
+        let a_engagement = a.likes + a.comments_count + a.shares_count;
+        let b_engagement = b.likes + b.comments_count + b.shares_count;
+        b_engagement.cmp(&a_engagement)
+    });
+
+    // Take top 20 trending posts
+    recent_posts.truncate(20);
+
+    recent_posts
+}
+
+// ============ COMMENT FUNCTIONS ============
+
+// Replies can be nested this many levels below a top-level comment
+const MAX_COMMENT_DEPTH: u32 = 5;
+
+// Replies shown under each top-level comment by get_post_comment_threads
+const INLINED_REPLIES: usize = 3;
+
+// Range of a thread level in POST_THREADS or COMMENT_REPLIES, after the cursor if there is one
+fn thread_range(parent_id: u64, after: Option<u64>) -> KeyRange<(u64, u64)> {
+    let start = match after {
+        Some(comment_id) => std::ops::Bound::Excluded((parent_id, comment_id)),
+        None => std::ops::Bound::Included((parent_id, 0)),
+    };
+    (start, std::ops::Bound::Included((parent_id, u64::MAX)))
+}
+
+// Whether any reply, deleted or not, is still linked under a comment. Replies are
+// unlinked when purged, so this covers every descendant that has not been purged.
+fn has_replies(comment_id: u64) -> bool {
+    COMMENT_REPLIES.with(|replies| replies.borrow().range(thread_range(comment_id, None)).next().is_some())
+}
+
+fn live_reply_count(comment_id: u64) -> u64 {
+    COMMENT_REPLIES.with(|replies| {
+        replies.borrow().range(thread_range(comment_id, None))
+            .filter_map(|((_, reply_id), _)| load_comment(reply_id))
+            .filter(|reply| !reply.is_deleted)
+            .count() as u64
+    })
+}
+
+// Updates a comment's reply count after one of its replies was deleted or restored
+fn recount_replies(comment_id: u64) {
+    if let Some(mut comment) = load_comment(comment_id) {
+        comment.reply_count = live_reply_count(comment_id);
+        save_comment(comment);
+    }
+}
+
+// Updates the reply counts of every comment under a post, after its comments were
+// deleted or restored together
+fn recount_thread_replies(post_id: u64) {
+    let child_ids = |set: &'static LocalKey<RefCell<PostItemSet>>, parent_id: u64| -> Vec<u64> {
+        set.with(|set| set.borrow().range(thread_range(parent_id, None)).map(|((_, comment_id), _)| comment_id).collect())
+    };
+    let mut pending = child_ids(&POST_THREADS, post_id);
+    while let Some(comment_id) = pending.pop() {
+        pending.extend(child_ids(&COMMENT_REPLIES, comment_id));
+        recount_replies(comment_id);
+    }
+}
+
+// Loads a comment of a thread level. A deleted comment with descendants left is shown
+// as a "[deleted]" placeholder so the replies below it stay reachable.
+fn load_thread_comment(comment_id: u64) -> Option<Comment> {
+    let mut comment = load_comment(comment_id)?;
+    if comment.is_deleted {
+        if !has_replies(comment_id) {
+            return None;
+        }
+        comment.author_id = Principal::anonymous();
+        comment.content = "[deleted]".to_string();
+        comment.likes = 0;
+        comment.reactions.clear();
+        comment.updated_at = None;
+        comment.deleted_at = None;
+    }
+    Some(comment)
+}
+
+#[ic_cdk::update]
+fn create_comment(post_id: u64, content: String) -> Result<u64, String> {
+    create_comment_v2(post_id, content).map_err(|e| e.to_string())
+}
+
+#[ic_cdk::update]
+fn create_comment_v2(post_id: u64, content: String) -> Result<u64, ApiError> {
+    insert_comment(post_id, None, content)
+}
+
+// Replies to a comment, up to MAX_COMMENT_DEPTH levels deep
+#[ic_cdk::update]
+fn reply_to_comment(comment_id: u64, content: String) -> Result<u64, ApiError> {
+    let parent = load_active_comment(comment_id)?;
+    if parent.depth >= MAX_COMMENT_DEPTH {
+        return Err(ApiError::Validation {
+            field: "comment_id".to_string(),
+            reason: format!("Replies can be nested at most {} levels deep", MAX_COMMENT_DEPTH),
+        });
+    }
+
+    insert_comment(parent.post_id, Some(parent), content)
+}
+
+// Stores a new top-level comment or reply
+fn insert_comment(post_id: u64, parent: Option<Comment>, content: String) -> Result<u64, ApiError> {
+    let author_id = caller();
+
+    // Check if user exists
+    require_registered(&author_id)?;
+
+    // Validate content
+    validate_content(&content, "Comment", 500)?;
+
+    // Check if post exists and is not deleted
+    let mut post = load_active_post(post_id)?;
+
+    let comment_id = next_comment_id();
+    let current_timestamp = current_time();
+
+    let comment = Comment {
+        comment_id,
+        post_id,
+        author_id,
+        content: content.trim().to_string(),
+        created_at: current_timestamp,
+        updated_at: None,
+        likes: 0,
+        is_deleted: false,
+        deleted_at: None,
+        parent_id: parent.as_ref().map(|parent| parent.comment_id),
+        depth: parent.as_ref().map_or(0, |parent| parent.depth + 1),
+        reply_count: 0,
+        reactions: Vec::new(),
+    };
+
+    // Store the comment
+    index_comment(&comment);
+    save_comment(comment);
+
+    if let Some(mut parent) = parent {
+        parent.reply_count += 1;
+        if parent.author_id != post.author_id {
+            notify(parent.author_id, author_id, NotificationKind::Comment, Some(post_id), Some(comment_id));
+        }
+        save_comment(parent);
+    }
+
+    // Increment comment count on the post
+    post.comments_count += 1;
+    notify(post.author_id, author_id, NotificationKind::Comment, Some(post_id), Some(comment_id));
+    notify_mentions(&extract_mentions(&content), author_id, post_id, Some(comment_id));
+    save_post(post);
+
+    // Update user's last active timestamp
+    if let Some(mut user) = load_user(&author_id) {
+        user.last_active = current_timestamp;
+        save_user(user);
+    }
+
+    Ok(comment_id)
+}
+
+#[ic_cdk::query]
+fn get_post_comments(post_id: u64) -> Vec<Comment> {
+    // Comments of deleted or missing posts are hidden
+    if load_active_post(post_id).is_err() {
+        return Vec::new();
+    }
+
+    let mut post_comments = load_comments(comment_ids_for_post(post_id));
+
+    // Sort by creation date (oldest first for comments)
+    post_comments.sort_by_key(|comment| comment.created_at);
+
+    post_comments
+}
+
+#[ic_cdk::query]
+fn get_post_comments_paged(post_id: u64, cursor: Option<String>, limit: u64) -> Result<Page<Comment>, ApiError> {
+    load_active_post(post_id)?;
+
+    // Oldest first for comments. Comment IDs are handed out in creation order.
+    let after: Option<(u64, u64)> = decode_cursor(cursor)?;
+    let start = match after {
+        Some((_, comment_id)) => std::ops::Bound::Excluded((post_id, comment_id)),
+        None => std::ops::Bound::Included((post_id, 0)),
+    };
+    let range = (start, std::ops::Bound::Included((post_id, u64::MAX)));
+    Ok(POST_COMMENTS.with(|post_comments| {
+        let load = |((_, comment_id), ()): ((u64, u64), ())| load_comment(comment_id).filter(|comment| !comment.is_deleted);
+        scan_page(&post_comments.borrow(), range, PageOrder::OldestFirst, limit, load, comment_page_key)
+    }))
+}
+
+// Top-level comments of a post, oldest first, each with its first replies inlined
+#[ic_cdk::query]
+fn get_post_comment_threads(post_id: u64, cursor: Option<String>, limit: u64) -> Result<Page<CommentThread>, ApiError> {
+    load_active_post(post_id)?;
+
+    // Comment IDs are handed out in creation order, so they double as the cursor
+    let after: Option<u64> = decode_cursor(cursor)?;
+    Ok(POST_THREADS.with(|threads| {
+        let load = |((_, comment_id), ()): ((u64, u64), ())| {
+            let comment = load_thread_comment(comment_id)?;
+            let replies = COMMENT_REPLIES.with(|replies| {
+                replies.borrow().range(thread_range(comment_id, None))
+                    .filter_map(|((_, reply_id), _)| load_thread_comment(reply_id))
+                    .take(INLINED_REPLIES)
+                    .collect()
+            });
+            Some(CommentThread { comment, replies })
+        };
+        let key = |thread: &CommentThread| thread.comment.comment_id;
+        scan_page(&threads.borrow(), thread_range(post_id, after), PageOrder::OldestFirst, limit, load, key)
+    }))
+}
+
+// Direct replies to a comment, oldest first
+#[ic_cdk::query]
+fn get_comment_replies(comment_id: u64, cursor: Option<String>, limit: u64) -> Result<Page<Comment>, ApiError> {
+    let comment = load_comment(comment_id).ok_or(ApiError::NotFound { resource: "Comment".to_string() })?;
+    load_active_post(comment.post_id)?;
+
+    let after: Option<u64> = decode_cursor(cursor)?;
+    Ok(COMMENT_REPLIES.with(|replies| {
+        let load = |((_, reply_id), ()): ((u64, u64), ())| load_thread_comment(reply_id);
+        let key = |reply: &Comment| reply.comment_id;
+        scan_page(&replies.borrow(), thread_range(comment_id, after), PageOrder::OldestFirst, limit, load, key)
+    }))
+}
+
+#[ic_cdk::update]
+fn update_comment(comment_id: u64, new_content: String) -> String {
+    match update_comment_v2(comment_id, new_content) {
+        Ok(_) => "Comment updated successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn update_comment_v2(comment_id: u64, new_content: String) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    // Validate content
+    validate_content(&new_content, "Comment", 500)?;
+
+    let mut comment = load_comment(comment_id).ok_or(ApiError::NotFound { resource: "Comment".to_string() })?;
+
+    // Check if caller is the author or admin
+    if comment.author_id != caller_id && !is_admin(&caller_id) {
+        return Err(ApiError::Unauthorized { reason: "You can only edit your own comments".to_string() });
+    }
+
+    if comment.is_deleted {
+        return Err(ApiError::Deleted { resource: "Comment".to_string() });
+    }
+
+    check_edit_window(comment.created_at, "comment")?;
+
+    let now = current_time();
+    let revision = Revision {
+        content: std::mem::replace(&mut comment.content, new_content.trim().to_string()),
+        written_at: comment.updated_at.unwrap_or(comment.created_at),
+        replaced_at: now,
+        edited_by: caller_id,
+    };
+    add_revision(&COMMENT_REVISIONS, comment_id, revision);
+
+    comment.updated_at = Some(now);
+    save_comment(comment);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn delete_comment(comment_id: u64) -> String {
+    match delete_comment_v2(comment_id) {
+        Ok(_) => "Comment deleted successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_comment_v2(comment_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    let mut comment = load_comment(comment_id).ok_or(ApiError::NotFound { resource: "Comment".to_string() })?;
+
+    // Check if caller is the author or admin
+    if comment.author_id != caller_id && !is_admin(&caller_id) {
+        return Err(ApiError::Unauthorized { reason: "You can only delete your own comments".to_string() });
+    }
+
+    if comment.is_deleted {
+        return Err(ApiError::Deleted { resource: "Comment".to_string() });
+    }
+
+    let post_id = comment.post_id;
+    let parent_id = comment.parent_id;
+    move_comment_to_trash(&mut comment, current_time());
+    save_comment(comment);
+    if let Some(parent_id) = parent_id {
+        recount_replies(parent_id);
+    }
+
+    // Decrement comment count on the post
+    if let Some(mut post) = load_post(post_id) {
+        if post.comments_count > 0 {
+            post.comments_count -= 1;
+            save_post(post);
+        }
+    }
+
+    Ok(())
+}
+
+// ============ TRASH ============
+
+// How long the author or an admin can still restore a deleted post or comment
+const TRASH_RESTORE_WINDOW_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Default time deleted posts and comments are kept before they are purged for good
+const DEFAULT_TRASH_RETENTION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// How many posts and comments the purge job removes per run
+const TRASH_PURGE_BATCH: usize = 500;
+
+// Marks a post as deleted, drops it from the indexes and adds it to the trash index.
+// The caller saves the post.
+fn move_post_to_trash(post: &mut Post, deleted_at: u64) {
+    post.is_deleted = true;
+    post.deleted_at = Some(deleted_at);
+    unindex_post(post);
+    TRASHED_POSTS.with(|trash| trash.borrow_mut().insert((deleted_at, post.post_id), ()));
+}
+
+// Marks a comment as deleted, drops it from the indexes and adds it to the trash index.
+// The caller saves the comment.
+fn move_comment_to_trash(comment: &mut Comment, deleted_at: u64) {
+    comment.is_deleted = true;
+    comment.deleted_at = Some(deleted_at);
+    unindex_comment(comment);
+    TRASHED_COMMENTS.with(|trash| trash.borrow_mut().insert((deleted_at, comment.comment_id), ()));
+}
+
+fn trash_retention_ns() -> u64 {
+    TRASH_RETENTION_NS.with(|retention| *retention.borrow().get())
+}
+
+// Helper function to list the trash entries deleted at or after a point in time
+fn trashed_since(trash: &'static LocalKey<RefCell<PostItemSet>>, since: u64) -> Vec<u64> {
+    trash.with(|trash| {
+        trash.borrow().range((since, 0)..)
+            .map(|((_, item_id), _)| item_id)
+            .collect()
+    })
+}
+
+// Checks that a deleted record can be restored by the caller: it must still be in the
+// restore window, the caller must be its author or an admin, and the author must still exist
+fn check_restorable(resource: &str, author_id: Principal, deleted_at: Option<u64>) -> Result<u64, ApiError> {
+    let caller_id = caller();
+    if author_id != caller_id && !is_admin(&caller_id) {
+        return Err(ApiError::Unauthorized { reason: format!("You can only restore your own {}s", resource.to_lowercase()) });
+    }
+
+    let deleted_at = deleted_at.unwrap_or(0);
+    if current_time().saturating_sub(deleted_at) > TRASH_RESTORE_WINDOW_NS {
+        return Err(ApiError::Conflict { reason: format!("{} can no longer be restored", resource) });
+    }
+    if !user_exists(&author_id) {
+        return Err(ApiError::Conflict { reason: format!("The author of this {} has been deleted", resource.to_lowercase()) });
+    }
+    Ok(deleted_at)
+}
+
+// Restores a deleted post together with the comments that were deleted with it.
+// Likes and shares were withdrawn at deletion and are not restored.
+#[ic_cdk::update]
+fn restore_post(post_id: u64) -> Result<(), ApiError> {
+    let mut post = load_post(post_id).ok_or(ApiError::NotFound { resource: "Post".to_string() })?;
+    if !post.is_deleted {
+        return Err(ApiError::Conflict { reason: "Post is not deleted".to_string() });
+    }
+    let deleted_at = check_restorable("Post", post.author_id, post.deleted_at)?;
+
+    // Comments that were deleted together with the post carry the same timestamp
+    let trashed_comments: Vec<Comment> = COMMENTS.with(|comments| {
+        let comments = comments.borrow();
+        TRASHED_COMMENTS.with(|trash| {
+            trash.borrow().range((deleted_at, 0)..(deleted_at + 1, 0))
+                .filter_map(|((_, comment_id), _)| comments.get(&comment_id))
+                .filter(|comment| comment.post_id == post_id)
+                .collect()
+        })
+    });
+    for mut comment in trashed_comments {
+        TRASHED_COMMENTS.with(|trash| trash.borrow_mut().remove(&(deleted_at, comment.comment_id)));
+        comment.is_deleted = false;
+        comment.deleted_at = None;
+        index_comment(&comment);
+        save_comment(comment);
+        post.comments_count += 1;
+    }
+    recount_thread_replies(post_id);
+
+    TRASHED_POSTS.with(|trash| trash.borrow_mut().remove(&(deleted_at, post_id)));
+    post.is_deleted = false;
+    post.deleted_at = None;
+    index_post(&post);
+    save_post(post);
+
+    Ok(())
+}
+
+// Restores a deleted comment. Comments of a deleted post come back with restore_post.
+#[ic_cdk::update]
+fn restore_comment(comment_id: u64) -> Result<(), ApiError> {
+    let mut comment = load_comment(comment_id).ok_or(ApiError::NotFound { resource: "Comment".to_string() })?;
+    if !comment.is_deleted {
+        return Err(ApiError::Conflict { reason: "Comment is not deleted".to_string() });
+    }
+    let deleted_at = check_restorable("Comment", comment.author_id, comment.deleted_at)?;
+    let mut post = load_active_post(comment.post_id)?;
+
+    TRASHED_COMMENTS.with(|trash| trash.borrow_mut().remove(&(deleted_at, comment_id)));
+    comment.is_deleted = false;
+    comment.deleted_at = None;
+    index_comment(&comment);
+    let parent_id = comment.parent_id;
+    save_comment(comment);
+    if let Some(parent_id) = parent_id {
+        recount_replies(parent_id);
+    }
+
+    post.comments_count += 1;
+    save_post(post);
+
+    Ok(())
+}
+
+// The caller's deleted posts and comments that are still within the restore window,
+// most recently deleted first
+#[ic_cdk::query]
+fn get_my_trash() -> Result<Trash, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    let since = current_time().saturating_sub(TRASH_RESTORE_WINDOW_NS);
+    let mut posts: Vec<Post> = trashed_since(&TRASHED_POSTS, since).into_iter()
+        .filter_map(load_post)
+        .filter(|post| post.author_id == caller_id)
+        .collect();
+    posts.reverse();
+    let mut comments: Vec<Comment> = trashed_since(&TRASHED_COMMENTS, since).into_iter()
+        .filter_map(load_comment)
+        .filter(|comment| comment.author_id == caller_id)
+        .collect();
+    comments.reverse();
+
+    Ok(Trash { posts, comments })
+}
+
+#[ic_cdk::query]
+fn admin_get_trash_retention() -> Result<u64, ApiError> {
+    require_admin()?;
+
+    Ok(trash_retention_ns())
+}
+
+// The retention can't be shorter than the restore window
+#[ic_cdk::update]
+fn admin_set_trash_retention(retention_ns: u64) -> Result<(), ApiError> {
+    require_admin()?;
+
+    if retention_ns < TRASH_RESTORE_WINDOW_NS {
+        return Err(ApiError::Validation {
+            field: "retention_ns".to_string(),
+            reason: "Retention must be at least the restore window".to_string(),
+        });
+    }
+    TRASH_RETENTION_NS.with(|retention| {
+        retention.borrow_mut().set(retention_ns).expect("Failed to set trash retention")
+    });
+    Ok(())
+}
+
+// Helper function to take up to `limit` trash entries deleted before the cutoff out of a trash index
+fn take_expired(trash: &'static LocalKey<RefCell<PostItemSet>>, cutoff: u64, limit: usize) -> Vec<u64> {
+    trash.with(|trash| {
+        let mut trash = trash.borrow_mut();
+        let expired: Vec<(u64, u64)> = trash.range(..(cutoff, 0))
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect();
+        for key in &expired {
+            trash.remove(key);
+        }
+        expired.into_iter().map(|(_, item_id)| item_id).collect()
+    })
+}
+
+// Permanently removes one batch of posts and comments that have been in the trash
+// longer than the retention period. A comment with replies left stays as a placeholder
+// outside the trash, and goes back in once its last reply is purged.
+fn purge_trash() {
+    let cutoff = current_time().saturating_sub(trash_retention_ns());
+
+    let post_ids = take_expired(&TRASHED_POSTS, cutoff, TRASH_PURGE_BATCH);
+    for post_id in post_ids {
+        if let Some(post) = POSTS.with(|posts| posts.borrow_mut().remove(&post_id)) {
+            forget_post(&post);
+        }
+        remove_all_reactions(ReactionTarget::Post(post_id));
+        remove_all_reposts(post_id);
+        remove_revisions(&POST_REVISIONS, post_id);
+        for user_id in item_user_ids(&POST_BOOKMARKERS, post_id) {
+            delete_bookmark(user_id, post_id);
+        }
+    }
+
+    let comment_ids = take_expired(&TRASHED_COMMENTS, cutoff, TRASH_PURGE_BATCH);
+    for comment_id in comment_ids {
+        if has_replies(comment_id) {
+            continue;
+        }
+        if let Some(comment) = COMMENTS.with(|comments| comments.borrow_mut().remove(&comment_id)) {
+            forget_comment(&comment);
+            requeue_placeholder(comment.parent_id);
+        }
+        remove_all_reactions(ReactionTarget::Comment(comment_id));
+        remove_revisions(&COMMENT_REVISIONS, comment_id);
+    }
+}
+
+// Puts a deleted parent back in the trash once its last reply is gone
+fn requeue_placeholder(parent_id: Option<u64>) {
+    let Some(parent) = parent_id.and_then(load_comment) else {
+        return;
+    };
+    if let (true, Some(deleted_at)) = (parent.is_deleted, parent.deleted_at) {
+        if !has_replies(parent.comment_id) {
+            TRASHED_COMMENTS.with(|trash| trash.borrow_mut().insert((deleted_at, parent.comment_id), ()));
+        }
+    }
+}
+
+// ============ NOTIFICATIONS ============
+
+// How long notifications are kept before the prune job removes them
+const NOTIFICATION_RETENTION_NS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+
+// How many notifications the prune job removes per run
+const NOTIFICATION_PRUNE_BATCH: usize = 1000;
+
+// How many actors a notification group lists by name
+const NOTIFICATION_GROUP_ACTORS: usize = 3;
+
+// How many of a group's notifications are read to summarize it
+const NOTIFICATION_GROUP_SCAN: usize = 100;
+
+// How many notifications one page of the inbox reads at most, group members included
+const NOTIFICATION_PAGE_SCAN: usize = 1000;
+
+fn next_notification_id() -> u64 {
+    NOTIFICATION_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = *counter.get();
+        let next_id = current_id + 1;
+        counter.set(next_id).expect("Failed to update notification ID counter");
+        next_id
+    })
+}
+
+// Likes, shares and comments are grouped per post and follows all together.
+// Mentions are shown one by one.
+fn notification_group_key(notification: &Notification) -> NotificationGroupKey {
+    let target = match notification.kind {
+        NotificationKind::Like | NotificationKind::Share | NotificationKind::Comment => notification.post_id.unwrap_or(0),
+        NotificationKind::CommentLike => notification.comment_id.unwrap_or(0),
+        NotificationKind::Follow => 0,
+        NotificationKind::Mention => notification.notification_id,
+    };
+    NotificationGroupKey {
+        recipient: PrincipalKey(notification.recipient_id),
+        kind: notification.kind,
+        target,
+        notification_id: notification.notification_id,
+    }
+}
+
+// Helper function to adjust a user's stored unread count
+fn update_unread_count(user_id: Principal, update: impl FnOnce(u64) -> u64) {
+    UNREAD_NOTIFICATIONS.with(|unread| {
+        let mut unread = unread.borrow_mut();
+        let count = update(unread.get(&PrincipalKey(user_id)).unwrap_or(0));
+        if count == 0 {
+            unread.remove(&PrincipalKey(user_id));
+        } else {
+            unread.insert(PrincipalKey(user_id), count);
+        }
+    });
+}
+
+// Checks the recipient's notification preferences
+fn wants_notification(recipient_id: Principal, actor_id: Principal, kind: NotificationKind, post_id: Option<u64>) -> bool {
+    let preferences = load_settings(recipient_id).notifications;
+    let enabled = match kind {
+        NotificationKind::Like | NotificationKind::CommentLike => preferences.likes,
+        NotificationKind::Comment => preferences.comments,
+        NotificationKind::Follow => preferences.follows,
+        NotificationKind::Mention => preferences.mentions,
+        NotificationKind::Share => preferences.shares,
+    };
+    if !enabled {
+        return false;
+    }
+
+    if let Some(post_id) = post_id {
+        if preferences.muted_posts.binary_search(&post_id).is_ok() {
+            return false;
+        }
+    }
+
+    !preferences.only_from_following || FOLLOWS.with(|follows| {
+        follows.borrow().contains_key(&(PrincipalKey(recipient_id), PrincipalKey(actor_id)))
+    })
+}
+
+// Records a notification for the recipient, if their preferences allow it.
+// Users are not notified of their own actions.
+fn notify(recipient_id: Principal, actor_id: Principal, kind: NotificationKind, post_id: Option<u64>, comment_id: Option<u64>) {
+    if recipient_id == actor_id || !user_exists(&recipient_id) {
+        return;
+    }
+    if !wants_notification(recipient_id, actor_id, kind, post_id) {
+        return;
+    }
+
+    let notification = Notification {
+        notification_id: next_notification_id(),
+        recipient_id,
+        actor_id,
+        kind,
+        post_id,
+        comment_id,
+        created_at: current_time(),
+        read: false,
+    };
+    let notification_id = notification.notification_id;
+    NOTIFICATION_RECIPIENTS.with(|recipients| recipients.borrow_mut().insert(notification_id, PrincipalKey(recipient_id)));
+    NOTIFICATION_GROUPS.with(|groups| groups.borrow_mut().insert(notification_group_key(&notification), ()));
+    NOTIFICATION_ACTORS.with(|actors| actors.borrow_mut().insert((PrincipalKey(actor_id), notification_id), ()));
+    NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().insert((PrincipalKey(recipient_id), notification_id), notification)
+    });
+    update_unread_count(recipient_id, |count| count + 1);
+}
+
+// Notifies every user mentioned in a post or comment
+fn notify_mentions(mentions: &[Principal], actor_id: Principal, post_id: u64, comment_id: Option<u64>) {
+    let mut notified = HashSet::new();
+    for mentioned in mentions {
+        if notified.insert(*mentioned) {
+            notify(*mentioned, actor_id, NotificationKind::Mention, Some(post_id), comment_id);
+        }
+    }
+}
+
+fn load_notification(recipient_id: Principal, notification_id: u64) -> Option<Notification> {
+    NOTIFICATIONS.with(|notifications| notifications.borrow().get(&(PrincipalKey(recipient_id), notification_id)))
+}
+
+// Helper function to remove a notification together with its index entries
+fn remove_notification(recipient_id: Principal, notification_id: u64) {
+    let Some(notification) = NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().remove(&(PrincipalKey(recipient_id), notification_id))
+    }) else {
+        return;
+    };
+    NOTIFICATION_RECIPIENTS.with(|recipients| recipients.borrow_mut().remove(&notification_id));
+    NOTIFICATION_GROUPS.with(|groups| groups.borrow_mut().remove(&notification_group_key(&notification)));
+    NOTIFICATION_ACTORS.with(|actors| actors.borrow_mut().remove(&(PrincipalKey(notification.actor_id), notification_id)));
+    if !notification.read {
+        update_unread_count(recipient_id, |count| count.saturating_sub(1));
+    }
+}
+
+// Helper function to remove every notification a user received or caused
+fn remove_user_notifications(user_id: Principal) {
+    let received: Vec<u64> = NOTIFICATIONS.with(|notifications| {
+        notifications.borrow().range(user_items_before(user_id, None))
+            .map(|((_, notification_id), _)| notification_id)
+            .collect()
+    });
+    for notification_id in received {
+        remove_notification(user_id, notification_id);
+    }
+
+    let caused: Vec<u64> = NOTIFICATION_ACTORS.with(|actors| {
+        actors.borrow().range(user_items_before(user_id, None))
+            .map(|((_, notification_id), _)| notification_id)
+            .collect()
+    });
+    for notification_id in caused {
+        if let Some(recipient) = NOTIFICATION_RECIPIENTS.with(|recipients| recipients.borrow().get(&notification_id)) {
+            remove_notification(recipient.0, notification_id);
+        }
+    }
+}
+
+// Whether no newer notification belongs to the same group
+fn is_newest_in_group(key: &NotificationGroupKey) -> bool {
+    let last = NotificationGroupKey { notification_id: u64::MAX, ..*key };
+    NOTIFICATION_GROUPS.with(|groups| {
+        groups.borrow().range((std::ops::Bound::Excluded(*key), std::ops::Bound::Included(last))).next().is_none()
+    })
+}
+
+// Summarizes a group from its newest notification, reading at most NOTIFICATION_GROUP_SCAN
+// of its notifications
+fn notification_group(newest: &Notification) -> NotificationGroup {
+    let key = notification_group_key(newest);
+    let first = NotificationGroupKey { notification_id: 0, ..key };
+    let notifications: Vec<Notification> = NOTIFICATION_GROUPS.with(|groups| {
+        groups.borrow().range(first..=key).rev()
+            .take(NOTIFICATION_GROUP_SCAN)
+            .filter_map(|(member, _)| load_notification(newest.recipient_id, member.notification_id))
+            .collect()
+    });
+
+    let mut group = NotificationGroup {
+        kind: newest.kind,
+        post_id: newest.post_id,
+        comment_id: newest.comment_id,
+        actors: Vec::new(),
+        actor_count: 0,
+        notification_ids: Vec::new(),
+        latest_at: newest.created_at,
+        unread: false,
+    };
+    let mut actors = HashSet::new();
+    for notification in notifications {
+        group.notification_ids.push(notification.notification_id);
+        group.unread |= !notification.read;
+        if actors.insert(notification.actor_id) {
+            if group.actors.len() < NOTIFICATION_GROUP_ACTORS {
+                group.actors.push(notification.actor_id);
+            }
+            group.actor_count += 1;
+        }
+    }
+    group
+}
+
+// The caller's notifications, grouped, most recent group first. A group is listed once,
+// at the position of its newest notification, so it never spans two pages; a group that
+// gets a new notification between two requests moves to the top. The cursor is the ID of
+// the last notification a page read.
+#[ic_cdk::query]
+fn get_notifications(cursor: Option<String>, limit: u64) -> Result<Page<NotificationGroup>, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    let limit = page_limit(limit, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT);
+    let before: Option<u64> = decode_cursor(cursor)?;
+
+    let mut groups = Vec::new();
+    let mut scanned = 0;
+    let mut last_read = None;
+    let mut more = false;
+    NOTIFICATIONS.with(|notifications| {
+        for ((_, notification_id), notification) in notifications.borrow().range(user_items_before(caller_id, before)).rev() {
+            if groups.len() == limit || scanned >= NOTIFICATION_PAGE_SCAN {
+                more = true;
+                break;
+            }
+            scanned += 1;
+            last_read = Some(notification_id);
+
+            // Older notifications of a group were listed with its newest one
+            if !is_newest_in_group(&notification_group_key(&notification)) {
+                continue;
+            }
+            let group = notification_group(&notification);
+            scanned += group.notification_ids.len();
+            groups.push(group);
+        }
+    });
+
+    let next_cursor = if more { last_read.map(|notification_id| notification_id.encode()) } else { None };
+    Ok(Page { items: groups, next_cursor })
+}
+
+#[ic_cdk::query]
+fn get_unread_notification_count() -> Result<u64, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    Ok(UNREAD_NOTIFICATIONS.with(|unread| unread.borrow().get(&PrincipalKey(caller_id)).unwrap_or(0)))
+}
+
+// Marks one notification as read, or all of them without an ID.
+// Returns the number of notifications that were unread.
+#[ic_cdk::update]
+fn mark_notifications_read(notification_id: Option<u64>) -> Result<u64, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    let notifications: Vec<Notification> = match notification_id {
+        Some(notification_id) => {
+            let notification = load_notification(caller_id, notification_id)
+                .ok_or(ApiError::NotFound { resource: "Notification".to_string() })?;
+            vec![notification]
+        }
+        None => NOTIFICATIONS.with(|notifications| {
+            notifications.borrow().range(user_items_before(caller_id, None))
+                .map(|(_, notification)| notification)
+                .filter(|notification| !notification.read)
+                .collect()
+        }),
+    };
+
+    let mut marked = 0;
+    NOTIFICATIONS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for mut notification in notifications.into_iter().filter(|notification| !notification.read) {
+            notification.read = true;
+            stored.insert((PrincipalKey(caller_id), notification.notification_id), notification);
+            marked += 1;
+        }
+    });
+    update_unread_count(caller_id, |count| count.saturating_sub(marked));
+    Ok(marked)
+}
+
+// Removes one batch of notifications older than the retention period
+fn prune_notifications() {
+    let cutoff = current_time().saturating_sub(NOTIFICATION_RETENTION_NS);
+
+    let oldest: Vec<(u64, PrincipalKey)> = NOTIFICATION_RECIPIENTS.with(|recipients| {
+        recipients.borrow().iter().take(NOTIFICATION_PRUNE_BATCH).collect()
+    });
+    for (notification_id, recipient) in oldest {
+        // Notifications are listed oldest first, the rest are newer
+        let expired = load_notification(recipient.0, notification_id)
+            .is_none_or(|notification| notification.created_at < cutoff);
+        if !expired {
+            break;
+        }
+        remove_notification(recipient.0, notification_id);
+        NOTIFICATION_RECIPIENTS.with(|recipients| recipients.borrow_mut().remove(&notification_id));
+    }
+}
+
+// ============ DIRECT MESSAGES ============
+
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+fn next_conversation_id() -> u64 {
+    CONVERSATION_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = *counter.get();
+        let next_id = current_id + 1;
+        counter.set(next_id).expect("Failed to update conversation ID counter");
+        next_id
+    })
+}
+
+fn next_message_id() -> u64 {
+    MESSAGE_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = *counter.get();
+        let next_id = current_id + 1;
+        counter.set(next_id).expect("Failed to update message ID counter");
+        next_id
+    })
+}
+
+fn load_conversation(conversation_id: u64) -> Option<Conversation> {
+    CONVERSATIONS.with(|conversations| conversations.borrow().get(&conversation_id))
+}
+
+fn save_conversation(conversation: Conversation) {
+    CONVERSATIONS.with(|conversations| conversations.borrow_mut().insert(conversation.conversation_id, conversation));
+}
+
+fn load_message(conversation_id: u64, message_id: u64) -> Option<Message> {
+    MESSAGES.with(|messages| messages.borrow().get(&(conversation_id, message_id)))
+}
+
+fn save_message(message: Message) {
+    MESSAGES.with(|messages| messages.borrow_mut().insert((message.conversation_id, message.message_id), message));
+}
+
+// Key of the direct conversation between two users, the same whoever asks
+fn direct_conversation_key(a: Principal, b: Principal) -> (PrincipalKey, PrincipalKey) {
+    if PrincipalKey(a) <= PrincipalKey(b) {
+        (PrincipalKey(a), PrincipalKey(b))
+    } else {
+        (PrincipalKey(b), PrincipalKey(a))
+    }
+}
+
+// Helper function to load a conversation the caller takes part in
+fn load_participating_conversation(conversation_id: u64, user_id: Principal) -> Result<Conversation, ApiError> {
+    let conversation = load_conversation(conversation_id)
+        .ok_or(ApiError::NotFound { resource: "Conversation".to_string() })?;
+    if !conversation.participants.contains(&user_id) {
+        return Err(ApiError::Unauthorized { reason: "You are not a participant of this conversation".to_string() });
+    }
+    Ok(conversation)
+}
+
+// Adds a conversation to a participant's conversation list and inbox
+fn add_conversation_membership(conversation: &Conversation, user_id: Principal) {
+    USER_CONVERSATIONS.with(|user_conversations| {
+        user_conversations.borrow_mut().insert((PrincipalKey(user_id), conversation.conversation_id), ())
+    });
+    CONVERSATION_INBOX.with(|inbox| {
+        inbox.borrow_mut().insert((PrincipalKey(user_id), conversation.last_activity_at, conversation.conversation_id), ())
+    });
+}
+
+// Returns the direct conversation between two users, starting it if needed
+fn direct_conversation(sender_id: Principal, recipient_id: Principal) -> Conversation {
+    let key = direct_conversation_key(sender_id, recipient_id);
+    if let Some(conversation) = DIRECT_CONVERSATIONS.with(|direct| direct.borrow().get(&key)).and_then(load_conversation) {
+        return conversation;
+    }
+
+    let conversation = Conversation {
+        conversation_id: next_conversation_id(),
+        participants: vec![sender_id, recipient_id],
+        created_at: current_time(),
+        last_activity_at: current_time(),
+        last_message_id: None,
+        is_group: false,
+        title: None,
+        owner_id: None,
+    };
+    DIRECT_CONVERSATIONS.with(|direct| direct.borrow_mut().insert(key, conversation.conversation_id));
+    for participant in &conversation.participants {
+        add_conversation_membership(&conversation, *participant);
+    }
+    save_conversation(conversation.clone());
+    conversation
+}
+
+// Moves a conversation to the top of every participant's inbox
+fn touch_conversation(conversation: &mut Conversation, at: u64) {
+    CONVERSATION_INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        for participant in &conversation.participants {
+            inbox.remove(&(PrincipalKey(*participant), conversation.last_activity_at, conversation.conversation_id));
+            inbox.insert((PrincipalKey(*participant), at, conversation.conversation_id), ());
+        }
+    });
+    conversation.last_activity_at = at;
+}
+
+// Range of a conversation's messages before a message ID cursor
+fn messages_before(conversation_id: u64, before_message_id: Option<u64>) -> KeyRange<(u64, u64)> {
+    let end = match before_message_id {
+        Some(message_id) => std::ops::Bound::Excluded((conversation_id, message_id)),
+        None => std::ops::Bound::Included((conversation_id, u64::MAX)),
+    };
+    (std::ops::Bound::Included((conversation_id, 0)), end)
+}
+
+// ID of the newest message of a conversation, deleted or not
+fn latest_message_id(conversation_id: u64) -> Option<u64> {
+    MESSAGES.with(|messages| {
+        messages.borrow().range(messages_before(conversation_id, None)).next_back().map(|((_, message_id), _)| message_id)
+    })
+}
+
+// Newest message that has not been deleted, walking back from the end of the conversation
+fn find_last_message_id(conversation_id: u64) -> Option<u64> {
+    MESSAGES.with(|messages| {
+        messages.borrow().range(messages_before(conversation_id, None)).rev()
+            .find(|(_, message)| !message.is_deleted)
+            .map(|((_, message_id), _)| message_id)
+    })
+}
+
+fn read_receipt(conversation_id: u64, user_id: Principal) -> Option<ReadReceipt> {
+    READ_RECEIPTS.with(|receipts| receipts.borrow().get(&(conversation_id, PrincipalKey(user_id))))
+}
+
+// Messages from the other participants the user has not read yet
+fn unread_message_count(conversation_id: u64, user_id: Principal) -> u64 {
+    UNREAD_COUNTS.with(|counts| counts.borrow().get(&(conversation_id, PrincipalKey(user_id)))).unwrap_or(0)
+}
+
+fn set_unread_message_count(conversation_id: u64, user_id: Principal, count: u64) {
+    UNREAD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        if count == 0 {
+            counts.remove(&(conversation_id, PrincipalKey(user_id)));
+        } else {
+            counts.insert((conversation_id, PrincipalKey(user_id)), count);
+        }
+    });
+}
+
+// Marks everything up to the given message as read, so the unread count starts over
+fn mark_read_up_to(conversation_id: u64, user_id: Principal, message_id: u64) {
+    let receipt = ReadReceipt {
+        user_id,
+        last_read_message_id: message_id,
+        read_at: current_time(),
+    };
+    READ_RECEIPTS.with(|receipts| receipts.borrow_mut().insert((conversation_id, PrincipalKey(user_id)), receipt));
+    set_unread_message_count(conversation_id, user_id, 0);
+}
+
+// Stores a new message, moves the conversation to the top of the inboxes and counts
+// it as unread for the other participants
+fn append_message(mut conversation: Conversation, sender_id: Principal, content: String) -> Result<Message, ApiError> {
+    validate_content(&content, "Message", MAX_MESSAGE_LENGTH)?;
+
+    let message = Message {
+        message_id: next_message_id(),
+        conversation_id: conversation.conversation_id,
+        sender_id,
+        content: content.trim().to_string(),
+        created_at: current_time(),
+        is_deleted: false,
+    };
+    SENT_MESSAGES.with(|sent| {
+        sent.borrow_mut().insert((PrincipalKey(sender_id), message.message_id), message.conversation_id)
+    });
+    save_message(message.clone());
+
+    for participant in conversation.participants.iter().filter(|participant| **participant != sender_id) {
+        let unread = unread_message_count(message.conversation_id, *participant);
+        set_unread_message_count(message.conversation_id, *participant, unread + 1);
+    }
+
+    touch_conversation(&mut conversation, message.created_at);
+    conversation.last_message_id = Some(message.message_id);
+    save_conversation(conversation);
+
+    // Sending a message means the sender has read everything before it
+    mark_read_up_to(message.conversation_id, sender_id, message.message_id);
+
+    Ok(message)
+}
+
+// Deletes a message for every participant: participants who hadn't read it no longer
+// count it, and the conversation's last message falls back to the one before
+fn withdraw_message(conversation: &mut Conversation, mut message: Message) {
+    for participant in conversation.participants.iter().filter(|participant| **participant != message.sender_id) {
+        let last_read = read_receipt(conversation.conversation_id, *participant).map_or(0, |receipt| receipt.last_read_message_id);
+        if last_read < message.message_id {
+            let unread = unread_message_count(conversation.conversation_id, *participant);
+            set_unread_message_count(conversation.conversation_id, *participant, unread.saturating_sub(1));
+        }
+    }
+
+    let message_id = message.message_id;
+    message.is_deleted = true;
+    message.content.clear();
+    save_message(message);
+
+    if conversation.last_message_id == Some(message_id) {
+        conversation.last_message_id = find_last_message_id(conversation.conversation_id);
+    }
+}
+
+// Sends a direct message, starting the conversation on the first message
+#[ic_cdk::update]
+fn send_message(recipient_id: Principal, content: String) -> Result<Message, ApiError> {
+    let sender_id = caller();
+    require_registered(&sender_id)?;
+
+    if !user_exists(&recipient_id) {
+        return Err(ApiError::NotFound { resource: "User".to_string() });
+    }
+    if sender_id == recipient_id {
+        return Err(ApiError::Validation {
+            field: "recipient_id".to_string(),
+            reason: "You cannot message yourself".to_string(),
+        });
+    }
+    // Checked here too so an invalid first message doesn't start an empty conversation
+    validate_content(&content, "Message", MAX_MESSAGE_LENGTH)?;
+
+    let conversation = direct_conversation(sender_id, recipient_id);
+    append_message(conversation, sender_id, content)
+}
+
+// Sends a message to an existing conversation the caller is a member of
+#[ic_cdk::update]
+fn send_conversation_message(conversation_id: u64, content: String) -> Result<Message, ApiError> {
+    let sender_id = caller();
+    require_registered(&sender_id)?;
+    let conversation = load_participating_conversation(conversation_id, sender_id)?;
+
+    // The other side of a direct conversation may have deleted their account
+    if !conversation.is_group && conversation.participants.len() < 2 {
+        return Err(ApiError::Conflict { reason: "The other participant has left this conversation".to_string() });
+    }
+
+    append_message(conversation, sender_id, content)
+}
+
+// The caller's conversations, most recently active first
+#[ic_cdk::query]
+fn get_conversations(cursor: Option<String>, limit: u64) -> Result<Page<ConversationSummary>, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    let before: Option<(u64, u64)> = decode_cursor(cursor)?;
+    let user_key = PrincipalKey(caller_id);
+    let end = before.map_or(std::ops::Bound::Included((user_key, u64::MAX, u64::MAX)), |(activity_at, conversation_id)| {
+        std::ops::Bound::Excluded((user_key, activity_at, conversation_id))
+    });
+    let range = (std::ops::Bound::Included((user_key, 0, 0)), end);
+
+    Ok(CONVERSATION_INBOX.with(|inbox| {
+        let load = |((_, _, conversation_id), ()): ((PrincipalKey, u64, u64), ())| {
+            let conversation = load_conversation(conversation_id)?;
+            Some(ConversationSummary {
+                last_message: conversation.last_message_id.and_then(|message_id| load_message(conversation_id, message_id)),
+                unread_count: unread_message_count(conversation_id, caller_id),
+                conversation,
+            })
+        };
+        scan_page(&inbox.borrow(), range, PageOrder::NewestFirst, limit, load, |summary| {
+            (summary.conversation.last_activity_at, summary.conversation.conversation_id)
+        })
+    }))
+}
+
+// Messages of a conversation, newest first. Deleted messages are left out.
+#[ic_cdk::query]
+fn get_messages(conversation_id: u64, cursor: Option<String>, limit: u64) -> Result<Page<Message>, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    load_participating_conversation(conversation_id, caller_id)?;
+
+    let before = decode_cursor(cursor)?;
+    Ok(MESSAGES.with(|messages| {
+        let load = |(_, message): ((u64, u64), Message)| Some(message).filter(|message| !message.is_deleted);
+        let range = messages_before(conversation_id, before);
+        scan_page(&messages.borrow(), range, PageOrder::NewestFirst, limit, load, |message| message.message_id)
+    }))
+}
+
+// Marks every message of a conversation as read by the caller
+#[ic_cdk::update]
+fn mark_conversation_read(conversation_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    load_participating_conversation(conversation_id, caller_id)?;
+
+    if let Some(message_id) = latest_message_id(conversation_id) {
+        mark_read_up_to(conversation_id, caller_id, message_id);
+    }
+    Ok(())
+}
+
+// How far each participant has read the conversation
+#[ic_cdk::query]
+fn get_read_receipts(conversation_id: u64) -> Result<Vec<ReadReceipt>, ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    let conversation = load_participating_conversation(conversation_id, caller_id)?;
+
+    Ok(conversation.participants.into_iter()
+        .filter_map(|participant| read_receipt(conversation_id, participant))
+        .collect())
+}
+
+// Deletes one of the caller's own messages for every participant
+#[ic_cdk::update]
+fn delete_message(conversation_id: u64, message_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    let mut conversation = load_participating_conversation(conversation_id, caller_id)?;
+
+    let message = load_message(conversation_id, message_id)
+        .ok_or(ApiError::NotFound { resource: "Message".to_string() })?;
+    if message.sender_id != caller_id {
+        return Err(ApiError::Unauthorized { reason: "You can only delete your own messages".to_string() });
+    }
+    if message.is_deleted {
+        return Err(ApiError::Deleted { resource: "Message".to_string() });
+    }
+
+    withdraw_message(&mut conversation, message);
+    save_conversation(conversation);
+    Ok(())
+}
+
+// Drops a member from a conversation along with their inbox entry, read pointer and
+// unread count. When a group's owner leaves, the longest-standing remaining member takes over.
+fn remove_participant(conversation: &mut Conversation, user_id: Principal) {
+    // A new message between the same principals starts a new direct conversation
+    if let (false, [a, b]) = (conversation.is_group, &conversation.participants[..]) {
+        DIRECT_CONVERSATIONS.with(|direct| direct.borrow_mut().remove(&direct_conversation_key(*a, *b)));
+    }
+    conversation.participants.retain(|participant| *participant != user_id);
+    if conversation.owner_id == Some(user_id) {
+        conversation.owner_id = conversation.participants.first().copied();
+    }
+
+    let conversation_id = conversation.conversation_id;
+    READ_RECEIPTS.with(|receipts| receipts.borrow_mut().remove(&(conversation_id, PrincipalKey(user_id))));
+    set_unread_message_count(conversation_id, user_id, 0);
+    USER_CONVERSATIONS.with(|user_conversations| {
+        user_conversations.borrow_mut().remove(&(PrincipalKey(user_id), conversation_id))
+    });
+    CONVERSATION_INBOX.with(|inbox| {
+        inbox.borrow_mut().remove(&(PrincipalKey(user_id), conversation.last_activity_at, conversation_id))
+    });
+}
+
+// Removes a deleted user from their conversations: their messages are deleted and
+// their membership dropped. The other participants keep the conversation.
+fn leave_all_conversations(user_id: Principal) {
+    let sent: Vec<(u64, u64)> = SENT_MESSAGES.with(|sent| {
+        sent.borrow().range(user_items_after(user_id, None))
+            .map(|((_, message_id), conversation_id)| (conversation_id, message_id))
+            .collect()
+    });
+    for (conversation_id, message_id) in sent {
+        SENT_MESSAGES.with(|sent| sent.borrow_mut().remove(&(PrincipalKey(user_id), message_id)));
+        let (Some(mut conversation), Some(message)) = (load_conversation(conversation_id), load_message(conversation_id, message_id)) else {
+            continue;
+        };
+        if !message.is_deleted {
+            withdraw_message(&mut conversation, message);
+            save_conversation(conversation);
+        }
+    }
+
+    let conversation_ids = USER_CONVERSATIONS.with(|user_conversations| {
+        user_item_ids(&user_conversations.borrow(), user_id)
+    });
+    for conversation_id in conversation_ids {
+        if let Some(mut conversation) = load_conversation(conversation_id) {
+            remove_participant(&mut conversation, user_id);
+            save_conversation(conversation);
+        }
+    }
+}
+
+// ============ GROUP CONVERSATIONS ============
+
+const DEFAULT_MAX_GROUP_MEMBERS: u64 = 50;
+const GROUP_TITLE_MAX_LEN: usize = 100;
+
+fn max_group_members() -> u64 {
+    MAX_GROUP_MEMBERS.with(|max| *max.borrow().get())
+}
+
+fn validate_group_title(title: &str) -> Result<(), ApiError> {
+    let title = title.trim();
+    if title.is_empty() || title.len() > GROUP_TITLE_MAX_LEN {
+        return Err(ApiError::Validation {
+            field: "title".to_string(),
+            reason: format!("Title must be between 1 and {} characters", GROUP_TITLE_MAX_LEN),
+        });
+    }
+    Ok(())
+}
+
+fn require_group(conversation: &Conversation) -> Result<(), ApiError> {
+    if !conversation.is_group {
+        return Err(ApiError::Validation {
+            field: "conversation_id".to_string(),
+            reason: "Not a group conversation".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to load a group conversation the caller owns
+fn load_owned_group(conversation_id: u64, user_id: Principal) -> Result<Conversation, ApiError> {
+    let conversation = load_participating_conversation(conversation_id, user_id)?;
+    require_group(&conversation)?;
+    if conversation.owner_id != Some(user_id) {
+        return Err(ApiError::Unauthorized { reason: "Only the group owner can manage members".to_string() });
+    }
+    Ok(conversation)
+}
+
+fn check_group_capacity(member_count: usize) -> Result<(), ApiError> {
+    if member_count as u64 > max_group_members() {
+        return Err(ApiError::Validation {
+            field: "members".to_string(),
+            reason: format!("A group can have at most {} members", max_group_members()),
+        });
+    }
+    Ok(())
+}
+
+// Starts a group conversation owned by the caller
+#[ic_cdk::update]
+fn create_group_conversation(title: String, members: Vec<Principal>) -> Result<Conversation, ApiError> {
+    let owner_id = caller();
+    require_registered(&owner_id)?;
+    validate_group_title(&title)?;
+
+    let mut participants = vec![owner_id];
+    for member in members {
+        if !user_exists(&member) {
+            return Err(ApiError::NotFound { resource: "User".to_string() });
+        }
+        if !participants.contains(&member) {
+            participants.push(member);
+        }
+    }
+    check_group_capacity(participants.len())?;
+
+    let conversation = Conversation {
+        conversation_id: next_conversation_id(),
+        participants,
+        created_at: current_time(),
+        last_activity_at: current_time(),
+        last_message_id: None,
+        is_group: true,
+        title: Some(title.trim().to_string()),
+        owner_id: Some(owner_id),
+    };
+    for participant in &conversation.participants {
+        add_conversation_membership(&conversation, *participant);
+    }
+    save_conversation(conversation.clone());
+
+    Ok(conversation)
+}
+
+// Adds a user to a group. Owner only.
+#[ic_cdk::update]
+fn invite_to_conversation(conversation_id: u64, user_id: Principal) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    let mut conversation = load_owned_group(conversation_id, caller_id)?;
+
+    if !user_exists(&user_id) {
+        return Err(ApiError::NotFound { resource: "User".to_string() });
+    }
+    if conversation.participants.contains(&user_id) {
+        return Err(ApiError::AlreadyExists { resource: "Member".to_string() });
+    }
+    check_group_capacity(conversation.participants.len() + 1)?;
+
+    conversation.participants.push(user_id);
+    add_conversation_membership(&conversation, user_id);
+    save_conversation(conversation);
+
+    // New members can read the history but start with nothing unread
+    if let Some(message_id) = latest_message_id(conversation_id) {
+        mark_read_up_to(conversation_id, user_id, message_id);
+    }
+
+    Ok(())
+}
+
+// Removes another member from a group. Owner only, owners leave with leave_conversation.
+#[ic_cdk::update]
+fn remove_from_conversation(conversation_id: u64, user_id: Principal) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    let mut conversation = load_owned_group(conversation_id, caller_id)?;
+
+    if user_id == caller_id {
+        return Err(ApiError::Validation {
+            field: "user_id".to_string(),
+            reason: "Use leave_conversation to leave your own group".to_string(),
+        });
+    }
+    if !conversation.participants.contains(&user_id) {
+        return Err(ApiError::NotFound { resource: "Member".to_string() });
+    }
+
+    remove_participant(&mut conversation, user_id);
+    save_conversation(conversation);
+
+    Ok(())
+}
+
+// Leaves a group. The caller's messages stay, but they lose access to the history.
+#[ic_cdk::update]
+fn leave_conversation(conversation_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+    let mut conversation = load_participating_conversation(conversation_id, caller_id)?;
+    require_group(&conversation)?;
+
+    remove_participant(&mut conversation, caller_id);
+    save_conversation(conversation);
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_get_max_group_members() -> Result<u64, ApiError> {
+    require_admin()?;
+
+    Ok(max_group_members())
+}
+
+// Existing groups above a lowered limit keep their members but can't grow
+#[ic_cdk::update]
+fn admin_set_max_group_members(max_members: u64) -> Result<(), ApiError> {
+    require_admin()?;
+
+    if max_members < 2 {
+        return Err(ApiError::Validation {
+            field: "max_members".to_string(),
+            reason: "A group must allow at least 2 members".to_string(),
+        });
+    }
+    MAX_GROUP_MEMBERS.with(|max| {
+        max.borrow_mut().set(max_members).expect("Failed to set group member limit")
+    });
+    Ok(())
+}
+
+// ============ FOLLOW FUNCTIONS ============
+
+#[ic_cdk::update]
+fn follow_user(user_to_follow: Principal) -> String {
+    match follow_user_v2(user_to_follow) {
+        Ok(_) => "User followed successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn follow_user_v2(user_to_follow: Principal) -> Result<(), ApiError> {
+    let follower_id = caller();
+
+    // Check if user exists
+    require_registered(&follower_id)?;
+
+    // Check if target user exists
+    if !user_exists(&user_to_follow) {
+        return Err(ApiError::NotFound { resource: "User".to_string() });
+    }
+
+    // Can't follow yourself
+    if follower_id == user_to_follow {
+        return Err(ApiError::Validation {
+            field: "user_to_follow".to_string(),
+            reason: "You cannot follow yourself".to_string(),
+        });
+    }
+
+    if !add_follow(follower_id, user_to_follow, current_time()) {
+        return Err(ApiError::AlreadyExists { resource: "Follow".to_string() });
+    }
+    notify(user_to_follow, follower_id, NotificationKind::Follow, None, None);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unfollow_user(user_to_unfollow: Principal) -> String {
+    match unfollow_user_v2(user_to_unfollow) {
+        Ok(_) => "User unfollowed successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn unfollow_user_v2(user_to_unfollow: Principal) -> Result<(), ApiError> {
+    let follower_id = caller();
+
+    if !remove_follow(follower_id, user_to_unfollow) {
+        return Err(ApiError::NotFound { resource: "Follow".to_string() });
+    }
+
+    Ok(())
+}
+
+// Followers and following are listed by follow date (most recent first)
+#[ic_cdk::query]
+fn get_followers(user_id: Principal) -> Vec<Follow> {
+    let mut followers = followers_of(user_id);
+    followers.sort_by_key(|follow| std::cmp::Reverse(follow.created_at));
+    followers
+}
+
+#[ic_cdk::query]
+fn get_following(user_id: Principal) -> Vec<Follow> {
+    let mut following = following_of(user_id);
+    following.sort_by_key(|follow| std::cmp::Reverse(follow.created_at));
+    following
+}
+
+#[ic_cdk::query]
+fn get_followers_paged(user_id: Principal, cursor: Option<String>, limit: u64) -> Result<Page<Follow>, ApiError> {
+    let after = decode_cursor(cursor)?;
+    Ok(FOLLOWERS.with(|followers| {
+        let load = |((following, follower), ()): ((PrincipalKey, PrincipalKey), ())| {
+            FOLLOWS.with(|follows| follows.borrow().get(&(follower, following)))
+        };
+        let range = user_principals_after(user_id, after);
+        scan_page(&followers.borrow(), range, PageOrder::OldestFirst, limit, load, |follow| PrincipalKey(follow.follower_id))
+    }))
+}
+
+#[ic_cdk::query]
+fn get_following_paged(user_id: Principal, cursor: Option<String>, limit: u64) -> Result<Page<Follow>, ApiError> {
+    let after = decode_cursor(cursor)?;
+    Ok(FOLLOWS.with(|follows| {
+        let load = |(_, follow): ((PrincipalKey, PrincipalKey), Follow)| Some(follow);
+        let range = user_principals_after(user_id, after);
+        scan_page(&follows.borrow(), range, PageOrder::OldestFirst, limit, load, |follow| PrincipalKey(follow.following_id))
+    }))
+}
+
+#[ic_cdk::query]
+fn is_following(user_id: Principal) -> bool {
+    let caller_id = caller();
+    FOLLOWS.with(|follows| follows.borrow().contains_key(&(PrincipalKey(caller_id), PrincipalKey(user_id))))
+}
+
+#[ic_cdk::query]
+fn get_user_feed() -> Vec<Post> {
+    // The 50 most recent posts and reposts of followed users and the caller, each post
+    // listed once at its newest entry
+    let authors = feed_authors(caller());
+    let mut seen = std::collections::HashSet::new();
+    let mut feed = Vec::new();
+    let mut cursor = None;
+    while feed.len() < 50 {
+        let Ok(page) = scan_feed(&authors, cursor, 50) else {
+            break;
+        };
+        for entry in page.items {
+            if feed.len() < 50 && seen.insert(entry.post.post_id) {
+                feed.push(entry.post);
+            }
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    feed
+}
+
+#[ic_cdk::query]
+fn get_user_feed_paged(cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let page = scan_feed(&feed_authors(caller()), cursor, limit)?;
+    let mut seen = std::collections::HashSet::new();
+    let items = page.items.into_iter()
+        .map(|entry| entry.post)
+        .filter(|post| seen.insert(post.post_id))
+        .collect();
+    Ok(Page { items, next_cursor: page.next_cursor })
+}
+
+// Like get_user_feed_paged, with reposts listed as their own entries
+#[ic_cdk::query]
+fn get_user_feed_entries(cursor: Option<String>, limit: u64) -> Result<Page<FeedEntry>, ApiError> {
+    scan_feed(&feed_authors(caller()), cursor, limit)
+}
+
+// A user's posts and reposts, newest first
+#[ic_cdk::query]
+fn get_user_timeline(user_id: Principal, cursor: Option<String>, limit: u64) -> Result<Page<FeedEntry>, ApiError> {
+    scan_feed(&[user_id], cursor, limit)
+}
+
+// ============ SHARE FUNCTIONS ============
+
+#[ic_cdk::update]
+fn share_post(post_id: u64) -> String {
+    match share_post_v2(post_id) {
+        Ok(_) => "Post shared successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[ic_cdk::update]
+fn share_post_v2(post_id: u64) -> Result<(), ApiError> {
+    repost(post_id)
+}
+
+// Shares a post to the caller's followers. A user reposts a post at most once.
+#[ic_cdk::update]
+fn repost(post_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    // Check if user exists
+    require_registered(&caller_id)?;
+
+    // Check if post exists and is not deleted
+    let mut post = load_active_post(post_id)?;
+
+    if !insert_user_item(&USER_SHARES, &POST_SHARERS, caller_id, post_id) {
+        return Err(ApiError::AlreadyExists { resource: "Repost".to_string() });
+    }
+    index_repost(caller_id, post_id, current_time());
+    notify(post.author_id, caller_id, NotificationKind::Share, Some(post_id), None);
+
+    post.shares_count += 1;
+    save_post(post);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn undo_repost(post_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+    require_registered(&caller_id)?;
+
+    if !remove_repost(caller_id, post_id) {
+        return Err(ApiError::NotFound { resource: "Repost".to_string() });
+    }
+    Ok(())
+}
+
+// Creates a post quoting another one
+#[ic_cdk::update]
+fn quote_post(
+    quoted_post_id: u64,
+    content: String,
+    hashtags: Vec<String>,
+    media_urls: Vec<String>,
+) -> Result<u64, ApiError> {
+    insert_post(caller(), content, hashtags, media_urls, Some(quoted_post_id))
+}
+
+// Live posts quoting a post, newest first
+#[ic_cdk::query]
+fn get_quote_posts(post_id: u64, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let before: Option<u64> = decode_cursor(cursor)?;
+    let end = before.map_or(std::ops::Bound::Included((post_id, u64::MAX)), |quote_id| std::ops::Bound::Excluded((post_id, quote_id)));
+    Ok(QUOTE_POSTS.with(|quotes| {
+        let load = |((_, quote_id), ()): ((u64, u64), ())| load_post(quote_id).filter(|post| !post.is_deleted);
+        let range = (std::ops::Bound::Included((post_id, 0)), end);
+        scan_page(&quotes.borrow(), range, PageOrder::NewestFirst, limit, load, |post| post.post_id)
+    }))
+}
+
+fn index_repost(user_id: Principal, post_id: u64, reposted_at: u64) {
+    let user_key = PrincipalKey(user_id);
+    REPOSTED_AT.with(|reposted| reposted.borrow_mut().insert((user_key, post_id), reposted_at));
+    USER_REPOSTS.with(|reposts| reposts.borrow_mut().insert((user_key, reposted_at, post_id), ()));
+}
+
+fn unindex_repost(user_id: Principal, post_id: u64) {
+    let user_key = PrincipalKey(user_id);
+    if let Some(reposted_at) = REPOSTED_AT.with(|reposted| reposted.borrow_mut().remove(&(user_key, post_id))) {
+        USER_REPOSTS.with(|reposts| reposts.borrow_mut().remove(&(user_key, reposted_at, post_id)));
+    }
+}
+
+// Removes a user's repost of a post. Returns false if there was none.
+fn remove_repost(user_id: Principal, post_id: u64) -> bool {
+    if !remove_user_item(&USER_SHARES, &POST_SHARERS, user_id, post_id) {
+        return false;
+    }
+    unindex_repost(user_id, post_id);
+    if let Some(mut post) = load_post(post_id) {
+        post.shares_count = post.shares_count.saturating_sub(1);
+        save_post(post);
+    }
+    true
+}
+
+// Removes every repost of a post, leaving its shares_count to the caller
+fn remove_all_reposts(post_id: u64) {
+    for user_id in remove_item_users(&USER_SHARES, &POST_SHARERS, post_id) {
+        unindex_repost(user_id, post_id);
+    }
+}
+
+// ============ COMMENT LIKE FUNCTIONS ============
+
 #[ic_cdk::update]
-fn unlike_post(post_id: u64) -> String {
-    let caller_id = caller();
+fn like_comment(comment_id: u64) -> String {
+    match like_comment_v2(comment_id) {
+        Ok(_) => "Comment liked successfully".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
 
-    // Check if user exists
-    if !user_exists(&caller_id) {
-        return "User must be registered to unlike posts".to_string();
+#[ic_cdk::update]
+fn like_comment_v2(comment_id: u64) -> Result<(), ApiError> {
+    add_reaction(caller(), ReactionTarget::Comment(comment_id), LIKE_REACTION, "Like")
+}
+
+#[ic_cdk::update]
+fn unlike_comment(comment_id: u64) -> String {
+    match unlike_comment_v2(comment_id) {
+        Ok(_) => "Comment unliked successfully".to_string(),
+        Err(e) => e.to_string(),
     }
+}
 
-    // Check if user has liked this post
-    {
-        let mut user_likes = USER_LIKES.lock().unwrap();
-        if let Some(user_liked_posts) = user_likes.get_mut(&caller_id) {
-            if !user_liked_posts.contains(&post_id) {
-                return "You haven't liked this post".to_string();
-            }
+#[ic_cdk::update]
+fn unlike_comment_v2(comment_id: u64) -> Result<(), ApiError> {
+    withdraw_reaction(caller(), ReactionTarget::Comment(comment_id), Some(LIKE_REACTION), "Like")
+}
 
-            user_liked_posts.remove(&post_id);
-        } else {
-            return "You haven't liked this post".to_string();
-        }
+// ============ EDIT HISTORY ============
+
+type RevisionMap = StableBTreeMap<(u64, u64), Revision, Memory>;
+
+fn edit_policy() -> EditPolicy {
+    EDIT_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+// The edit window applies to everyone, admins included
+fn check_edit_window(created_at: u64, kind: &str) -> Result<(), ApiError> {
+    let Some(window) = edit_policy().edit_window_ns else {
+        return Ok(());
+    };
+    if current_time().saturating_sub(created_at) > window {
+        return Err(ApiError::Unauthorized {
+            reason: format!("The edit window for this {} has closed", kind),
+        });
     }
+    Ok(())
+}
 
-    // Decrement like count
-    {
-        let mut posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get_mut(&post_id) {
-            if post.likes > 0 {
-                post.likes -= 1;
-            }
+fn revisions_of(revisions: &'static LocalKey<RefCell<RevisionMap>>, item_id: u64) -> Vec<Revision> {
+    revisions.with(|revisions| {
+        revisions.borrow().range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+// Numbers the revision after the item's last one
+fn add_revision(revisions: &'static LocalKey<RefCell<RevisionMap>>, item_id: u64, revision: Revision) {
+    revisions.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        let number = revisions.range((item_id, 0)..=(item_id, u64::MAX)).next_back().map_or(0, |((_, number), _)| number + 1);
+        revisions.insert((item_id, number), revision);
+    });
+}
+
+fn remove_revisions(revisions: &'static LocalKey<RefCell<RevisionMap>>, item_id: u64) {
+    revisions.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        let keys: Vec<(u64, u64)> = revisions.range((item_id, 0)..=(item_id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            revisions.remove(&key);
         }
-    }
+    });
+}
 
-    "Post unliked successfully".to_string()
+// Revisions are readable by everyone when the policy allows it, otherwise by the
+// author and admins. Deleted items are only visible to admins.
+fn check_revision_access(author_id: Principal, is_deleted: bool, resource: &str) -> Result<(), ApiError> {
+    let caller_id = caller();
+    if is_admin(&caller_id) {
+        return Ok(());
+    }
+    if is_deleted {
+        return Err(ApiError::Deleted { resource: resource.to_string() });
+    }
+    if !edit_policy().revisions_public && author_id != caller_id {
+        return Err(ApiError::Unauthorized { reason: "Edit history is only visible to the author and admins".to_string() });
+    }
+    Ok(())
 }
 
+// Earlier versions of a post, oldest first. The current content is on the post itself.
 #[ic_cdk::query]
-fn has_user_liked_post(post_id: u64) -> bool {
-    let caller_id = caller();
-    let user_likes = USER_LIKES.lock().unwrap();
+fn get_post_revisions(post_id: u64) -> Result<Vec<Revision>, ApiError> {
+    let post = load_post(post_id).ok_or(ApiError::NotFound { resource: "Post".to_string() })?;
+    check_revision_access(post.author_id, post.is_deleted, "Post")?;
+    Ok(revisions_of(&POST_REVISIONS, post_id))
+}
 
-    if let Some(user_liked_posts) = user_likes.get(&caller_id) {
-        user_liked_posts.contains(&post_id)
-    } else {
-        false
-    }
+#[ic_cdk::query]
+fn get_comment_revisions(comment_id: u64) -> Result<Vec<Revision>, ApiError> {
+    let comment = load_comment(comment_id).ok_or(ApiError::NotFound { resource: "Comment".to_string() })?;
+    check_revision_access(comment.author_id, comment.is_deleted, "Comment")?;
+    Ok(revisions_of(&COMMENT_REVISIONS, comment_id))
 }
 
 #[ic_cdk::query]
-fn get_user_liked_posts() -> Vec<u64> {
-    let caller_id = caller();
-    let user_likes = USER_LIKES.lock().unwrap();
+fn admin_get_edit_policy() -> Result<EditPolicy, ApiError> {
+    require_admin()?;
 
-    if let Some(user_liked_posts) = user_likes.get(&caller_id) {
-        user_liked_posts.iter().cloned().collect()
-    } else {
-        Vec::new()
+    Ok(edit_policy())
+}
+
+// A new edit window applies to existing posts and comments too
+#[ic_cdk::update]
+fn admin_set_edit_policy(policy: EditPolicy) -> Result<(), ApiError> {
+    require_admin()?;
+
+    if policy.edit_window_ns == Some(0) {
+        return Err(ApiError::Validation {
+            field: "edit_window_ns".to_string(),
+            reason: "The edit window must be positive, use no window to allow edits at any time".to_string(),
+        });
     }
+    EDIT_POLICY.with(|cell| cell.borrow_mut().set(policy).expect("Failed to set edit policy"));
+    Ok(())
 }
 
-// ============ ADMIN-ONLY FUNCTIONS ============
+// ============ BOOKMARKS ============
 
-#[ic_cdk::query]
-fn is_caller_admin() -> bool {
-    is_admin(&caller())
+const MAX_BOOKMARK_COLLECTIONS: usize = 100;
+const COLLECTION_NAME_MAX_LEN: usize = 50;
+
+fn collection_key(user_id: Principal, name: &str) -> CollectionKey {
+    CollectionKey { user: PrincipalKey(user_id), name: name.to_string() }
 }
 
-#[ic_cdk::update]
-fn admin_delete_user(target_user_id: Principal) -> String {
-    match require_admin() {
-        Ok(_) => {
-            // First, delete all posts by this user
-            {
-                let mut posts = POSTS.lock().unwrap();
-                for post in posts.values_mut() {
-                    if post.author_id == target_user_id {
-                        post.is_deleted = true;
-                    }
-                }
-            }
+// Helper function to list a user's collections with their creation times
+fn collections_of(user_id: Principal) -> Vec<(String, u64)> {
+    BOOKMARK_COLLECTIONS.with(|collections| {
+        collections.borrow().range(collection_key(user_id, "")..)
+            .take_while(|(key, _)| key.user.0 == user_id)
+            .map(|(key, created_at)| (key.name, created_at))
+            .collect()
+    })
+}
 
-            // Delete all comments by this user
-            {
-                let mut comments = COMMENTS.lock().unwrap();
-                for comment in comments.values_mut() {
-                    if comment.author_id == target_user_id {
-                        comment.is_deleted = true;
-                    }
-                }
-            }
+fn collection_exists(user_id: Principal, name: &str) -> bool {
+    BOOKMARK_COLLECTIONS.with(|collections| collections.borrow().contains_key(&collection_key(user_id, name)))
+}
 
-            // Remove user from likes
-            {
-                let mut user_likes = USER_LIKES.lock().unwrap();
-                user_likes.remove(&target_user_id);
-            }
+// Range of a collection's entries in COLLECTION_BOOKMARKS
+fn collection_range(collection: &CollectionKey) -> std::ops::RangeInclusive<(CollectionKey, u64, u64)> {
+    (collection.clone(), 0, 0)..=(collection.clone(), u64::MAX, u64::MAX)
+}
+
+fn index_bookmark(user_id: Principal, bookmark: &Bookmark) {
+    let user_key = PrincipalKey(user_id);
+    USER_BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().insert((user_key, bookmark.created_at, bookmark.post_id), ()));
+    if let Some(name) = &bookmark.collection {
+        let key = (collection_key(user_id, name), bookmark.created_at, bookmark.post_id);
+        COLLECTION_BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().insert(key, ()));
+    }
+    POST_BOOKMARKERS.with(|bookmarkers| bookmarkers.borrow_mut().insert((bookmark.post_id, user_key), ()));
+}
 
-            // Remove user from following relationships
-            {
-                let mut follows = FOLLOWS.lock().unwrap();
-                follows.remove(&target_user_id);
+fn unindex_bookmark(user_id: Principal, bookmark: &Bookmark) {
+    let user_key = PrincipalKey(user_id);
+    USER_BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().remove(&(user_key, bookmark.created_at, bookmark.post_id)));
+    if let Some(name) = &bookmark.collection {
+        let key = (collection_key(user_id, name), bookmark.created_at, bookmark.post_id);
+        COLLECTION_BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().remove(&key));
+    }
+    POST_BOOKMARKERS.with(|bookmarkers| bookmarkers.borrow_mut().remove(&(bookmark.post_id, user_key)));
+}
 
-                // Also remove this user from other users' following lists
-                for following_set in follows.values_mut() {
-                    following_set.remove(&target_user_id);
-                }
-            }
+fn save_bookmark(user_id: Principal, bookmark: Bookmark) {
+    if let Some(previous) = BOOKMARKS.with(|bookmarks| bookmarks.borrow().get(&(PrincipalKey(user_id), bookmark.post_id))) {
+        unindex_bookmark(user_id, &previous);
+    }
+    index_bookmark(user_id, &bookmark);
+    BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().insert((PrincipalKey(user_id), bookmark.post_id), bookmark));
+}
 
-            // Finally, delete the user
-            let mut users = USERS.lock().unwrap();
-            if users.remove(&target_user_id).is_some() {
-                format!("User {} and all their data deleted successfully by admin", target_user_id)
-            } else {
-                "User not found".to_string()
-            }
+fn delete_bookmark(user_id: Principal, post_id: u64) -> bool {
+    match BOOKMARKS.with(|bookmarks| bookmarks.borrow_mut().remove(&(PrincipalKey(user_id), post_id))) {
+        Some(bookmark) => {
+            unindex_bookmark(user_id, &bookmark);
+            true
         }
-        Err(e) => e,
+        None => false,
     }
 }
 
-#[ic_cdk::update]
-fn admin_promote_user(target_user_id: Principal) -> String {
-    match require_admin() {
-        Ok(_) => {
-            // Add to promoted admins
-            let mut promoted_admins = PROMOTED_ADMINS.lock().unwrap();
-            promoted_admins.insert(target_user_id);
-
-            // Update user's admin status
-            let mut users = USERS.lock().unwrap();
-            if let Some(user) = users.get_mut(&target_user_id) {
-                user.is_admin = true;
-                format!("User {} promoted to admin successfully", target_user_id)
-            } else {
-                "User not found".to_string()
-            }
-        }
-        Err(e) => e,
+// Removes all of a user's bookmarks and collections
+fn remove_bookmarks(user_id: Principal) {
+    let post_ids = BOOKMARKS.with(|bookmarks| {
+        bookmarks.borrow().range(user_items_after(user_id, None)).map(|((_, post_id), _)| post_id).collect::<Vec<u64>>()
+    });
+    for post_id in post_ids {
+        delete_bookmark(user_id, post_id);
+    }
+    for (name, _) in collections_of(user_id) {
+        BOOKMARK_COLLECTIONS.with(|collections| collections.borrow_mut().remove(&collection_key(user_id, &name)));
     }
 }
 
-#[ic_cdk::update]
-fn admin_demote_user(target_user_id: Principal) -> String {
-    match require_admin() {
-        Ok(_) => {
-            // Check if user is a predefined admin (cannot be demoted)
-            if PREDEFINED_ADMINS.contains(&target_user_id) {
-                return "Cannot demote predefined admin".to_string();
-            }
+// Trims a collection name and checks its length
+fn validate_collection_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > COLLECTION_NAME_MAX_LEN {
+        return Err(ApiError::Validation {
+            field: "collection".to_string(),
+            reason: format!("Collection names must be 1 to {} characters", COLLECTION_NAME_MAX_LEN),
+        });
+    }
+    Ok(name.to_string())
+}
 
-            // Remove from promoted admins
-            let mut promoted_admins = PROMOTED_ADMINS.lock().unwrap();
-            promoted_admins.remove(&target_user_id);
-
-            // Update user's admin status
-            let mut users = USERS.lock().unwrap();
-            if let Some(user) = users.get_mut(&target_user_id) {
-                user.is_admin = false;
-                format!("User {} demoted from admin successfully", target_user_id)
-            } else {
-                "User not found".to_string()
-            }
-        }
-        Err(e) => e,
+// Creates a collection for the caller, unless it already exists
+fn ensure_collection(user_id: Principal, name: &str) -> Result<(), ApiError> {
+    if collection_exists(user_id, name) {
+        return Ok(());
     }
+    if collections_of(user_id).len() >= MAX_BOOKMARK_COLLECTIONS {
+        return Err(ApiError::Validation {
+            field: "collection".to_string(),
+            reason: format!("At most {} bookmark collections are allowed", MAX_BOOKMARK_COLLECTIONS),
+        });
+    }
+    BOOKMARK_COLLECTIONS.with(|collections| collections.borrow_mut().insert(collection_key(user_id, name), current_time()));
+    Ok(())
 }
 
+// Bookmarks a post, optionally filed in a collection that is created on first use.
+// Bookmarking a post again moves it to the given collection.
 #[ic_cdk::update]
-fn admin_create_follow_relationship(follower: Principal, following: Principal) -> String {
-    match require_admin() {
-        Ok(_) => {
-            // Check if both users exist
-            if !user_exists(&follower) {
-                return "Follower user not found".to_string();
-            }
-            if !user_exists(&following) {
-                return "Following user not found".to_string();
-            }
+fn bookmark_post(post_id: u64, collection: Option<String>) -> Result<(), ApiError> {
+    let caller_id = caller();
 
-            // Can't follow yourself
-            if follower == following {
-                return "User cannot follow themselves".to_string();
-            }
+    // Check if user exists
+    require_registered(&caller_id)?;
 
-            let mut follows = FOLLOWS.lock().unwrap();
-            let user_following = follows.entry(follower).or_insert_with(HashSet::new);
+    // Check if post exists and is not deleted
+    load_active_post(post_id)?;
 
-            if user_following.contains(&following) {
-                return "Follow relationship already exists".to_string();
-            }
+    let collection = collection.as_deref().map(validate_collection_name).transpose()?;
+    let existing = BOOKMARKS.with(|bookmarks| bookmarks.borrow().get(&(PrincipalKey(caller_id), post_id)));
+    if existing.as_ref().is_some_and(|bookmark| bookmark.collection == collection) {
+        return Err(ApiError::AlreadyExists { resource: "Bookmark".to_string() });
+    }
+    if let Some(name) = &collection {
+        ensure_collection(caller_id, name)?;
+    }
 
-            user_following.insert(following);
-            format!("Follow relationship created: {} now follows {}", follower, following)
-        }
-        Err(e) => e,
+    let bookmark = Bookmark {
+        post_id,
+        collection,
+        created_at: existing.map_or_else(current_time, |bookmark| bookmark.created_at),
+    };
+    save_bookmark(caller_id, bookmark);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_bookmark(post_id: u64) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    // Check if user exists
+    require_registered(&caller_id)?;
+
+    if !delete_bookmark(caller_id, post_id) {
+        return Err(ApiError::NotFound { resource: "Bookmark".to_string() });
     }
+    Ok(())
 }
 
+// The caller's bookmarked posts, most recently bookmarked first. Without a collection
+// all bookmarks are listed. Deleted posts are skipped.
 #[ic_cdk::query]
-fn admin_get_stats() -> Result<AdminStats, String> {
-    match require_admin() {
-        Ok(_) => {
-            let users = USERS.lock().unwrap();
-            let current_timestamp = current_time();
-            let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000); // 24 hours in nanoseconds
-
-            let total_users = users.len() as u64;
-            let total_admins = users.values().filter(|user| user.is_admin).count() as u64;
-            let recent_registrations = users.values()
-                .filter(|user| user.created_at > twenty_four_hours_ago)
-                .count() as u64;
-
-            Ok(AdminStats {
-                total_users,
-                total_admins,
-                recent_registrations,
+fn get_bookmarks(collection: Option<String>, cursor: Option<String>, limit: u64) -> Result<Page<Post>, ApiError> {
+    let caller_id = caller();
+    let before: Option<(u64, u64)> = decode_cursor(cursor)?;
+
+    let load = |created_at: u64, post_id: u64| load_post(post_id).filter(|post| !post.is_deleted).map(|post| (created_at, post));
+    let key = |(created_at, post): &(u64, Post)| (*created_at, post.post_id);
+    let page = match collection {
+        Some(name) => {
+            let collection = collection_key(caller_id, name.trim());
+            if !BOOKMARK_COLLECTIONS.with(|collections| collections.borrow().contains_key(&collection)) {
+                return Err(ApiError::NotFound { resource: "Collection".to_string() });
+            }
+            let end = match before {
+                Some((created_at, post_id)) => std::ops::Bound::Excluded((collection.clone(), created_at, post_id)),
+                None => std::ops::Bound::Included((collection.clone(), u64::MAX, u64::MAX)),
+            };
+            let range = (std::ops::Bound::Included((collection, 0, 0)), end);
+            COLLECTION_BOOKMARKS.with(|bookmarks| {
+                let load = |((_, created_at, post_id), ()): ((CollectionKey, u64, u64), ())| load(created_at, post_id);
+                scan_page(&bookmarks.borrow(), range, PageOrder::NewestFirst, limit, load, key)
             })
         }
-        Err(e) => Err(e),
-    }
+        None => {
+            let user_key = PrincipalKey(caller_id);
+            let end = match before {
+                Some((created_at, post_id)) => std::ops::Bound::Excluded((user_key, created_at, post_id)),
+                None => std::ops::Bound::Included((user_key, u64::MAX, u64::MAX)),
+            };
+            let range = (std::ops::Bound::Included((user_key, 0, 0)), end);
+            USER_BOOKMARKS.with(|bookmarks| {
+                let load = |((_, created_at, post_id), ()): ((PrincipalKey, u64, u64), ())| load(created_at, post_id);
+                scan_page(&bookmarks.borrow(), range, PageOrder::NewestFirst, limit, load, key)
+            })
+        }
+    };
+
+    Ok(Page {
+        items: page.items.into_iter().map(|(_, post)| post).collect(),
+        next_cursor: page.next_cursor,
+    })
 }
 
 #[ic_cdk::query]
-fn admin_get_post_stats() -> Result<PostStats, String> {
-    match require_admin() {
-        Ok(_) => {
-            let posts = POSTS.lock().unwrap();
-            let current_timestamp = current_time();
-            let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000);
-
-            let total_posts = posts.values().filter(|post| !post.is_deleted).count() as u64;
-            let recent_posts = posts.values()
-                .filter(|post| !post.is_deleted && post.created_at > twenty_four_hours_ago)
-                .count() as u64;
-            let total_likes = posts.values()
-                .filter(|post| !post.is_deleted)
-                .map(|post| post.likes)
-                .sum::<u64>();
-
-            Ok(PostStats {
-                total_posts,
-                recent_posts,
-                total_likes,
-            })
-        }
-        Err(e) => Err(e),
+fn is_bookmarked(post_id: u64) -> bool {
+    BOOKMARKS.with(|bookmarks| bookmarks.borrow().contains_key(&(PrincipalKey(caller()), post_id)))
+}
+
+#[ic_cdk::query]
+fn get_bookmark_collections() -> Vec<BookmarkCollection> {
+    let caller_id = caller();
+    collections_of(caller_id).into_iter()
+        .map(|(name, created_at)| {
+            let range = collection_range(&collection_key(caller_id, &name));
+            let bookmark_count = COLLECTION_BOOKMARKS.with(|bookmarks| bookmarks.borrow().range(range).count() as u64);
+            BookmarkCollection { name, created_at, bookmark_count }
+        })
+        .collect()
+}
+
+#[ic_cdk::update]
+fn create_bookmark_collection(name: String) -> Result<(), ApiError> {
+    let caller_id = caller();
+
+    // Check if user exists
+    require_registered(&caller_id)?;
+
+    let name = validate_collection_name(&name)?;
+    if collection_exists(caller_id, &name) {
+        return Err(ApiError::AlreadyExists { resource: "Collection".to_string() });
     }
+    ensure_collection(caller_id, &name)
 }
 
+// Deletes a collection. Its bookmarks are kept outside of any collection.
 #[ic_cdk::update]
-fn admin_delete_post(post_id: u64) -> String {
-    match require_admin() {
-        Ok(_) => {
-            let mut posts = POSTS.lock().unwrap();
-            if let Some(post) = posts.get_mut(&post_id) {
-                if post.is_deleted {
-                    return "Post already deleted".to_string();
-                }
+fn delete_bookmark_collection(name: String) -> Result<(), ApiError> {
+    let caller_id = caller();
 
-                post.is_deleted = true;
-                format!("Post {} deleted successfully by admin", post_id)
-            } else {
-                "Post not found".to_string()
-            }
+    // Check if user exists
+    require_registered(&caller_id)?;
+
+    let collection = collection_key(caller_id, name.trim());
+    BOOKMARK_COLLECTIONS.with(|collections| collections.borrow_mut().remove(&collection))
+        .ok_or(ApiError::NotFound { resource: "Collection".to_string() })?;
+
+    let filed: Vec<u64> = COLLECTION_BOOKMARKS.with(|bookmarks| {
+        bookmarks.borrow().range(collection_range(&collection)).map(|((_, _, post_id), ())| post_id).collect()
+    });
+    for post_id in filed {
+        if let Some(mut bookmark) = BOOKMARKS.with(|bookmarks| bookmarks.borrow().get(&(PrincipalKey(caller_id), post_id))) {
+            bookmark.collection = None;
+            save_bookmark(caller_id, bookmark);
         }
-        Err(e) => e,
     }
+
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn admin_get_all_posts() -> Result<Vec<Post>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let posts = POSTS.lock().unwrap();
-            let mut post_list: Vec<Post> = posts.values().cloned().collect();
+// ============ REACTIONS ============
+
+const LIKE_REACTION: &str = "like";
 
-            // Sort by creation date (most recent first)
-            post_list.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+// Available until an admin changes the set
+const DEFAULT_REACTION_KINDS: [&str; 6] = ["like", "love", "laugh", "wow", "sad", "angry"];
 
-            Ok(post_list)
+const MAX_REACTION_KINDS: u64 = 50;
+const MAX_REACTION_KIND_LEN: usize = 32;
+
+fn seed_reaction_kinds() {
+    REACTION_KINDS.with(|kinds| {
+        let mut kinds = kinds.borrow_mut();
+        if kinds.is_empty() {
+            for kind in DEFAULT_REACTION_KINDS {
+                kinds.insert(kind.to_string(), ());
+            }
         }
-        Err(e) => Err(e),
-    }
+    });
 }
 
-#[ic_cdk::query]
-fn admin_get_post_ids() -> Result<Vec<u64>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let posts = POSTS.lock().unwrap();
-            let post_ids: Vec<u64> = posts.keys().cloned().collect();
-            Ok(post_ids)
+fn reaction_key(target: ReactionTarget, kind: &str, user: PrincipalKey) -> ReactionKey {
+    ReactionKey { target, kind: kind.to_string(), user }
+}
+
+fn user_reaction(user_id: Principal, target: ReactionTarget) -> Option<String> {
+    USER_REACTIONS.with(|reactions| reactions.borrow().get(&(PrincipalKey(user_id), target)))
+}
+
+// Helper function to list the items a user has reacted to
+fn user_reaction_targets(user_id: Principal) -> Vec<ReactionTarget> {
+    let key = PrincipalKey(user_id);
+    USER_REACTIONS.with(|reactions| {
+        reactions.borrow().range((key, ReactionTarget::Post(0))..=(key, ReactionTarget::Comment(u64::MAX)))
+            .map(|((_, target), _)| target)
+            .collect()
+    })
+}
+
+// Helper function to list the reaction index entries of an item, grouped by kind
+fn reaction_keys(target: ReactionTarget) -> Vec<ReactionKey> {
+    REACTIONS.with(|reactions| {
+        reactions.borrow().range(reaction_key(target, "", PrincipalKey::min())..)
+            .take_while(|(key, _)| key.target == target)
+            .map(|(key, _)| key)
+            .collect()
+    })
+}
+
+// Counts an item's reactions per kind from the reaction index
+fn count_reactions(target: ReactionTarget) -> Vec<ReactionCount> {
+    let mut counts: Vec<ReactionCount> = Vec::new();
+    for key in reaction_keys(target) {
+        match counts.last_mut() {
+            Some(count) if count.kind == key.kind => count.count += 1,
+            _ => counts.push(ReactionCount { kind: key.kind, count: 1 }),
         }
-        Err(e) => Err(e),
     }
+    counts
 }
 
-#[ic_cdk::update]
-fn admin_clear_all_posts() -> Result<String, String> {
-    match require_admin() {
-        Ok(_) => {
-            let mut posts = POSTS.lock().unwrap();
-            let count = posts.len();
-            posts.clear();
-            Ok(format!("Cleared {} posts successfully", count))
+// Adds or removes one reaction of a kind, keeping the counts sorted by kind
+fn adjust_reaction_count(counts: &mut Vec<ReactionCount>, kind: &str, added: bool) {
+    match counts.binary_search_by(|count| count.kind.as_str().cmp(kind)) {
+        Ok(index) if added => counts[index].count += 1,
+        Ok(index) => {
+            counts[index].count = counts[index].count.saturating_sub(1);
+            if counts[index].count == 0 {
+                counts.remove(index);
+            }
         }
-        Err(e) => Err(e),
+        Err(index) if added => counts.insert(index, ReactionCount { kind: kind.to_string(), count: 1 }),
+        Err(_) => {}
     }
 }
 
-#[ic_cdk::update]
-fn admin_clear_profile_pics() -> Result<String, String> {
-    match require_admin() {
-        Ok(_) => {
-            let mut users = USERS.lock().unwrap();
-            let mut count = 0;
-            for user in users.values_mut() {
-                if user.profile_pic.is_some() {
-                    user.profile_pic = None;
-                    count += 1;
-                }
+// Records a reaction in USER_REACTIONS, the reaction index and, for likes, the like sets.
+// Counters are left to the caller.
+fn store_reaction(user_id: Principal, target: ReactionTarget, kind: &str) {
+    USER_REACTIONS.with(|reactions| reactions.borrow_mut().insert((PrincipalKey(user_id), target), kind.to_string()));
+    REACTIONS.with(|reactions| reactions.borrow_mut().insert(reaction_key(target, kind, PrincipalKey(user_id)), ()));
+    if kind == LIKE_REACTION {
+        match target {
+            ReactionTarget::Post(post_id) => insert_user_item(&USER_LIKES, &POST_LIKERS, user_id, post_id),
+            ReactionTarget::Comment(comment_id) => insert_user_item(&USER_COMMENT_LIKES, &COMMENT_LIKERS, user_id, comment_id),
+        };
+    }
+}
+
+// Undoes store_reaction. The user's reaction is only dropped if it is of this kind, so
+// integrity repairs can remove a stale index entry without touching the current reaction.
+fn unstore_reaction(user_id: Principal, target: ReactionTarget, kind: &str) {
+    if user_reaction(user_id, target).as_deref() == Some(kind) {
+        USER_REACTIONS.with(|reactions| reactions.borrow_mut().remove(&(PrincipalKey(user_id), target)));
+    }
+    REACTIONS.with(|reactions| reactions.borrow_mut().remove(&reaction_key(target, kind, PrincipalKey(user_id))));
+    if kind == LIKE_REACTION {
+        match target {
+            ReactionTarget::Post(post_id) => remove_user_item(&USER_LIKES, &POST_LIKERS, user_id, post_id),
+            ReactionTarget::Comment(comment_id) => remove_user_item(&USER_COMMENT_LIKES, &COMMENT_LIKERS, user_id, comment_id),
+        };
+    }
+}
+
+// Updates the reaction counts of an item, and its like count for likes
+fn update_reaction_counts(target: ReactionTarget, kind: &str, added: bool) {
+    let adjust_likes = |likes: u64| match (kind == LIKE_REACTION, added) {
+        (false, _) => likes,
+        (true, true) => likes + 1,
+        (true, false) => likes.saturating_sub(1),
+    };
+    match target {
+        ReactionTarget::Post(post_id) => {
+            if let Some(mut post) = load_post(post_id) {
+                adjust_reaction_count(&mut post.reactions, kind, added);
+                post.likes = adjust_likes(post.likes);
+                save_post(post);
+            }
+        }
+        ReactionTarget::Comment(comment_id) => {
+            if let Some(mut comment) = load_comment(comment_id) {
+                adjust_reaction_count(&mut comment.reactions, kind, added);
+                comment.likes = adjust_likes(comment.likes);
+                save_comment(comment);
             }
-            Ok(format!("Cleared {} profile pictures successfully", count))
         }
-        Err(e) => Err(e),
     }
 }
 
-#[ic_cdk::query]
-fn admin_get_all_users_detailed() -> Result<Vec<User>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let users = USERS.lock().unwrap();
-            Ok(users.values().cloned().collect())
+// Removes a user's reaction to an item, if any, and returns its kind
+fn clear_reaction(user_id: Principal, target: ReactionTarget) -> Option<String> {
+    let kind = user_reaction(user_id, target)?;
+    unstore_reaction(user_id, target, &kind);
+    update_reaction_counts(target, &kind, false);
+    Some(kind)
+}
+
+// Drops every reaction to an item without touching its counters, for items being deleted
+fn remove_all_reactions(target: ReactionTarget) {
+    for key in reaction_keys(target) {
+        unstore_reaction(key.user.0, target, &key.kind);
+    }
+}
+
+// Likes are always accepted, since admin_remove_reaction_kind refuses to remove them
+fn check_reaction_kind(kind: &str) -> Result<(), ApiError> {
+    if kind != LIKE_REACTION && !REACTION_KINDS.with(|kinds| kinds.borrow().contains_key(&kind.to_string())) {
+        return Err(ApiError::Validation {
+            field: "kind".to_string(),
+            reason: format!("Unknown reaction kind: {}", kind),
+        });
+    }
+    Ok(())
+}
+
+// Reacts to an existing, non-deleted item, replacing the user's previous reaction.
+// `resource` names the reaction in the AlreadyExists error. Only likes are notified.
+fn add_reaction(user_id: Principal, target: ReactionTarget, kind: &str, resource: &str) -> Result<(), ApiError> {
+    require_registered(&user_id)?;
+    check_reaction_kind(kind)?;
+
+    let (author_id, post_id, comment_id, notification_kind) = match target {
+        ReactionTarget::Post(post_id) => (load_active_post(post_id)?.author_id, post_id, None, NotificationKind::Like),
+        ReactionTarget::Comment(comment_id) => {
+            let comment = load_active_comment(comment_id)?;
+            (comment.author_id, comment.post_id, Some(comment_id), NotificationKind::CommentLike)
         }
-        Err(e) => Err(e),
+    };
+
+    if user_reaction(user_id, target).as_deref() == Some(kind) {
+        return Err(ApiError::AlreadyExists { resource: resource.to_string() });
+    }
+    clear_reaction(user_id, target);
+    store_reaction(user_id, target, kind);
+    update_reaction_counts(target, kind, true);
+
+    if kind == LIKE_REACTION {
+        notify(author_id, user_id, notification_kind, Some(post_id), comment_id);
+    }
+    Ok(())
+}
+
+// Withdraws the user's reaction to an item. With `only_kind`, only a reaction of that kind.
+fn withdraw_reaction(user_id: Principal, target: ReactionTarget, only_kind: Option<&str>, resource: &str) -> Result<(), ApiError> {
+    require_registered(&user_id)?;
+
+    let not_found = || ApiError::NotFound { resource: resource.to_string() };
+    let kind = user_reaction(user_id, target).ok_or_else(not_found)?;
+    if only_kind.is_some_and(|only_kind| only_kind != kind) {
+        return Err(not_found());
     }
+
+    clear_reaction(user_id, target);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn react(target: ReactionTarget, kind: String) -> Result<(), ApiError> {
+    add_reaction(caller(), target, &kind, "Reaction")
+}
+
+#[ic_cdk::update]
+fn unreact(target: ReactionTarget) -> Result<(), ApiError> {
+    withdraw_reaction(caller(), target, None, "Reaction")
 }
 
+// The users who reacted to an item with a given kind
 #[ic_cdk::query]
-fn admin_search_users(query: String) -> Result<Vec<User>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let users = USERS.lock().unwrap();
-            let query_lower = query.to_lowercase();
-            let filtered_users: Vec<User> = users.values()
-                .filter(|user| {
-                    user.username.to_lowercase().contains(&query_lower) ||
-                    user.full_name.as_ref().map_or(false, |name| name.to_lowercase().contains(&query_lower)) ||
-                    user.email.as_ref().map_or(false, |email| email.to_lowercase().contains(&query_lower))
-                })
-                .cloned()
-                .collect();
-            Ok(filtered_users)
-        }
-        Err(e) => Err(e),
+fn get_reactions(target: ReactionTarget, kind: String, cursor: Option<String>, limit: u64) -> Result<Page<Principal>, ApiError> {
+    let after: Option<PrincipalKey> = decode_cursor(cursor)?;
+    let start = match after {
+        Some(user) => std::ops::Bound::Excluded(reaction_key(target, &kind, user)),
+        None => std::ops::Bound::Included(reaction_key(target, &kind, PrincipalKey::min())),
+    };
+    let range = (start, std::ops::Bound::Included(reaction_key(target, &kind, PrincipalKey::max())));
+    Ok(REACTIONS.with(|reactions| {
+        let load = |(key, ()): (ReactionKey, ())| Some(key.user.0);
+        scan_page(&reactions.borrow(), range, PageOrder::OldestFirst, limit, load, |user_id| PrincipalKey(*user_id))
+    }))
+}
+
+// The caller's reaction to an item, if any
+#[ic_cdk::query]
+fn get_my_reaction(target: ReactionTarget) -> Option<String> {
+    user_reaction(caller(), target)
+}
+
+#[ic_cdk::query]
+fn get_reaction_kinds() -> Vec<String> {
+    REACTION_KINDS.with(|kinds| kinds.borrow().iter().map(|(kind, _)| kind).collect())
+}
+
+// Adds a reaction kind, e.g. a custom emoji
+#[ic_cdk::update]
+fn admin_add_reaction_kind(kind: String) -> Result<(), ApiError> {
+    require_admin()?;
+
+    if kind.is_empty() || kind.len() > MAX_REACTION_KIND_LEN || kind.chars().any(char::is_whitespace) {
+        return Err(ApiError::Validation {
+            field: "kind".to_string(),
+            reason: format!("Reaction kinds must be 1 to {} bytes without whitespace", MAX_REACTION_KIND_LEN),
+        });
     }
+    REACTION_KINDS.with(|kinds| {
+        let mut kinds = kinds.borrow_mut();
+        if kinds.contains_key(&kind) {
+            return Err(ApiError::AlreadyExists { resource: "Reaction kind".to_string() });
+        }
+        if kinds.len() >= MAX_REACTION_KINDS {
+            return Err(ApiError::Validation {
+                field: "kind".to_string(),
+                reason: format!("At most {} reaction kinds are allowed", MAX_REACTION_KINDS),
+            });
+        }
+        kinds.insert(kind, ());
+        Ok(())
+    })
 }
 
-// Admin function to manually create or update a user with specific principal
+// Stops new reactions of a kind. Existing reactions of that kind are kept.
 #[ic_cdk::update]
-fn admin_create_user_with_principal(
-    target_principal: Principal,
-    username: String,
-    full_name: Option<String>,
-    email: Option<String>,
-    bio: Option<String>,
-    profile_pic: Option<String>,
-    location: Option<String>,
-    website: Option<String>,
-) -> String {
-    match require_admin() {
-        Ok(_) => {
-            let current_timestamp = current_time();
-
-            let user = User {
-                user_id: target_principal,
-                username,
-                full_name,
-                email,
-                bio,
-                profile_pic,
-                location,
-                website,
-                is_admin: is_admin(&target_principal),
-                created_at: current_timestamp,
-                last_active: current_timestamp,
-            };
+fn admin_remove_reaction_kind(kind: String) -> Result<(), ApiError> {
+    require_admin()?;
+
+    if kind == LIKE_REACTION {
+        return Err(ApiError::Validation {
+            field: "kind".to_string(),
+            reason: "The like reaction can't be removed".to_string(),
+        });
+    }
+    REACTION_KINDS.with(|kinds| kinds.borrow_mut().remove(&kind))
+        .ok_or(ApiError::NotFound { resource: "Reaction kind".to_string() })?;
+    Ok(())
+}
+
+// ============ UPGRADE FUNCTIONS ============
+
+// All state lives in stable structures and survives upgrades without a pre_upgrade hook.
+// The heap-based releases instead saved a candid snapshot to raw stable memory, which
+// is migrated into the stable structures the first time such a canister is upgraded.
+#[derive(CandidType, Deserialize)]
+struct LegacyState {
+    users: HashMap<Principal, UserV3>,
+    posts: HashMap<u64, PostV6>,
+    post_id_counter: u64,
+    user_likes: HashMap<Principal, HashSet<u64>>,
+    comments: HashMap<u64, CommentV6>,
+    comment_id_counter: u64,
+    user_comment_likes: HashMap<Principal, HashSet<u64>>,
+    follows: HashMap<Principal, HashSet<Principal>>,
+    user_shares: HashMap<Principal, HashSet<u64>>,
+    promoted_admins: HashSet<Principal>,
+}
+
+// Reads the snapshot written by the heap-based pre_upgrade hook, if there is one.
+// Must run before the memory manager is first used, as it claims the same stable memory.
+fn read_legacy_state() -> Option<LegacyState> {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return None;
+    }
+
+    // Snapshots start with the candid magic, the memory manager with its own header
+    let mut magic = [0u8; 4];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    if &magic != b"DIDL" {
+        return None;
+    }
+
+    let (state,): (LegacyState,) = ic_cdk::storage::stable_restore()
+        .expect("Failed to restore legacy state from stable memory");
+    Some(state)
+}
+
+fn restore_legacy_state(state: LegacyState) {
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        for (user_id, user) in state.users {
+            users.insert(PrincipalKey(user_id), user.into());
+        }
+    });
+    POSTS.with(|posts| {
+        let mut posts = posts.borrow_mut();
+        for (post_id, post) in state.posts {
+            posts.insert(post_id, PostV11::from(PostV10::from(post)).into());
+        }
+    });
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        for (comment_id, comment) in state.comments {
+            comments.insert(comment_id, CommentV10::from(CommentV9::from(comment)).into());
+        }
+    });
+    POST_ID_COUNTER.with(|counter| {
+        counter.borrow_mut().set(state.post_id_counter).expect("Failed to restore post ID counter")
+    });
+    COMMENT_ID_COUNTER.with(|counter| {
+        counter.borrow_mut().set(state.comment_id_counter).expect("Failed to restore comment ID counter")
+    });
+
+    let restore_user_items = |set: &RefCell<UserItemSet>, items: HashMap<Principal, HashSet<u64>>| {
+        let mut set = set.borrow_mut();
+        for (user_id, item_ids) in items {
+            for item_id in item_ids {
+                set.insert((PrincipalKey(user_id), item_id), ());
+            }
+        }
+    };
+    USER_LIKES.with(|user_likes| restore_user_items(user_likes, state.user_likes));
+    USER_COMMENT_LIKES.with(|user_comment_likes| restore_user_items(user_comment_likes, state.user_comment_likes));
+    USER_SHARES.with(|user_shares| restore_user_items(user_shares, state.user_shares));
+
+    // Snapshot follows use the pre-v4 layout, the v3 -> v4 migration picks them up from there
+    let mut follows = legacy_follows();
+    for (follower_id, following_ids) in state.follows {
+        for following_id in following_ids {
+            follows.insert((PrincipalKey(follower_id), PrincipalKey(following_id)), ());
+        }
+    }
+    PROMOTED_ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        for admin_id in state.promoted_admins {
+            admins.insert(PrincipalKey(admin_id), ());
+        }
+    });
+}
+
+fn stored_schema_version() -> u8 {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
 
-            let mut users = USERS.lock().unwrap();
-            users.insert(target_principal, user);
+fn set_stored_schema_version(schema_version: u8) {
+    STORED_SCHEMA_VERSION.with(|version| {
+        version.borrow_mut().set(schema_version).expect("Failed to update schema version")
+    });
+}
 
-            format!("User created/updated successfully for principal: {}", target_principal)
+// Re-encodes every record at the current schema version
+fn rewrite_all_records() {
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let all_users: Vec<(PrincipalKey, User)> = users.iter().collect();
+        for (user_key, user) in all_users {
+            users.insert(user_key, user);
         }
-        Err(e) => e,
-    }
+    });
+    POSTS.with(|posts| {
+        let mut posts = posts.borrow_mut();
+        let all_posts: Vec<(u64, Post)> = posts.iter().collect();
+        for (post_id, post) in all_posts {
+            posts.insert(post_id, post);
+        }
+    });
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        let all_comments: Vec<(u64, Comment)> = comments.iter().collect();
+        for (comment_id, comment) in all_comments {
+            comments.insert(comment_id, comment);
+        }
+    });
 }
 
-#[ic_cdk::query]
-fn admin_get_recent_users(limit: u64) -> Result<Vec<User>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let users = USERS.lock().unwrap();
-            let mut user_list: Vec<User> = users.values().cloned().collect();
+// Follow edges as stored up to schema version 3
+fn legacy_follows() -> StableBTreeMap<(PrincipalKey, PrincipalKey), (), Memory> {
+    StableBTreeMap::init(get_memory(LEGACY_FOLLOWS_MEMORY_ID))
+}
 
-            // Sort by creation date (most recent first)
-            user_list.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+// Moves the old follow edges into timestamped Follow records and the followers index.
+// When the edges were created was never recorded, so the migration time stands in for it.
+fn migrate_follows() {
+    let mut legacy = legacy_follows();
+    let edges: Vec<(PrincipalKey, PrincipalKey)> = legacy.iter().map(|(edge, _)| edge).collect();
+    let migrated_at = current_time();
+
+    for (follower, following) in edges {
+        let follow = Follow {
+            follower_id: follower.0,
+            following_id: following.0,
+            created_at: migrated_at,
+        };
+        FOLLOWS.with(|follows| follows.borrow_mut().insert((follower, following), follow));
+        FOLLOWERS.with(|followers| followers.borrow_mut().insert((following, follower), ()));
+        legacy.remove(&(follower, following));
+    }
 
-            // Take only the requested number
-            user_list.truncate(limit as usize);
+    recompute_follow_counts();
+}
 
-            Ok(user_list)
-        }
-        Err(e) => Err(e),
+// Recomputes every user's follower and following counts from the follow records
+fn recompute_follow_counts() {
+    for mut user in collect_users(|_| true) {
+        user.followers_count = followers_of(user.user_id).len() as u64;
+        user.following_count = following_of(user.user_id).len() as u64;
+        save_user(user);
     }
 }
 
-#[ic_cdk::query]
-fn admin_get_all_users() -> Result<Vec<User>, String> {
-    match require_admin() {
-        Ok(_) => {
-            let users = USERS.lock().unwrap();
-            let user_list: Vec<User> = users.values().cloned().collect();
-            Ok(user_list)
+// Rebuilds the reverse sets of likes, comment likes and shares from the forward sets
+fn rebuild_reverse_item_sets() {
+    let rebuild = |set: &'static LocalKey<RefCell<UserItemSet>>, reverse: &'static LocalKey<RefCell<ItemUserSet>>| {
+        let entries: Vec<(PrincipalKey, u64)> = set.with(|set| set.borrow().iter().map(|(key, _)| key).collect());
+        reverse.with(|reverse| {
+            let mut reverse = reverse.borrow_mut();
+            for (user, item_id) in entries {
+                reverse.insert((item_id, user), ());
+            }
+        });
+    };
+    rebuild(&USER_LIKES, &POST_LIKERS);
+    rebuild(&USER_COMMENT_LIKES, &COMMENT_LIKERS);
+    rebuild(&USER_SHARES, &POST_SHARERS);
+}
+
+// Turns every like into a "like" reaction and fills in the reaction counts
+fn migrate_reactions() {
+    seed_reaction_kinds();
+
+    let migrate_likes = |set: &'static LocalKey<RefCell<UserItemSet>>, target: fn(u64) -> ReactionTarget| {
+        let likes: Vec<(PrincipalKey, u64)> = set.with(|set| set.borrow().iter().map(|(key, _)| key).collect());
+        for (user, item_id) in likes {
+            store_reaction(user.0, target(item_id), LIKE_REACTION);
         }
-        Err(e) => Err(e),
+    };
+    migrate_likes(&USER_LIKES, ReactionTarget::Post);
+    migrate_likes(&USER_COMMENT_LIKES, ReactionTarget::Comment);
+
+    let all_posts: Vec<Post> = POSTS.with(|posts| posts.borrow().iter().map(|(_, post)| post).collect());
+    for mut post in all_posts {
+        post.reactions = count_reactions(ReactionTarget::Post(post.post_id));
+        save_post(post);
+    }
+    let all_comments: Vec<Comment> = COMMENTS.with(|comments| comments.borrow().iter().map(|(_, comment)| comment).collect());
+    for mut comment in all_comments {
+        comment.reactions = count_reactions(ReactionTarget::Comment(comment.comment_id));
+        save_comment(comment);
     }
 }
 
-// ============ HELPER FUNCTIONS ============
-
-fn extract_mentions(content: &str) -> Vec<Principal> {
-    let mut mentions = Vec::new();
-    let words: Vec<&str> = content.split_whitespace().collect();
+// Dates the existing shares by their post's creation time and counts each user's share once
+fn migrate_reposts() {
+    let shares: Vec<(PrincipalKey, u64)> = USER_SHARES.with(|shares| shares.borrow().iter().map(|(key, _)| key).collect());
+    for (user, post_id) in shares {
+        let reposted_at = load_post(post_id).map_or(0, |post| post.created_at);
+        index_repost(user.0, post_id, reposted_at);
+    }
 
-    for word in words {
-        if word.starts_with('@') && word.len() > 1 {
-            let username = &word[1..];
-            // Find user by username and get their principal
-            let users = USERS.lock().unwrap();
-            for user in users.values() {
-                if user.username == username {
-                    mentions.push(user.user_id);
-                    break;
-                }
-            }
-        }
+    let all_posts: Vec<Post> = POSTS.with(|posts| posts.borrow().iter().map(|(_, post)| post).collect());
+    for mut post in all_posts {
+        post.shares_count = item_user_ids(&POST_SHARERS, post.post_id).len() as u64;
+        save_post(post);
     }
+}
 
-    mentions
+fn rewrite_settings() {
+    SETTINGS.with(|settings| {
+        let mut settings = settings.borrow_mut();
+        let all_settings: Vec<(PrincipalKey, UserSettings)> = settings.iter().collect();
+        for (user_key, user_settings) in all_settings {
+            settings.insert(user_key, user_settings);
+        }
+    });
 }
 
-fn next_comment_id() -> u64 {
-    let mut counter = COMMENT_ID_COUNTER.lock().unwrap();
-    *counter += 1;
-    *counter
+fn rewrite_conversations() {
+    CONVERSATIONS.with(|conversations| {
+        let mut conversations = conversations.borrow_mut();
+        let all_conversations: Vec<(u64, Conversation)> = conversations.iter().collect();
+        for (conversation_id, conversation) in all_conversations {
+            conversations.insert(conversation_id, conversation);
+        }
+    });
 }
 
-// ============ SEARCH FUNCTIONS ============
+// Stamps the posts and comments that were deleted before deletion times were recorded
+// and fills the trash indexes. Their restore window and retention start at the migration.
+fn migrate_trash() {
+    let migrated_at = current_time();
 
-#[ic_cdk::query]
-fn search_users(query: String) -> Vec<User> {
-    let users = USERS.lock().unwrap();
-    let query_lower = query.to_lowercase();
+    let all_posts: Vec<Post> = POSTS.with(|posts| posts.borrow().iter().map(|(_, post)| post).collect());
+    for mut post in all_posts {
+        if post.is_deleted {
+            let deleted_at = *post.deleted_at.get_or_insert(migrated_at);
+            TRASHED_POSTS.with(|trash| trash.borrow_mut().insert((deleted_at, post.post_id), ()));
+        }
+        save_post(post);
+    }
 
-    users.values()
-        .filter(|user| {
-            user.username.to_lowercase().contains(&query_lower) ||
-            user.full_name.as_ref().map_or(false, |name| name.to_lowercase().contains(&query_lower)) ||
-            user.bio.as_ref().map_or(false, |bio| bio.to_lowercase().contains(&query_lower))
-        })
-        .cloned()
-        .collect()
+    let all_comments: Vec<Comment> = COMMENTS.with(|comments| comments.borrow().iter().map(|(_, comment)| comment).collect());
+    for mut comment in all_comments {
+        if comment.is_deleted {
+            let deleted_at = *comment.deleted_at.get_or_insert(migrated_at);
+            TRASHED_COMMENTS.with(|trash| trash.borrow_mut().insert((deleted_at, comment.comment_id), ()));
+        }
+        save_comment(comment);
+    }
 }
 
-#[ic_cdk::query]
-fn search_posts(query: String) -> Vec<Post> {
-    let posts = POSTS.lock().unwrap();
-    let query_lower = query.to_lowercase();
-
-    let mut filtered_posts: Vec<Post> = posts.values()
-        .filter(|post| {
-            !post.is_deleted && (
-                post.content.to_lowercase().contains(&query_lower) ||
-                post.hashtags.iter().any(|tag| tag.to_lowercase().contains(&query_lower))
-            )
-        })
-        .cloned()
-        .collect();
+// Runs every migration step between the stored schema version and SCHEMA_VERSION
+fn migrate_schema() {
+    let stored_version = stored_schema_version();
+    if stored_version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stored schema version {} is newer than this release ({}), refusing to downgrade",
+            stored_version, SCHEMA_VERSION
+        ));
+    }
 
-    // Sort by creation date (most recent first)
-    filtered_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    for version in stored_version..SCHEMA_VERSION {
+        MIGRATIONS[version as usize]();
+        set_stored_schema_version(version + 1);
+    }
+}
 
-    filtered_posts
+// How often the trash purge and notification prune jobs run
+#[cfg(not(test))]
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+#[cfg(not(test))]
+const NOTIFICATION_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Timers don't survive upgrades, so this runs from both init and post_upgrade
+#[cfg(not(test))]
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(TRASH_PURGE_INTERVAL, purge_trash);
+    ic_cdk_timers::set_timer_interval(NOTIFICATION_PRUNE_INTERVAL, prune_notifications);
 }
 
-#[ic_cdk::query]
-fn get_posts_by_hashtag(hashtag: String) -> Vec<Post> {
-    let posts = POSTS.lock().unwrap();
-    let hashtag_lower = hashtag.to_lowercase();
+// Timers need the system API, native unit tests run the jobs themselves
+#[cfg(test)]
+fn start_timers() {}
 
-    let mut filtered_posts: Vec<Post> = posts.values()
-        .filter(|post| {
-            !post.is_deleted &&
-            post.hashtags.iter().any(|tag| tag.to_lowercase() == hashtag_lower)
-        })
-        .cloned()
-        .collect();
+#[ic_cdk::init]
+fn init() {
+    // A fresh canister starts out at the latest schema, nothing to migrate
+    set_stored_schema_version(SCHEMA_VERSION);
+    seed_reaction_kinds();
+    start_timers();
+}
 
-    // Sort by creation date (most recent first)
-    filtered_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    if let Some(state) = read_legacy_state() {
+        restore_legacy_state(state);
+        // The snapshot only holds records, later steps still have to build the derived structures
+        set_stored_schema_version(LEGACY_SNAPSHOT_VERSION);
+    }
 
-    filtered_posts
+    migrate_schema();
+    start_timers();
 }
 
-#[ic_cdk::query]
-fn get_trending_posts() -> Vec<Post> {
-    let posts = POSTS.lock().unwrap();
-    let current_timestamp = current_time();
-    let twenty_four_hours_ago = current_timestamp.saturating_sub(24 * 60 * 60 * 1_000_000_000);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
 
-    let mut recent_posts: Vec<Post> = posts.values()
-        .filter(|post| !post.is_deleted && post.created_at > twenty_four_hours_ago)
-        .cloned()
-        .collect();
+    thread_local! {
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static CLOCK: Cell<u64> = const { Cell::new(1_700_000_000_000_000_000) };
+    }
 
-    // Sort by engagement (likes + comments + shares)
-    recent_posts.sort_by(|a, b| {
-        let a_engagement = a.likes + a.comments_count + a.shares_count;
-        let b_engagement = b.likes + b.comments_count + b.shares_count;
-        b_engagement.cmp(&a_engagement)
-    });
+    pub(super) fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
 
-    // Take top 20 trending posts
-    recent_posts.truncate(20);
+    pub(super) fn current_time() -> u64 {
+        CLOCK.with(|clock| clock.get())
+    }
 
-    recent_posts
-}
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
 
-// ============ COMMENT FUNCTIONS ============
+    fn call_as(n: u8) {
+        CALLER.with(|caller| caller.set(principal(n)));
+    }
 
-#[ic_cdk::update]
-fn create_comment(post_id: u64, content: String) -> Result<u64, String> {
-    let author_id = caller();
+    fn advance_clock(ns: u64) {
+        CLOCK.with(|clock| clock.set(clock.get() + ns));
+    }
 
-    // Check if user exists
-    if !user_exists(&author_id) {
-        return Err("User must be registered to create comments".to_string());
+    fn register(n: u8, username: &str) {
+        call_as(n);
+        assert_eq!(
+            create_user(username.to_string(), None, None, None, None, None, None),
+            "User created successfully"
+        );
     }
 
-    // Validate content
-    if content.trim().is_empty() {
-        return Err("Comment content cannot be empty".to_string());
+    #[test]
+    fn stable_maps_back_likes_follows_and_counters() {
+        register(1, "alice");
+        register(2, "bob");
+        let post_id = create_post("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(like_post_v2(post_id), Ok(()));
+        assert_eq!(like_post_v2(post_id), Err(ApiError::AlreadyExists { resource: "Like".to_string() }));
+        assert_eq!(follow_user_v2(principal(1)), Ok(()));
+        assert_eq!(follow_user_v2(principal(1)), Err(ApiError::AlreadyExists { resource: "Follow".to_string() }));
+
+        assert_eq!(get_post(post_id).unwrap().likes, 1);
+        assert_eq!(get_user_liked_posts(), vec![post_id]);
+        assert_eq!(follower_ids(&get_followers(principal(1))), vec![principal(2)]);
+        assert_eq!(following_ids(&get_following(principal(2))), vec![principal(1)]);
+        assert_eq!(create_post("next".to_string(), Vec::new(), Vec::new()).unwrap(), post_id + 1);
     }
 
-    if content.len() > 500 {
-        return Err("Comment content too long (max 500 characters)".to_string());
+    #[test]
+    fn legacy_heap_snapshot_is_migrated_into_stable_maps() {
+        let post = PostV6 {
+            post_id: 7,
+            author_id: principal(1),
+            content: "from the heap".to_string(),
+            created_at: 1,
+            updated_at: None,
+            likes: 1,
+            comments_count: 0,
+            shares_count: 0,
+            hashtags: Vec::new(),
+            mentions: Vec::new(),
+            media_urls: Vec::new(),
+            is_deleted: false,
+        };
+        let user = UserV3 {
+            user_id: principal(1),
+            username: "alice".to_string(),
+            full_name: None,
+            email: None,
+            bio: None,
+            profile_pic: None,
+            location: None,
+            website: None,
+            is_admin: false,
+            created_at: 1,
+            last_active: 1,
+        };
+        let state = LegacyState {
+            users: HashMap::from([(principal(1), user)]),
+            posts: HashMap::from([(7, post)]),
+            post_id_counter: 7,
+            user_likes: HashMap::from([(principal(2), HashSet::from([7]))]),
+            comments: HashMap::new(),
+            comment_id_counter: 3,
+            user_comment_likes: HashMap::new(),
+            follows: HashMap::from([(principal(2), HashSet::from([principal(1)]))]),
+            user_shares: HashMap::new(),
+            promoted_admins: HashSet::from([principal(3)]),
+        };
+        // The snapshot comes back exactly as the heap-based pre_upgrade wrote it
+        let bytes = candid::encode_one(state).unwrap();
+        restore_legacy_state(candid::decode_one(&bytes).unwrap());
+        set_stored_schema_version(LEGACY_SNAPSHOT_VERSION);
+        migrate_schema();
+
+        assert_eq!(get_user(principal(1)).unwrap().username, "alice");
+        assert_eq!(get_post(7).unwrap().content, "from the heap");
+        assert_eq!(follower_ids(&get_followers(principal(1))), vec![principal(2)]);
+        assert_eq!(get_user(principal(1)).unwrap().followers_count, 1);
+        assert!(is_admin(&principal(3)));
+        call_as(2);
+        assert_eq!(get_user_liked_posts(), vec![7]);
+
+        // Counters continue where the heap release stopped
+        register(2, "bob");
+        assert_eq!(create_post("new".to_string(), Vec::new(), Vec::new()).unwrap(), 8);
+        assert_eq!(create_comment(7, "new".to_string()).unwrap(), 4);
     }
 
-    // Check if post exists and is not deleted
-    {
-        let posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get(&post_id) {
-            if post.is_deleted {
-                return Err("Cannot comment on deleted post".to_string());
-            }
-        } else {
-            return Err("Post not found".to_string());
-        }
+    fn follower_ids(follows: &[Follow]) -> Vec<Principal> {
+        follows.iter().map(|follow| follow.follower_id).collect()
     }
 
-    let comment_id = next_comment_id();
-    let current_timestamp = current_time();
+    fn following_ids(follows: &[Follow]) -> Vec<Principal> {
+        follows.iter().map(|follow| follow.following_id).collect()
+    }
 
-    let comment = Comment {
-        comment_id,
-        post_id,
-        author_id,
-        content: content.trim().to_string(),
-        created_at: current_timestamp,
-        updated_at: None,
-        likes: 0,
-        is_deleted: false,
-    };
+    fn admin_caller() -> Principal {
+        let admin = principal(200);
+        PROMOTED_ADMINS.with(|admins| admins.borrow_mut().insert(PrincipalKey(admin), ()));
+        CALLER.with(|caller| caller.set(admin));
+        admin
+    }
 
-    // Store the comment
-    {
-        let mut comments = COMMENTS.lock().unwrap();
-        comments.insert(comment_id, comment);
+    #[test]
+    fn unversioned_records_are_rewritten_by_the_migration() {
+        register(1, "alice");
+        let post_id = create_post("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        // Store the post the way the pre-versioning release did: bare candid
+        let post = get_post(post_id).unwrap();
+        let legacy_bytes = Encode!(&post).unwrap();
+        assert_eq!(Post::from_bytes(Cow::Borrowed(&legacy_bytes)).content, "hello");
+
+        set_stored_schema_version(0);
+        migrate_schema();
+        assert_eq!(stored_schema_version(), SCHEMA_VERSION);
+        assert_eq!(get_post(post_id).unwrap().to_bytes()[0], SCHEMA_VERSION);
+        assert_eq!(load_user(&principal(1)).unwrap().to_bytes()[0], SCHEMA_VERSION);
     }
 
-    // Increment comment count on the post
-    {
-        let mut posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get_mut(&post_id) {
-            post.comments_count += 1;
-        }
+    #[test]
+    fn schema_version_is_reported_to_admins_only() {
+        init();
+        register(1, "alice");
+        assert!(admin_get_schema_version().is_err());
+        admin_caller();
+        assert_eq!(admin_get_schema_version(), Ok(SCHEMA_VERSION));
     }
 
-    // Update user's last active timestamp
-    {
-        let mut users = USERS.lock().unwrap();
-        if let Some(user) = users.get_mut(&author_id) {
-            user.last_active = current_timestamp;
-        }
+    #[test]
+    #[should_panic(expected = "Stored record is empty")]
+    fn empty_stored_record_is_rejected() {
+        User::from_bytes(Cow::Borrowed(&[]));
     }
 
-    Ok(comment_id)
-}
+    #[test]
+    fn v2_endpoints_return_typed_errors() {
+        call_as(1);
+        assert_eq!(create_post_v2("hi".to_string(), Vec::new(), Vec::new()), Err(ApiError::NotRegistered));
+        register(1, "alice");
+        assert!(matches!(
+            create_post_v2("   ".to_string(), Vec::new(), Vec::new()),
+            Err(ApiError::Validation { .. })
+        ));
+        let post_id = create_post_v2("hi".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(like_post_v2(999), Err(ApiError::NotFound { resource: "Post".to_string() }));
+
+        register(2, "bob");
+        assert!(matches!(update_post_v2(post_id, "mine".to_string()), Err(ApiError::Unauthorized { .. })));
+        assert!(matches!(follow_user_v2(principal(2)), Err(ApiError::Validation { .. })));
+        assert!(matches!(admin_delete_user_v2(principal(1)), Err(ApiError::Unauthorized { .. })));
+
+        call_as(1);
+        assert_eq!(delete_post_v2(post_id), Ok(()));
+        assert_eq!(delete_post_v2(post_id), Err(ApiError::Deleted { resource: "Post".to_string() }));
+    }
 
-#[ic_cdk::query]
-fn get_post_comments(post_id: u64) -> Vec<Comment> {
-    let comments = COMMENTS.lock().unwrap();
-    let mut post_comments: Vec<Comment> = comments.values()
-        .filter(|comment| comment.post_id == post_id && !comment.is_deleted)
-        .cloned()
-        .collect();
+    #[test]
+    fn legacy_endpoints_render_the_typed_error() {
+        register(1, "alice");
+        let err = update_post_v2(42, "text".to_string()).unwrap_err();
+        assert_eq!(update_post(42, "text".to_string()), err.to_string());
+        assert_eq!(update_post(42, "text".to_string()), "Post not found");
+        assert_eq!(like_post(42), "Post not found");
+    }
 
-    // Sort by creation date (oldest first for comments)
-    post_comments.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    fn post_ids(page: &Page<Post>) -> Vec<u64> {
+        page.items.iter().map(|post| post.post_id).collect()
+    }
 
-    post_comments
-}
+    #[test]
+    fn post_pages_are_stable_while_new_posts_arrive() {
+        register(1, "alice");
+        for n in 1..=5 {
+            create_post_v2(format!("post {}", n), Vec::new(), Vec::new()).unwrap();
+            advance_clock(1);
+        }
 
-#[ic_cdk::update]
-fn update_comment(comment_id: u64, new_content: String) -> String {
-    let caller_id = caller();
+        let first = get_all_posts_paged(None, 2).unwrap();
+        assert_eq!(post_ids(&first), vec![5, 4]);
 
-    // Validate content
-    if new_content.trim().is_empty() {
-        return "Comment content cannot be empty".to_string();
-    }
+        // A post created between two requests doesn't shift the next page
+        create_post_v2("late".to_string(), Vec::new(), Vec::new()).unwrap();
+        let second = get_all_posts_paged(first.next_cursor, 2).unwrap();
+        assert_eq!(post_ids(&second), vec![3, 2]);
+        let last = get_all_posts_paged(second.next_cursor, 2).unwrap();
+        assert_eq!(post_ids(&last), vec![1]);
+        assert_eq!(last.next_cursor, None);
 
-    if new_content.len() > 500 {
-        return "Comment content too long (max 500 characters)".to_string();
+        assert!(matches!(get_all_posts_paged(Some("garbage".to_string()), 2), Err(ApiError::Validation { .. })));
     }
 
-    let mut comments = COMMENTS.lock().unwrap();
-    if let Some(comment) = comments.get_mut(&comment_id) {
-        // Check if caller is the author or admin
-        if comment.author_id != caller_id && !is_admin(&caller_id) {
-            return "Access denied: You can only edit your own comments".to_string();
+    #[test]
+    fn user_pages_follow_creation_order() {
+        for (n, name) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+            register(n, name);
+            advance_clock(1);
         }
+        let first = get_all_users_paged(None, 2).unwrap();
+        let names: Vec<String> = first.items.iter().map(|user| user.username.clone()).collect();
+        assert_eq!(names, vec!["carol", "bob"]);
+        let rest = get_all_users_paged(first.next_cursor, 2).unwrap();
+        assert_eq!(rest.items[0].username, "alice");
+        assert_eq!(rest.next_cursor, None);
+
+        call_as(2);
+        delete_user_v2().unwrap();
+        let page = search_users_paged("o".to_string(), None, 0).unwrap();
+        let names: Vec<String> = page.items.iter().map(|user| user.username.clone()).collect();
+        assert_eq!(names, vec!["carol"]);
+    }
 
-        if comment.is_deleted {
-            return "Cannot edit deleted comment".to_string();
+    #[test]
+    fn follow_and_comment_pages_walk_their_keys() {
+        for n in 1..=4 {
+            register(n, &format!("user{}", n));
         }
-
-        comment.content = new_content.trim().to_string();
-        comment.updated_at = Some(current_time());
-
-        return "Comment updated successfully".to_string();
+        for n in 2..=4 {
+            call_as(n);
+            follow_user_v2(principal(1)).unwrap();
+        }
+        call_as(1);
+        follow_user_v2(principal(3)).unwrap();
+        follow_user_v2(principal(4)).unwrap();
+
+        let first = get_followers_paged(principal(1), None, 2).unwrap();
+        assert_eq!(follower_ids(&first.items), vec![principal(2), principal(3)]);
+        let rest = get_followers_paged(principal(1), first.next_cursor, 2).unwrap();
+        assert_eq!(follower_ids(&rest.items), vec![principal(4)]);
+        assert_eq!(rest.next_cursor, None);
+
+        let following = get_following_paged(principal(1), None, 1).unwrap();
+        assert_eq!(following_ids(&following.items), vec![principal(3)]);
+        let following = get_following_paged(principal(1), following.next_cursor, 1).unwrap();
+        assert_eq!(following_ids(&following.items), vec![principal(4)]);
+
+        let post_id = create_post_v2("thread".to_string(), Vec::new(), Vec::new()).unwrap();
+        let other_post = create_post_v2("other".to_string(), Vec::new(), Vec::new()).unwrap();
+        for n in 1..=3 {
+            create_comment_v2(post_id, format!("comment {}", n)).unwrap();
+            create_comment_v2(other_post, "elsewhere".to_string()).unwrap();
+        }
+        let comments = get_post_comments_paged(post_id, None, 2).unwrap();
+        let contents: Vec<&str> = comments.items.iter().map(|comment| comment.content.as_str()).collect();
+        assert_eq!(contents, vec!["comment 1", "comment 2"]);
+        let comments = get_post_comments_paged(post_id, comments.next_cursor, 2).unwrap();
+        assert_eq!(comments.items.len(), 1);
+        assert_eq!(comments.next_cursor, None);
     }
 
-    "Comment not found".to_string()
-}
+    #[test]
+    fn indexes_follow_posts_through_edit_and_delete() {
+        register(1, "Alice");
+        register(2, "bob");
+        let tagged = create_post_v2("hi @ALICE".to_string(), vec!["#Rust".to_string()], Vec::new()).unwrap();
+        let other = create_post_v2("plain".to_string(), Vec::new(), Vec::new()).unwrap();
+
+        assert_eq!(get_post(tagged).unwrap().mentions, vec![principal(1)]);
+        assert_eq!(post_ids(&get_posts_by_hashtag_paged("rust".to_string(), None, 0).unwrap()), vec![tagged]);
+        assert_eq!(post_ids(&get_user_posts_paged(principal(2), None, 0).unwrap()), vec![other, tagged]);
+
+        delete_post_v2(tagged).unwrap();
+        assert!(get_posts_by_hashtag("RUST".to_string()).is_empty());
+        assert_eq!(post_ids(&get_user_posts_paged(principal(2), None, 0).unwrap()), vec![other]);
+        // The author index still knows the deleted post
+        assert_eq!(post_ids_by_author(principal(2)), vec![tagged, other]);
+
+        // Rebuilding from the records gives the same answers
+        rebuild_indexes();
+        assert!(get_posts_by_hashtag("rust".to_string()).is_empty());
+        assert_eq!(post_ids_by_author(principal(2)), vec![tagged, other]);
+        assert_eq!(find_user_by_username("alice"), Some(principal(1)));
+    }
 
-#[ic_cdk::update]
-fn delete_comment(comment_id: u64) -> String {
-    let caller_id = caller();
+    #[test]
+    fn comment_indexes_and_admin_delete_user() {
+        register(1, "alice");
+        let post_id = create_post_v2("post".to_string(), Vec::new(), Vec::new()).unwrap();
+        register(2, "bob");
+        let comment_id = create_comment_v2(post_id, "reply".to_string()).unwrap();
+        let own_post = create_post_v2("bob's".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(comment_ids_for_post(post_id), vec![comment_id]);
+        assert_eq!(comment_ids_by_author(principal(2)), vec![comment_id]);
+
+        admin_caller();
+        admin_delete_user_v2(principal(2)).unwrap();
+        assert!(get_post_comments(post_id).is_empty());
+        assert!(get_post(own_post).is_none());
+        assert_eq!(find_user_by_username("bob"), None);
+        // The records are kept, soft-deleted, and stay reachable from their author
+        assert!(load_comment(comment_id).unwrap().is_deleted);
+        assert_eq!(comment_ids_by_author(principal(2)), vec![comment_id]);
+    }
 
-    let mut comments = COMMENTS.lock().unwrap();
-    if let Some(comment) = comments.get_mut(&comment_id) {
-        // Check if caller is the author or admin
-        if comment.author_id != caller_id && !is_admin(&caller_id) {
-            return "Access denied: You can only delete your own comments".to_string();
+    #[test]
+    fn feed_merges_followed_authors_newest_first() {
+        for n in 1..=3 {
+            register(n, &format!("user{}", n));
         }
-
-        if comment.is_deleted {
-            return "Comment already deleted".to_string();
+        // Posts 1..=6 alternate between the three authors
+        for n in 0..6u8 {
+            call_as(n % 3 + 1);
+            create_post_v2(format!("post {}", n + 1), Vec::new(), Vec::new()).unwrap();
+            advance_clock(1);
         }
+        call_as(1);
+        follow_user_v2(principal(2)).unwrap();
+
+        let first = get_user_feed_paged(None, 2).unwrap();
+        assert_eq!(post_ids(&first), vec![5, 4]);
+        let rest = get_user_feed_paged(first.next_cursor, 10).unwrap();
+        assert_eq!(post_ids(&rest), vec![2, 1]);
+        assert_eq!(rest.next_cursor, None);
+
+        delete_post_v2(4).unwrap();
+        let feed: Vec<u64> = get_user_feed().iter().map(|post| post.post_id).collect();
+        assert_eq!(feed, vec![5, 2, 1]);
+    }
 
-        let post_id = comment.post_id;
-        comment.is_deleted = true;
-
-        // Decrement comment count on the post
-        drop(comments); // Release the comments lock
-        let mut posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get_mut(&post_id) {
-            if post.comments_count > 0 {
-                post.comments_count -= 1;
-            }
+    #[test]
+    fn follows_are_timestamped_and_counted() {
+        for n in 1..=3 {
+            register(n, &format!("user{}", n));
         }
-
-        return "Comment deleted successfully".to_string();
+        call_as(2);
+        follow_user_v2(principal(1)).unwrap();
+        advance_clock(5);
+        call_as(3);
+        follow_user_v2(principal(1)).unwrap();
+        follow_user_v2(principal(2)).unwrap();
+
+        // Most recent follow first, each with the time it was created
+        let followers = get_followers(principal(1));
+        assert_eq!(follower_ids(&followers), vec![principal(3), principal(2)]);
+        assert_eq!(followers[0].created_at, followers[1].created_at + 5);
+        assert_eq!(get_user(principal(1)).unwrap().followers_count, 2);
+        assert_eq!(get_user(principal(3)).unwrap().following_count, 2);
+
+        unfollow_user_v2(principal(1)).unwrap();
+        assert_eq!(get_user(principal(1)).unwrap().followers_count, 1);
+        assert_eq!(get_user(principal(3)).unwrap().following_count, 1);
+        assert_eq!(unfollow_user_v2(principal(1)), Err(ApiError::NotFound { resource: "Follow".to_string() }));
+
+        // Deleting a user drops their edges and the counts they contributed to
+        admin_caller();
+        admin_delete_user_v2(principal(2)).unwrap();
+        assert!(get_followers(principal(1)).is_empty());
+        assert_eq!(get_user(principal(1)).unwrap().followers_count, 0);
+        assert_eq!(get_user(principal(3)).unwrap().following_count, 0);
     }
 
-    "Comment not found".to_string()
-}
+    #[test]
+    fn untimestamped_follow_edges_are_migrated() {
+        register(1, "alice");
+        register(2, "bob");
+        legacy_follows().insert((PrincipalKey(principal(2)), PrincipalKey(principal(1))), ());
+        set_stored_schema_version(3);
+        advance_clock(9);
+        migrate_schema();
+
+        let followers = get_followers(principal(1));
+        assert_eq!(follower_ids(&followers), vec![principal(2)]);
+        assert_eq!(followers[0].created_at, current_time());
+        assert_eq!(get_user(principal(1)).unwrap().followers_count, 1);
+        assert_eq!(get_user(principal(2)).unwrap().following_count, 1);
+        assert!(legacy_follows().is_empty());
+    }
 
-// ============ FOLLOW FUNCTIONS ============
+    fn username_error(reason: &str) -> Result<(), ApiError> {
+        Err(ApiError::Validation { field: "username".to_string(), reason: reason.to_string() })
+    }
 
-#[ic_cdk::update]
-fn follow_user(user_to_follow: Principal) -> String {
-    let follower_id = caller();
+    #[test]
+    fn usernames_are_unique_validated_and_reserved() {
+        register(1, "Alice");
+        call_as(2);
+        let taken = Err(ApiError::AlreadyExists { resource: "Username".to_string() });
+        assert_eq!(create_user_v2("alice".to_string(), None, None, None, None, None, None), taken);
+        // Full-width letters collapse onto the same name
+        assert_eq!(check_username_available("ＡＬＩＣＥ".to_string()), taken);
+        assert_eq!(check_username_available("al".to_string()), username_error("Username must be between 3 and 30 characters"));
+        assert_eq!(check_username_available("bob smith".to_string()), username_error("Username can only contain letters, digits and underscores"));
+        assert_eq!(check_username_available("Support".to_string()), username_error("Username is reserved"));
+        assert_eq!(check_username_available("  bob_2 ".to_string()), Ok(()));
+
+        // The stored name is the canonical form, and the holder may keep their own name
+        register(2, "  bob_2 ");
+        assert_eq!(get_user(principal(2)).unwrap().username, "bob_2");
+        call_as(1);
+        assert_eq!(check_username_available("ALICE".to_string()), Ok(()));
+
+        // Admins may give out reserved names
+        admin_caller();
+        admin_create_user_with_principal_v2(principal(3), "support".to_string(), None, None, None, None, None, None).unwrap();
+        assert_eq!(get_user(principal(3)).unwrap().username, "support");
+    }
 
-    // Check if user exists
-    if !user_exists(&follower_id) {
-        return "User must be registered to follow others".to_string();
+    #[test]
+    fn mentions_resolve_case_insensitively_without_punctuation() {
+        register(1, "alice");
+        register(2, "bob");
+        let post_id = create_post_v2("hi @ALICE, meet @nobody".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(get_post(post_id).unwrap().mentions, vec![principal(1)]);
     }
 
-    // Check if target user exists
-    if !user_exists(&user_to_follow) {
-        return "User to follow not found".to_string();
+    #[test]
+    fn colliding_usernames_are_renamed_by_the_migration() {
+        register(1, "alice");
+        advance_clock(1);
+        register(2, "bob");
+        // Written before names were unique
+        let mut duplicate = load_user(&principal(2)).unwrap();
+        duplicate.username = "ALICE".to_string();
+        save_user(duplicate);
+        set_stored_schema_version(4);
+        migrate_schema();
+
+        assert_eq!(get_user(principal(1)).unwrap().username, "alice");
+        assert_eq!(get_user(principal(2)).unwrap().username, "ALICE_2");
+        assert_eq!(find_user_by_username("alice_2"), Some(principal(2)));
+        assert_eq!(find_user_by_username("alice"), Some(principal(1)));
     }
 
-    // Can't follow yourself
-    if follower_id == user_to_follow {
-        return "You cannot follow yourself".to_string();
+    #[test]
+    fn username_changes_keep_a_cooldown_history_and_redirects() {
+        register(1, "alice");
+        assert_eq!(change_username("alice_w".to_string()), Ok(()));
+        let retry_after_ns = USERNAME_CHANGE_COOLDOWN_NS;
+        assert_eq!(change_username("alice_x".to_string()), Err(ApiError::RateLimited { retry_after_ns }));
+
+        // The old handle stays with its former owner during the grace period
+        register(2, "bob");
+        let taken = Err(ApiError::AlreadyExists { resource: "Username".to_string() });
+        assert_eq!(check_username_available("alice".to_string()), taken);
+        let post_id = create_post_v2("hi @alice".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(get_post(post_id).unwrap().mentions, vec![principal(1)]);
+
+        advance_clock(USERNAME_REDIRECT_GRACE_NS);
+        assert_eq!(check_username_available("alice".to_string()), Ok(()));
+        call_as(1);
+        assert_eq!(change_username("alice_x".to_string()), Ok(()));
+
+        assert!(admin_get_username_history(principal(1)).is_err());
+        admin_caller();
+        let history: Vec<(String, String)> = admin_get_username_history(principal(1)).unwrap().into_iter()
+            .map(|change| (change.old_username, change.new_username))
+            .collect();
+        assert_eq!(history, vec![
+            ("alice".to_string(), "alice_w".to_string()),
+            ("alice_w".to_string(), "alice_x".to_string()),
+        ]);
     }
 
-    let mut follows = FOLLOWS.lock().unwrap();
-    let user_following = follows.entry(follower_id).or_insert_with(HashSet::new);
+    #[test]
+    fn admin_renames_keep_the_account_and_skip_the_cooldown() {
+        register(1, "alice");
+        let created_at = get_user(principal(1)).unwrap().created_at;
+        advance_clock(10);
+        admin_caller();
+        admin_create_user_with_principal_v2(principal(1), "alice_admin".to_string(), Some("Alice".to_string()), None, None, None, None, None).unwrap();
+
+        let user = get_user(principal(1)).unwrap();
+        assert_eq!((user.username.as_str(), user.created_at), ("alice_admin", created_at));
+        assert_eq!(user.full_name, Some("Alice".to_string()));
+        assert_eq!(get_all_users().len(), 1);
+        assert_eq!(find_user_by_username("alice"), None);
+        assert_eq!(resolve_username("alice"), Some(principal(1)));
+        assert!(admin_get_username_history(principal(1)).unwrap()[0].system);
+
+        call_as(1);
+        assert_eq!(change_username("alice_again".to_string()), Ok(()));
+    }
 
-    if user_following.contains(&user_to_follow) {
-        return "You are already following this user".to_string();
+    #[test]
+    fn collision_renames_skip_the_cooldown() {
+        register(1, "alice");
+        advance_clock(1);
+        register(2, "bob");
+        let mut duplicate = load_user(&principal(2)).unwrap();
+        duplicate.username = "alice".to_string();
+        save_user(duplicate);
+        set_stored_schema_version(4);
+        migrate_schema();
+
+        admin_caller();
+        let history = admin_get_username_history(principal(2)).unwrap();
+        assert_eq!((history[0].new_username.as_str(), history[0].system), ("alice_2", true));
+        call_as(2);
+        assert_eq!(change_username("bobby".to_string()), Ok(()));
     }
 
-    user_following.insert(user_to_follow);
-    "User followed successfully".to_string()
-}
+    #[test]
+    fn users_are_found_by_exact_handle_and_redirect() {
+        register(1, "alice");
+        register(2, "alicia");
+        assert_eq!(get_user_by_username(" @ALICE".to_string()).unwrap().user_id, principal(1));
+        assert!(get_user_by_username("ali".to_string()).is_none());
+
+        change_username("alicia_b".to_string()).unwrap();
+        assert_eq!(get_user_by_username("alicia".to_string()).unwrap().username, "alicia_b");
+        advance_clock(USERNAME_REDIRECT_GRACE_NS);
+        assert!(get_user_by_username("alicia".to_string()).is_none());
+    }
 
-#[ic_cdk::update]
-fn unfollow_user(user_to_unfollow: Principal) -> String {
-    let follower_id = caller();
+    #[test]
+    fn public_queries_hide_private_profile_fields() {
+        call_as(1);
+        create_user_v2("alice".to_string(), None, Some("alice@example.com".to_string()), None, None,
+            Some("Lisbon".to_string()), None).unwrap();
+        register(2, "bob");
+        register(3, "carol");
+
+        // Email is private by default, even to anonymous callers
+        CALLER.with(|caller| caller.set(Principal::anonymous()));
+        assert_eq!(get_user(principal(1)).unwrap().email, None);
+        assert_eq!(get_user(principal(1)).unwrap().location, Some("Lisbon".to_string()));
+        assert_eq!(update_settings(UserSettings::default()), Err(ApiError::NotRegistered));
+
+        call_as(1);
+        assert_eq!(get_user(principal(1)).unwrap().email, Some("alice@example.com".to_string()));
+        assert_eq!(get_my_profile().unwrap().email, Some("alice@example.com".to_string()));
+        let privacy = PrivacySettings { email: Visibility::Followers, location: Visibility::OnlyMe, website: Visibility::Public };
+        update_settings(UserSettings { privacy, ..UserSettings::default() }).unwrap();
+
+        call_as(2);
+        follow_user_v2(principal(1)).unwrap();
+        let profile = &search_users("alice".to_string())[0];
+        assert_eq!((profile.email.as_deref(), profile.location.as_deref()), (Some("alice@example.com"), None));
+
+        call_as(3);
+        let profile = get_user_by_username("alice".to_string()).unwrap();
+        assert_eq!((profile.email, profile.location), (None, None));
+
+        admin_caller();
+        let page = get_all_users_paged(None, 10).unwrap();
+        let profile = page.items.iter().find(|profile| profile.user_id == principal(1)).unwrap();
+        assert_eq!(profile.location, Some("Lisbon".to_string()));
+    }
 
-    let mut follows = FOLLOWS.lock().unwrap();
-    if let Some(user_following) = follows.get_mut(&follower_id) {
-        if user_following.remove(&user_to_unfollow) {
-            return "User unfollowed successfully".to_string();
-        }
+    #[test]
+    fn deleting_an_account_cascades_to_everything_it_touched() {
+        register(1, "alice");
+        let alice_post = create_post_v2("alice's".to_string(), Vec::new(), Vec::new()).unwrap();
+        let alice_comment = create_comment_v2(alice_post, "mine".to_string()).unwrap();
+        register(2, "bob");
+        let bob_post = create_post_v2("bob's".to_string(), Vec::new(), Vec::new()).unwrap();
+        let bob_comment = create_comment_v2(bob_post, "on bob's post".to_string()).unwrap();
+        create_comment_v2(alice_post, "on alice's post".to_string()).unwrap();
+        like_post_v2(alice_post).unwrap();
+        share_post_v2(alice_post).unwrap();
+        like_comment_v2(alice_comment).unwrap();
+        follow_user_v2(principal(1)).unwrap();
+        change_username("bobby".to_string()).unwrap();
+        call_as(1);
+        follow_user_v2(principal(2)).unwrap();
+
+        call_as(2);
+        assert_eq!(delete_user_v2(), Ok(()));
+        assert_eq!(delete_user_v2(), Err(ApiError::NotRegistered));
+
+        // Bob's content is gone and the counters on Alice's content are back to what she did herself
+        assert!(get_post(bob_post).is_none());
+        assert!(load_comment(bob_comment).unwrap().is_deleted);
+        let post = get_post(alice_post).unwrap();
+        assert_eq!((post.likes, post.shares_count, post.comments_count), (0, 0, 1));
+        assert_eq!(load_comment(alice_comment).unwrap().likes, 0);
+        let alice = get_user(principal(1)).unwrap();
+        assert_eq!((alice.followers_count, alice.following_count), (0, 0));
+        assert!(get_user_liked_posts().is_empty());
+
+        // Both the current and the old handle are free again
+        assert_eq!(find_user_by_username("bobby"), None);
+        assert_eq!(resolve_username("bob"), None);
+        assert!(get_all_users_paged(None, 10).unwrap().items.iter().all(|user| user.user_id != principal(2)));
     }
 
-    "You are not following this user".to_string()
-}
+    #[test]
+    fn data_export_walks_every_section_in_chunks() {
+        register(1, "alice");
+        register(2, "bob");
+        let post_ids: Vec<u64> = (0..3)
+            .map(|n| create_post_v2(format!("post {}", n), Vec::new(), Vec::new()).unwrap())
+            .collect();
+        delete_post_v2(post_ids[1]).unwrap();
+        let comment_id = create_comment_v2(post_ids[0], "note".to_string()).unwrap();
+        delete_comment_v2(comment_id).unwrap();
+        like_post_v2(post_ids[0]).unwrap();
+        follow_user_v2(principal(1)).unwrap();
+        call_as(1);
+        create_post_v2("not bob's".to_string(), Vec::new(), Vec::new()).unwrap();
+        follow_user_v2(principal(2)).unwrap();
+
+        call_as(2);
+        let mut chunks = Vec::new();
+        let mut cursor = None;
+        loop {
+            let chunk = export_my_data(cursor, 2).unwrap();
+            cursor = chunk.next_cursor.clone();
+            chunks.push(chunk);
+            if cursor.is_none() {
+                break;
+            }
+        }
 
-#[ic_cdk::query]
-fn get_followers(user_id: Principal) -> Vec<Principal> {
-    let follows = FOLLOWS.lock().unwrap();
-    let mut followers = Vec::new();
+        // Deleted posts and comments are part of the archive, other users' are not
+        let posts: Vec<(u64, bool)> = chunks.iter().flat_map(|chunk| &chunk.posts).map(|post| (post.post_id, post.is_deleted)).collect();
+        assert_eq!(posts, vec![(post_ids[0], false), (post_ids[1], true), (post_ids[2], false)]);
+        assert_eq!(chunks.iter().filter(|chunk| chunk.section == ExportSection::Posts).count(), 2);
+        let comments: Vec<bool> = chunks.iter().flat_map(|chunk| &chunk.comments).map(|comment| comment.is_deleted).collect();
+        assert_eq!(comments, vec![true]);
+        assert_eq!(chunks[0].profile.as_ref().unwrap().username, "bob");
+        let liked: Vec<u64> = chunks.iter().flat_map(|chunk| chunk.liked_post_ids.clone()).collect();
+        assert_eq!(liked, vec![post_ids[0]]);
+        assert_eq!(chunks.iter().flat_map(|chunk| &chunk.following).count(), 1);
+        assert_eq!(chunks.iter().flat_map(|chunk| &chunk.followers).count(), 1);
+
+        assert!(matches!(export_my_data(Some("99".to_string()), 2), Err(ApiError::Validation { .. })));
+        call_as(3);
+        assert!(matches!(export_my_data(None, 2), Err(ApiError::NotRegistered)));
+    }
 
-    for (follower, following_set) in follows.iter() {
-        if following_set.contains(&user_id) {
-            followers.push(*follower);
+    // Walks verify_integrity or repair_integrity to the end in small batches
+    fn run_integrity(repair: bool) -> Vec<IntegrityIssue> {
+        let mut issues = Vec::new();
+        let mut cursor = None;
+        loop {
+            let report = if repair { repair_integrity(cursor, 2) } else { verify_integrity(cursor, 2) }.unwrap();
+            issues.extend(report.issues);
+            cursor = report.next_cursor;
+            if cursor.is_none() {
+                return issues;
+            }
         }
     }
 
-    followers
-}
-
-#[ic_cdk::query]
-fn get_following(user_id: Principal) -> Vec<Principal> {
-    let follows = FOLLOWS.lock().unwrap();
-    if let Some(following_set) = follows.get(&user_id) {
-        following_set.iter().cloned().collect()
-    } else {
-        Vec::new()
+    #[test]
+    fn integrity_check_reports_and_repairs_drift() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let kept_post = create_post_v2("kept".to_string(), Vec::new(), Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "hi".to_string()).unwrap();
+        like_post_v2(kept_post).unwrap();
+        register(2, "bob");
+        follow_user_v2(principal(1)).unwrap();
+
+        // Drift: a stale counter, a like reaction missing its reverse entry and its count,
+        // a like of a deleted user and a comment left behind by a removed post
+        let mut post = load_post(kept_post).unwrap();
+        post.shares_count = 5;
+        save_post(post);
+        store_reaction(principal(2), ReactionTarget::Post(kept_post), LIKE_REACTION);
+        POST_LIKERS.with(|likers| likers.borrow_mut().remove(&(kept_post, PrincipalKey(principal(2)))));
+        USER_LIKES.with(|likes| likes.borrow_mut().insert((PrincipalKey(principal(9)), kept_post), ()));
+        POSTS.with(|posts| posts.borrow_mut().remove(&post_id));
+        let mut user = load_user(&principal(1)).unwrap();
+        user.followers_count = 0;
+        save_user(user);
+
+        assert!(matches!(verify_integrity(None, 10), Err(ApiError::Unauthorized { .. })));
+        admin_caller();
+        let issues = run_integrity(false);
+        let count = |f: fn(&IntegrityIssue) -> bool| issues.iter().filter(|issue| f(issue)).count();
+        assert_eq!(count(|issue| matches!(issue, IntegrityIssue::OrphanedEntry { .. })), 1);
+        assert_eq!(count(|issue| matches!(issue, IntegrityIssue::MissingEntry { .. })), 1);
+        assert_eq!(count(|issue| matches!(issue, IntegrityIssue::OrphanedComment { .. })), 1);
+        assert_eq!(count(|issue| matches!(issue, IntegrityIssue::CounterMismatch { .. })), 3);
+        // Verifying changes nothing
+        assert_eq!(run_integrity(false).len(), issues.len());
+
+        // Once the missing reverse entry is restored the like counter is behind as well
+        assert_eq!(run_integrity(true).len(), issues.len() + 1);
+        assert!(run_integrity(false).is_empty());
+        let post = load_post(kept_post).unwrap();
+        assert_eq!((post.likes, post.shares_count), (2, 0));
+        assert!(load_comment(comment_id).unwrap().is_deleted);
+        assert_eq!(load_user(&principal(1)).unwrap().followers_count, 1);
     }
-}
 
-#[ic_cdk::query]
-fn is_following(user_id: Principal) -> bool {
-    let caller_id = caller();
-    let follows = FOLLOWS.lock().unwrap();
+    #[test]
+    fn deleting_a_post_cascades_to_its_comments_likes_and_shares() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "first".to_string()).unwrap();
+        register(2, "bob");
+        like_post_v2(post_id).unwrap();
+        share_post_v2(post_id).unwrap();
+        like_comment_v2(comment_id).unwrap();
+
+        call_as(1);
+        delete_post_v2(post_id).unwrap();
+
+        assert!(load_comment(comment_id).unwrap().is_deleted);
+        assert_eq!(load_comment(comment_id).unwrap().likes, 0);
+        assert!(item_user_ids(&COMMENT_LIKERS, comment_id).is_empty());
+        assert!(item_user_ids(&POST_SHARERS, post_id).is_empty());
+        assert!(get_post_comments(post_id).is_empty());
+        assert_eq!(get_post_comments_paged(post_id, None, 10).err(), Some(ApiError::Deleted { resource: "Post".to_string() }));
+        call_as(2);
+        assert!(get_user_liked_posts().is_empty());
+
+        // Nothing is left for the integrity checker to fix
+        admin_caller();
+        assert!(run_integrity(false).is_empty());
+    }
 
-    if let Some(following_set) = follows.get(&caller_id) {
-        following_set.contains(&user_id)
-    } else {
-        false
+    #[test]
+    fn deleted_posts_can_be_restored_until_the_trash_is_purged() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), vec!["rust".to_string()], Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "first".to_string()).unwrap();
+        let kept_id = create_comment_v2(post_id, "second".to_string()).unwrap();
+        delete_comment_v2(kept_id).unwrap();
+        advance_clock(1);
+        delete_post_v2(post_id).unwrap();
+
+        let trash = get_my_trash().unwrap();
+        assert_eq!(trash.posts.iter().map(|post| post.post_id).collect::<Vec<_>>(), vec![post_id]);
+        assert_eq!(trash.comments.iter().map(|comment| comment.comment_id).collect::<Vec<_>>(), vec![comment_id, kept_id]);
+
+        // Only the author restores, and only the comments deleted with the post come back
+        register(2, "bob");
+        assert!(matches!(restore_post(post_id), Err(ApiError::Unauthorized { .. })));
+        call_as(1);
+        assert_eq!(restore_post(post_id), Ok(()));
+        assert_eq!(restore_post(post_id), Err(ApiError::Conflict { reason: "Post is not deleted".to_string() }));
+        let post = get_post(post_id).unwrap();
+        assert_eq!(post.comments_count, 1);
+        assert_eq!(post.deleted_at, None);
+        assert_eq!(get_post_comments(post_id).iter().map(|comment| comment.comment_id).collect::<Vec<_>>(), vec![comment_id]);
+        assert_eq!(restore_comment(kept_id), Ok(()));
+        assert_eq!(get_post(post_id).unwrap().comments_count, 2);
+
+        // Past the restore window the post stays deleted until the purge removes it
+        delete_post_v2(post_id).unwrap();
+        advance_clock(TRASH_RESTORE_WINDOW_NS + 1);
+        assert_eq!(restore_post(post_id), Err(ApiError::Conflict { reason: "Post can no longer be restored".to_string() }));
+        assert!(get_my_trash().unwrap().posts.is_empty());
+        purge_trash();
+        assert!(load_post(post_id).is_some());
+        advance_clock(DEFAULT_TRASH_RETENTION_NS);
+        purge_trash();
+        assert!(load_post(post_id).is_none());
+        assert!(load_comment(comment_id).is_none());
+        assert!(load_comment(kept_id).is_none());
+        assert!(post_ids_by_author(principal(1)).is_empty());
+        assert!(comment_ids_by_author(principal(1)).is_empty());
+
+        admin_caller();
+        assert!(run_integrity(false).is_empty());
     }
-}
 
-#[ic_cdk::query]
-fn get_user_feed() -> Vec<Post> {
-    let caller_id = caller();
-    let follows = FOLLOWS.lock().unwrap();
-    let posts = POSTS.lock().unwrap();
+    #[test]
+    fn trash_retention_is_admin_only_and_covers_the_restore_window() {
+        register(1, "alice");
+        assert!(admin_set_trash_retention(DEFAULT_TRASH_RETENTION_NS).is_err());
 
-    // Get list of users the caller is following
-    let following_set = follows.get(&caller_id).cloned().unwrap_or_default();
+        admin_caller();
+        assert_eq!(admin_get_trash_retention(), Ok(DEFAULT_TRASH_RETENTION_NS));
+        assert!(matches!(admin_set_trash_retention(TRASH_RESTORE_WINDOW_NS - 1), Err(ApiError::Validation { .. })));
+        assert_eq!(admin_set_trash_retention(TRASH_RESTORE_WINDOW_NS), Ok(()));
+        assert_eq!(admin_get_trash_retention(), Ok(TRASH_RESTORE_WINDOW_NS));
+    }
 
-    // Include caller's own posts in the feed
-    let mut feed_users = following_set;
-    feed_users.insert(caller_id);
+    // The (kind, actors, actor_count) of each group on a notification page
+    fn notification_groups(page: &Page<NotificationGroup>) -> Vec<(NotificationKind, Vec<Principal>, u64)> {
+        page.items.iter().map(|group| (group.kind, group.actors.clone(), group.actor_count)).collect()
+    }
 
-    // Get posts from followed users
-    let mut feed_posts: Vec<Post> = posts.values()
-        .filter(|post| !post.is_deleted && feed_users.contains(&post.author_id))
-        .cloned()
-        .collect();
+    #[test]
+    fn notifications_are_grouped_counted_and_marked_read() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        register(2, "bob");
+        like_post_v2(post_id).unwrap();
+        register(3, "carol");
+        like_post_v2(post_id).unwrap();
+        follow_user_v2(principal(1)).unwrap();
+        create_comment_v2(post_id, "hi @alice".to_string()).unwrap();
+        register(4, "dave");
+        like_post_v2(post_id).unwrap();
+
+        call_as(1);
+        // Own actions don't notify
+        like_post_v2(post_id).unwrap();
+        assert_eq!(get_unread_notification_count(), Ok(6));
+
+        // Groups are listed once, at their newest notification, and never straddle a cursor
+        let first = get_notifications(None, 2).unwrap();
+        let likes = (NotificationKind::Like, vec![principal(4), principal(3), principal(2)], 3);
+        let mention = (NotificationKind::Mention, vec![principal(3)], 1);
+        assert!(notification_groups(&first) == vec![likes, mention]);
+        let second = get_notifications(first.next_cursor, 2).unwrap();
+        let comment = (NotificationKind::Comment, vec![principal(3)], 1);
+        let follow = (NotificationKind::Follow, vec![principal(3)], 1);
+        assert!(notification_groups(&second) == vec![comment, follow]);
+        let last = get_notifications(second.next_cursor, 2).unwrap();
+        assert!(last.items.is_empty() && last.next_cursor.is_none());
+
+        let like_ids = first.items[0].notification_ids.clone();
+        assert_eq!(like_ids.len(), 3);
+        assert_eq!(mark_notifications_read(Some(like_ids[0])), Ok(1));
+        assert_eq!(mark_notifications_read(Some(like_ids[0])), Ok(0));
+        assert_eq!(get_unread_notification_count(), Ok(5));
+        assert!(get_notifications(None, 1).unwrap().items[0].unread);
+        assert_eq!(mark_notifications_read(None), Ok(5));
+        assert_eq!(get_unread_notification_count(), Ok(0));
+        assert!(get_notifications(None, 10).unwrap().items.iter().all(|group| !group.unread));
+        assert_eq!(mark_notifications_read(Some(0)), Err(ApiError::NotFound { resource: "Notification".to_string() }));
+    }
 
-    // Sort by creation date (most recent first)
-    feed_posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    #[test]
+    fn notification_pages_stop_after_the_scan_limit() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        register(2, "bob");
+        follow_user_v2(principal(1)).unwrap();
+        for _ in 0..NOTIFICATION_PAGE_SCAN {
+            notify(principal(1), principal(2), NotificationKind::Like, Some(post_id), None);
+        }
 
-    // Limit to 50 most recent posts
-    feed_posts.truncate(50);
+        // The likes group is summarized from its newest notifications, the rest of the
+        // page budget runs out before reaching the follow
+        call_as(1);
+        let first = get_notifications(None, 10).unwrap();
+        assert!(notification_groups(&first) == vec![(NotificationKind::Like, vec![principal(2)], 1)]);
+        assert_eq!(first.items[0].notification_ids.len(), NOTIFICATION_GROUP_SCAN);
+        let second = get_notifications(first.next_cursor, 10).unwrap();
+        assert!(notification_groups(&second) == vec![(NotificationKind::Follow, vec![principal(2)], 1)]);
+        assert_eq!(second.next_cursor, None);
+    }
 
-    feed_posts
-}
+    #[test]
+    fn notifications_go_with_their_actor_and_expire() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        register(2, "bob");
+        like_post_v2(post_id).unwrap();
+        follow_user_v2(principal(1)).unwrap();
+        register(3, "carol");
+        share_post_v2(post_id).unwrap();
+        assert!(share_post_v2(post_id).is_err());
+
+        // Deleting an account removes the notifications it caused
+        call_as(2);
+        delete_user_v2().unwrap();
+        call_as(1);
+        assert_eq!(get_unread_notification_count(), Ok(1));
+        let page = get_notifications(None, 10).unwrap();
+        assert!(notification_groups(&page) == vec![(NotificationKind::Share, vec![principal(3)], 1)]);
+
+        // Old notifications are pruned
+        prune_notifications();
+        assert_eq!(get_notifications(None, 10).unwrap().items.len(), 1);
+        advance_clock(NOTIFICATION_RETENTION_NS + 1);
+        prune_notifications();
+        assert!(get_notifications(None, 10).unwrap().items.is_empty());
+        assert_eq!(get_unread_notification_count(), Ok(0));
+    }
 
-// ============ SHARE FUNCTIONS ============
+    #[test]
+    fn notification_preferences_filter_what_is_recorded() {
+        register(1, "alice");
+        let muted_post = create_post_v2("muted".to_string(), Vec::new(), Vec::new()).unwrap();
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let mut settings = get_settings().unwrap();
+        settings.notifications.shares = false;
+        settings.notifications.muted_posts = vec![muted_post, muted_post];
+        assert_eq!(update_settings(settings), Ok(()));
+        assert_eq!(get_settings().unwrap().notifications.muted_posts, vec![muted_post]);
+
+        register(2, "bob");
+        share_post_v2(post_id).unwrap();
+        like_post_v2(muted_post).unwrap();
+        like_post_v2(post_id).unwrap();
+        call_as(1);
+        assert_eq!(get_unread_notification_count(), Ok(1));
+
+        // Only accounts Alice follows get through
+        let mut settings = get_settings().unwrap();
+        settings.notifications.only_from_following = true;
+        update_settings(settings).unwrap();
+        register(3, "carol");
+        follow_user_v2(principal(1)).unwrap();
+        call_as(1);
+        follow_user_v2(principal(2)).unwrap();
+        call_as(2);
+        follow_user_v2(principal(1)).unwrap();
+        call_as(1);
+        assert_eq!(get_unread_notification_count(), Ok(2));
+
+        let mut settings = get_settings().unwrap();
+        settings.notifications.muted_posts = (0..=MAX_MUTED_POSTS as u64).collect();
+        assert!(matches!(update_settings(settings), Err(ApiError::Validation { .. })));
+    }
 
-#[ic_cdk::update]
-fn share_post(post_id: u64) -> String {
-    let caller_id = caller();
+    fn conversation_ids(page: &Page<ConversationSummary>) -> Vec<u64> {
+        page.items.iter().map(|summary| summary.conversation.conversation_id).collect()
+    }
 
-    // Check if user exists
-    if !user_exists(&caller_id) {
-        return "User must be registered to share posts".to_string();
+    #[test]
+    fn direct_messages_keep_an_inbox_by_activity_with_unread_counts() {
+        register(1, "alice");
+        register(2, "bob");
+        register(3, "carol");
+        assert!(matches!(send_message(principal(3), "me".to_string()), Err(ApiError::Validation { .. })));
+        call_as(2);
+        assert!(matches!(send_message(principal(3), "  ".to_string()), Err(ApiError::Validation { .. })));
+        send_message(principal(3), "hi".to_string()).unwrap();
+        let hi_alice = send_message(principal(1), "hi alice".to_string()).unwrap();
+        let with_alice = hi_alice.conversation_id;
+        let with_carol = send_message(principal(3), "still there?".to_string()).unwrap().conversation_id;
+
+        // Alice's reply moves her conversation with Bob back to the top
+        call_as(1);
+        advance_clock(1);
+        let reply = send_message(principal(2), "hello bob".to_string()).unwrap();
+        assert_eq!(reply.conversation_id, with_alice);
+        call_as(2);
+        let first = get_conversations(None, 1).unwrap();
+        assert_eq!(conversation_ids(&first), vec![with_alice]);
+        assert_eq!(first.items[0].unread_count, 1);
+        assert_eq!(first.items[0].last_message.as_ref().map(|message| message.message_id), Some(reply.message_id));
+        let second = get_conversations(first.next_cursor, 1).unwrap();
+        assert_eq!(conversation_ids(&second), vec![with_carol]);
+        assert_eq!(second.items[0].unread_count, 0);
+        assert_eq!(second.next_cursor, None);
+
+        // Only participants get in
+        call_as(3);
+        assert!(matches!(get_messages(with_alice, None, 10), Err(ApiError::Unauthorized { .. })));
+        assert!(matches!(mark_conversation_read(with_alice), Err(ApiError::Unauthorized { .. })));
+
+        // Deleting an unread message takes it off the count and the inbox preview
+        call_as(1);
+        assert!(matches!(delete_message(with_alice, hi_alice.message_id), Err(ApiError::Unauthorized { .. })));
+        assert_eq!(delete_message(with_alice, reply.message_id), Ok(()));
+        assert_eq!(delete_message(with_alice, reply.message_id), Err(ApiError::Deleted { resource: "Message".to_string() }));
+        call_as(2);
+        let summary = &get_conversations(None, 1).unwrap().items[0];
+        assert_eq!(summary.unread_count, 0);
+        assert_eq!(summary.last_message.as_ref().map(|message| message.content.as_str()), Some("hi alice"));
+        let messages = get_messages(with_alice, None, 10).unwrap();
+        assert_eq!(messages.items.iter().map(|message| message.content.as_str()).collect::<Vec<_>>(), vec!["hi alice"]);
+
+        call_as(1);
+        send_message(principal(2), "again".to_string()).unwrap();
+        call_as(2);
+        assert_eq!(get_conversations(None, 1).unwrap().items[0].unread_count, 1);
+        assert_eq!(mark_conversation_read(with_alice), Ok(()));
+        assert_eq!(get_conversations(None, 1).unwrap().items[0].unread_count, 0);
+        assert_eq!(get_read_receipts(with_alice).unwrap().len(), 2);
     }
 
-    // Check if post exists and is not deleted
-    {
-        let posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get(&post_id) {
-            if post.is_deleted {
-                return "Cannot share deleted post".to_string();
+    #[test]
+    fn leaving_users_take_their_messages_with_them() {
+        register(1, "alice");
+        register(2, "bob");
+        let conversation_id = send_message(principal(1), "hi".to_string()).unwrap().conversation_id;
+        send_message(principal(1), "there".to_string()).unwrap();
+
+        // The messages are part of Bob's data export
+        let mut cursor = None;
+        let mut exported = Vec::new();
+        loop {
+            let chunk = export_my_data(cursor, 1).unwrap();
+            exported.extend(chunk.messages.into_iter().map(|message| message.content));
+            cursor = chunk.next_cursor;
+            if cursor.is_none() {
+                break;
             }
-        } else {
-            return "Post not found".to_string();
         }
+        assert_eq!(exported, vec!["hi".to_string(), "there".to_string()]);
+
+        delete_user_v2().unwrap();
+        call_as(1);
+        let page = get_conversations(None, 10).unwrap();
+        assert_eq!(conversation_ids(&page), vec![conversation_id]);
+        let summary = &page.items[0];
+        assert_eq!(summary.conversation.participants, vec![principal(1)]);
+        assert_eq!(summary.unread_count, 0);
+        assert!(summary.last_message.is_none());
+        assert!(get_messages(conversation_id, None, 10).unwrap().items.is_empty());
+
+        // A new account under the same principal starts a new conversation
+        register(2, "bob");
+        let new_conversation = send_message(principal(1), "back".to_string()).unwrap().conversation_id;
+        assert_ne!(new_conversation, conversation_id);
     }
 
-    // Track the share
-    {
-        let mut user_shares = USER_SHARES.lock().unwrap();
-        let user_shared_posts = user_shares.entry(caller_id).or_insert_with(HashSet::new);
-        user_shared_posts.insert(post_id);
+    #[test]
+    fn group_owners_manage_members_up_to_the_limit() {
+        register(1, "alice");
+        register(2, "bob");
+        register(3, "carol");
+        register(4, "dave");
+        admin_caller();
+        assert!(matches!(admin_set_max_group_members(1), Err(ApiError::Validation { .. })));
+        assert_eq!(admin_set_max_group_members(3), Ok(()));
+
+        call_as(1);
+        assert!(matches!(create_group_conversation(" ".to_string(), Vec::new()), Err(ApiError::Validation { .. })));
+        let group = create_group_conversation("team".to_string(), vec![principal(2), principal(2)]).unwrap();
+        let group_id = group.conversation_id;
+        assert_eq!(group.participants, vec![principal(1), principal(2)]);
+        send_conversation_message(group_id, "welcome".to_string()).unwrap();
+
+        // Only the owner manages members, and only up to the limit
+        call_as(2);
+        assert!(matches!(invite_to_conversation(group_id, principal(3)), Err(ApiError::Unauthorized { .. })));
+        call_as(1);
+        assert_eq!(invite_to_conversation(group_id, principal(3)), Ok(()));
+        assert_eq!(invite_to_conversation(group_id, principal(3)), Err(ApiError::AlreadyExists { resource: "Member".to_string() }));
+        assert!(matches!(invite_to_conversation(group_id, principal(4)), Err(ApiError::Validation { .. })));
+
+        // New members read the history but have nothing unread
+        call_as(3);
+        assert_eq!(get_messages(group_id, None, 10).unwrap().items.len(), 1);
+        assert_eq!(get_conversations(None, 10).unwrap().items[0].unread_count, 0);
+        call_as(2);
+        assert_eq!(get_conversations(None, 10).unwrap().items[0].unread_count, 1);
+
+        call_as(1);
+        assert!(matches!(remove_from_conversation(group_id, principal(1)), Err(ApiError::Validation { .. })));
+        assert_eq!(remove_from_conversation(group_id, principal(2)), Ok(()));
+        call_as(2);
+        assert!(matches!(get_messages(group_id, None, 10), Err(ApiError::Unauthorized { .. })));
+        assert!(matches!(send_conversation_message(group_id, "hi".to_string()), Err(ApiError::Unauthorized { .. })));
+        assert!(get_conversations(None, 10).unwrap().items.is_empty());
+
+        // The owner leaving hands the group to the longest-standing member
+        call_as(1);
+        assert_eq!(leave_conversation(group_id), Ok(()));
+        call_as(3);
+        let summary = &get_conversations(None, 10).unwrap().items[0];
+        assert_eq!(summary.conversation.owner_id, Some(principal(3)));
+        assert_eq!(summary.conversation.participants, vec![principal(3)]);
+        assert_eq!(invite_to_conversation(group_id, principal(4)), Ok(()));
     }
 
-    // Increment share count
-    {
-        let mut posts = POSTS.lock().unwrap();
-        if let Some(post) = posts.get_mut(&post_id) {
-            post.shares_count += 1;
-        }
+    #[test]
+    fn direct_conversations_refuse_group_operations() {
+        register(1, "alice");
+        register(2, "bob");
+        let conversation_id = send_message(principal(1), "hi".to_string()).unwrap().conversation_id;
+        assert!(matches!(leave_conversation(conversation_id), Err(ApiError::Validation { .. })));
+        assert!(matches!(invite_to_conversation(conversation_id, principal(2)), Err(ApiError::Validation { .. })));
+        assert!(send_conversation_message(conversation_id, "again".to_string()).is_ok());
+
+        delete_user_v2().unwrap();
+        call_as(1);
+        assert!(matches!(send_conversation_message(conversation_id, "hello?".to_string()), Err(ApiError::Conflict { .. })));
     }
 
-    "Post shared successfully".to_string()
-}
+    #[test]
+    fn replies_nest_up_to_the_depth_limit_and_page_by_thread() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let first_id = create_comment_v2(post_id, "first".to_string()).unwrap();
+        let second_id = create_comment_v2(post_id, "second".to_string()).unwrap();
+
+        register(2, "bob");
+        let reply_ids: Vec<u64> = (0..4).map(|n| reply_to_comment(first_id, format!("reply {}", n)).unwrap()).collect();
+        assert_eq!(load_comment(first_id).unwrap().reply_count, 4);
+        assert_eq!(get_post(post_id).unwrap().comments_count, 6);
+
+        // Each reply goes one level deeper, up to MAX_COMMENT_DEPTH
+        let mut parent_id = reply_ids[0];
+        for _ in 1..MAX_COMMENT_DEPTH {
+            parent_id = reply_to_comment(parent_id, "deeper".to_string()).unwrap();
+        }
+        assert_eq!(load_comment(parent_id).unwrap().depth, MAX_COMMENT_DEPTH);
+        assert!(matches!(reply_to_comment(parent_id, "too deep".to_string()), Err(ApiError::Validation { .. })));
+
+        // Threads list top-level comments only, each with its oldest replies inlined
+        let page = get_post_comment_threads(post_id, None, 1).unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].comment.comment_id, first_id);
+        let inlined: Vec<u64> = page.items[0].replies.iter().map(|reply| reply.comment_id).collect();
+        assert_eq!(inlined, reply_ids[..INLINED_REPLIES].to_vec());
+        let page = get_post_comment_threads(post_id, page.next_cursor, 1).unwrap();
+        assert_eq!(page.items[0].comment.comment_id, second_id);
+        assert!(page.items[0].replies.is_empty());
+        assert!(page.next_cursor.is_none());
+
+        let page = get_comment_replies(first_id, None, 3).unwrap();
+        assert_eq!(page.items.iter().map(|reply| reply.comment_id).collect::<Vec<_>>(), reply_ids[..3].to_vec());
+        let page = get_comment_replies(first_id, page.next_cursor, 3).unwrap();
+        assert_eq!(page.items.iter().map(|reply| reply.comment_id).collect::<Vec<_>>(), reply_ids[3..].to_vec());
+        assert!(page.next_cursor.is_none());
+
+        // Reply counts follow the replies that go and come back with their post
+        delete_comment_v2(reply_ids[3]).unwrap();
+        advance_clock(1);
+        call_as(1);
+        delete_post_v2(post_id).unwrap();
+        assert_eq!(load_comment(first_id).unwrap().reply_count, 0);
+        restore_post(post_id).unwrap();
+        assert_eq!(load_comment(first_id).unwrap().reply_count, 3);
+
+        admin_caller();
+        assert!(run_integrity(false).is_empty());
+    }
 
-// ============ COMMENT LIKE FUNCTIONS ============
+    #[test]
+    fn deleted_comments_with_replies_stay_as_placeholders_until_purged() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let parent_id = create_comment_v2(post_id, "parent".to_string()).unwrap();
+        let kept_parent_id = create_comment_v2(post_id, "kept parent".to_string()).unwrap();
+        register(2, "bob");
+        let reply_id = reply_to_comment(parent_id, "reply".to_string()).unwrap();
+        let kept_reply_id = reply_to_comment(kept_parent_id, "kept reply".to_string()).unwrap();
+
+        call_as(1);
+        delete_comment_v2(parent_id).unwrap();
+        delete_comment_v2(kept_parent_id).unwrap();
+        let threads = get_post_comment_threads(post_id, None, 10).unwrap();
+        assert_eq!(threads.items.len(), 2);
+        let placeholder = &threads.items[0].comment;
+        assert_eq!(placeholder.comment_id, parent_id);
+        assert_eq!(placeholder.content, "[deleted]");
+        assert_eq!(placeholder.author_id, Principal::anonymous());
+        assert_eq!(placeholder.deleted_at, None);
+        assert_eq!(threads.items[0].replies[0].comment_id, reply_id);
+
+        // A deleted reply still keeps its deleted parent in place until it is purged
+        call_as(2);
+        delete_comment_v2(reply_id).unwrap();
+        assert_eq!(load_comment(parent_id).unwrap().reply_count, 0);
+        let threads = get_post_comment_threads(post_id, None, 10).unwrap();
+        assert_eq!(threads.items.len(), 2);
+        assert!(threads.items[0].replies.is_empty());
+
+        // The purge skips parents with replies left, and takes a parent once its last reply is gone
+        advance_clock(DEFAULT_TRASH_RETENTION_NS + 1);
+        purge_trash();
+        assert!(load_comment(reply_id).is_none());
+        assert!(load_comment(parent_id).is_some());
+        purge_trash();
+        assert!(load_comment(parent_id).is_none());
+        assert!(load_comment(kept_parent_id).is_some());
+        let threads = get_post_comment_threads(post_id, None, 10).unwrap();
+        assert_eq!(threads.items.len(), 1);
+        assert_eq!(threads.items[0].comment.comment_id, kept_parent_id);
+        assert_eq!(threads.items[0].replies[0].comment_id, kept_reply_id);
+
+        admin_caller();
+        assert!(run_integrity(false).is_empty());
+    }
 
-#[ic_cdk::update]
-fn like_comment(comment_id: u64) -> String {
-    let caller_id = caller();
+    // The (kind, count) pairs of a reaction summary
+    fn reaction_counts(reactions: &[ReactionCount]) -> Vec<(&str, u64)> {
+        reactions.iter().map(|count| (count.kind.as_str(), count.count)).collect()
+    }
 
-    // Check if user exists
-    if !user_exists(&caller_id) {
-        return "User must be registered to like comments".to_string();
+    #[test]
+    fn reactions_replace_each_other_and_only_likes_notify() {
+        seed_reaction_kinds();
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "hi".to_string()).unwrap();
+
+        register(2, "bob");
+        let post = ReactionTarget::Post(post_id);
+        assert_eq!(react(post, "love".to_string()), Ok(()));
+        assert_eq!(react(post, "love".to_string()), Err(ApiError::AlreadyExists { resource: "Reaction".to_string() }));
+        assert!(matches!(react(post, "meh".to_string()), Err(ApiError::Validation { .. })));
+        let stored = load_post(post_id).unwrap();
+        assert_eq!(reaction_counts(&stored.reactions), vec![("love", 1)]);
+        assert_eq!(stored.likes, 0);
+
+        // A like replaces the previous reaction and is the only one notified
+        like_post_v2(post_id).unwrap();
+        assert_eq!(get_my_reaction(post), Some(LIKE_REACTION.to_string()));
+        let stored = load_post(post_id).unwrap();
+        assert_eq!(reaction_counts(&stored.reactions), vec![("like", 1)]);
+        assert_eq!(stored.likes, 1);
+        react(ReactionTarget::Comment(comment_id), "laugh".to_string()).unwrap();
+        like_comment_v2(comment_id).unwrap();
+        assert_eq!(load_comment(comment_id).unwrap().likes, 1);
+
+        register(3, "carol");
+        react(post, "wow".to_string()).unwrap();
+        assert_eq!(unreact(post), Ok(()));
+        assert_eq!(unreact(post), Err(ApiError::NotFound { resource: "Reaction".to_string() }));
+        react(post, "like".to_string()).unwrap();
+        let page = get_reactions(post, LIKE_REACTION.to_string(), None, 1).unwrap();
+        let mut likers = page.items.clone();
+        likers.extend(get_reactions(post, LIKE_REACTION.to_string(), page.next_cursor, 1).unwrap().items);
+        let mut expected = vec![principal(2), principal(3)];
+        expected.sort_by_key(|user_id| PrincipalKey(*user_id));
+        assert_eq!(likers, expected);
+
+        call_as(1);
+        let page = get_notifications(None, 10).unwrap();
+        let groups: Vec<(NotificationKind, Option<u64>, u64)> =
+            page.items.iter().map(|group| (group.kind, group.comment_id, group.actor_count)).collect();
+        assert!(groups == vec![(NotificationKind::Like, None, 2), (NotificationKind::CommentLike, Some(comment_id), 1)]);
+
+        // Kinds are managed by admins, and likes always stay available
+        assert!(matches!(admin_add_reaction_kind("party".to_string()), Err(ApiError::Unauthorized { .. })));
+        admin_caller();
+        assert_eq!(admin_add_reaction_kind("party".to_string()), Ok(()));
+        assert!(matches!(admin_add_reaction_kind("two words".to_string()), Err(ApiError::Validation { .. })));
+        assert_eq!(admin_remove_reaction_kind("love".to_string()), Ok(()));
+        assert!(matches!(admin_remove_reaction_kind(LIKE_REACTION.to_string()), Err(ApiError::Validation { .. })));
+        assert_eq!(get_reaction_kinds(), vec!["angry", "laugh", "like", "party", "sad", "wow"]);
+        assert!(run_integrity(false).is_empty());
     }
 
-    // Check if comment exists and is not deleted
-    {
-        let comments = COMMENTS.lock().unwrap();
-        if let Some(comment) = comments.get(&comment_id) {
-            if comment.is_deleted {
-                return "Cannot like deleted comment".to_string();
-            }
-        } else {
-            return "Comment not found".to_string();
-        }
+    #[test]
+    fn deleting_a_post_withdraws_the_reactions_on_it_and_its_comments() {
+        seed_reaction_kinds();
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "hi".to_string()).unwrap();
+        let parent_id = create_comment_v2(post_id, "parent".to_string()).unwrap();
+        register(2, "bob");
+        reply_to_comment(parent_id, "reply".to_string()).unwrap();
+        react(ReactionTarget::Post(post_id), "love".to_string()).unwrap();
+        react(ReactionTarget::Comment(comment_id), "wow".to_string()).unwrap();
+        like_comment_v2(parent_id).unwrap();
+
+        // A deleted comment shown as a placeholder hides its reactions
+        call_as(1);
+        delete_comment_v2(parent_id).unwrap();
+        let placeholder = get_post_comment_threads(post_id, None, 10).unwrap().items.remove(1).comment;
+        assert_eq!(placeholder.comment_id, parent_id);
+        assert!(placeholder.reactions.is_empty());
+
+        delete_post_v2(post_id).unwrap();
+        call_as(2);
+        assert_eq!(get_my_reaction(ReactionTarget::Post(post_id)), None);
+        assert_eq!(get_my_reaction(ReactionTarget::Comment(comment_id)), None);
+        assert!(user_reaction_targets(principal(2)).contains(&ReactionTarget::Comment(parent_id)));
+        assert!(load_comment(comment_id).unwrap().reactions.is_empty());
+        assert!(load_post(post_id).unwrap().reactions.is_empty());
+
+        admin_caller();
+        assert!(run_integrity(false).is_empty());
     }
 
-    // Check if user already liked this comment
-    {
-        let mut user_comment_likes = USER_COMMENT_LIKES.lock().unwrap();
-        let user_liked_comments = user_comment_likes.entry(caller_id).or_insert_with(HashSet::new);
+    #[test]
+    fn reposts_are_counted_once_and_can_be_undone() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
 
-        if user_liked_comments.contains(&comment_id) {
-            return "You have already liked this comment".to_string();
-        }
+        register(2, "bob");
+        assert_eq!(repost(post_id), Ok(()));
+        assert_eq!(share_post_v2(post_id), Err(ApiError::AlreadyExists { resource: "Repost".to_string() }));
+        assert_eq!(load_post(post_id).unwrap().shares_count, 1);
 
-        user_liked_comments.insert(comment_id);
+        assert_eq!(undo_repost(post_id), Ok(()));
+        assert_eq!(undo_repost(post_id), Err(ApiError::NotFound { resource: "Repost".to_string() }));
+        assert_eq!(load_post(post_id).unwrap().shares_count, 0);
+        assert!(get_user_timeline(principal(2), None, 10).unwrap().items.is_empty());
     }
 
-    // Increment like count
-    {
-        let mut comments = COMMENTS.lock().unwrap();
-        if let Some(comment) = comments.get_mut(&comment_id) {
-            comment.likes += 1;
+    #[test]
+    fn feeds_list_reposts_at_the_time_they_were_made() {
+        register(1, "alice");
+        let old_post = create_post_v2("old".to_string(), Vec::new(), Vec::new()).unwrap();
+        advance_clock(1);
+        register(2, "bob");
+        let bob_post = create_post_v2("bob".to_string(), Vec::new(), Vec::new()).unwrap();
+        advance_clock(1);
+        repost(old_post).unwrap();
+        advance_clock(1);
+        let newer_post = create_post_v2("newer".to_string(), Vec::new(), Vec::new()).unwrap();
+
+        register(3, "carol");
+        follow_user_v2(principal(2)).unwrap();
+        follow_user_v2(principal(1)).unwrap();
+
+        // One entry per page, so every cursor is checked
+        let mut entries = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = get_user_feed_entries(cursor, 1).unwrap();
+            entries.extend(page.items.into_iter().map(|entry| (entry.post.post_id, entry.reposted_by)));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
         }
+        assert_eq!(entries, vec![
+            (newer_post, None),
+            (old_post, Some(principal(2))),
+            (bob_post, None),
+            (old_post, None),
+        ]);
+
+        // The legacy feed lists each post once, at its newest entry
+        let feed: Vec<u64> = get_user_feed().iter().map(|post| post.post_id).collect();
+        assert_eq!(feed, vec![newer_post, old_post, bob_post]);
+
+        let timeline: Vec<(u64, Option<Principal>)> = get_user_timeline(principal(2), None, 10).unwrap().items.into_iter()
+            .map(|entry| (entry.post.post_id, entry.reposted_by))
+            .collect();
+        assert_eq!(timeline, vec![(newer_post, None), (old_post, Some(principal(2))), (bob_post, None)]);
+
+        // Deleting the original drops its reposts
+        call_as(1);
+        delete_post_v2(old_post).unwrap();
+        let timeline = get_user_timeline(principal(2), None, 10).unwrap();
+        assert_eq!(timeline.items.len(), 2);
+        assert!(item_user_ids(&POST_SHARERS, old_post).is_empty());
     }
 
-    "Comment liked successfully".to_string()
-}
-
-#[ic_cdk::update]
-fn unlike_comment(comment_id: u64) -> String {
-    let caller_id = caller();
-
-    // Check if user exists
-    if !user_exists(&caller_id) {
-        return "User must be registered to unlike comments".to_string();
+    #[test]
+    fn quote_posts_are_indexed_by_the_quoted_post() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        let other_id = create_post_v2("other".to_string(), Vec::new(), Vec::new()).unwrap();
+
+        register(2, "bob");
+        let first = quote_post(post_id, "first".to_string(), Vec::new(), Vec::new()).unwrap();
+        let second = quote_post(post_id, "second".to_string(), Vec::new(), Vec::new()).unwrap();
+        quote_post(other_id, "elsewhere".to_string(), Vec::new(), Vec::new()).unwrap();
+        assert_eq!(load_post(first).unwrap().quoted_post_id, Some(post_id));
+
+        let page = get_quote_posts(post_id, None, 1).unwrap();
+        assert_eq!(page.items.iter().map(|post| post.post_id).collect::<Vec<_>>(), vec![second]);
+        let page = get_quote_posts(post_id, page.next_cursor, 1).unwrap();
+        assert_eq!(page.items.iter().map(|post| post.post_id).collect::<Vec<_>>(), vec![first]);
+        assert!(page.next_cursor.is_none());
+
+        // Deleted quotes drop out of the index, and deleted posts can't be quoted
+        delete_post_v2(second).unwrap();
+        assert_eq!(get_quote_posts(post_id, None, 10).unwrap().items.len(), 1);
+        assert!(matches!(quote_post(second, "gone".to_string(), Vec::new(), Vec::new()), Err(ApiError::Deleted { .. })));
+
+        call_as(1);
+        let kinds: Vec<NotificationKind> = get_notifications(None, 10).unwrap().items.iter().map(|group| group.kind).collect();
+        assert!(kinds.contains(&NotificationKind::Share));
     }
 
-    // Check if user has liked this comment
-    {
-        let mut user_comment_likes = USER_COMMENT_LIKES.lock().unwrap();
-        if let Some(user_liked_comments) = user_comment_likes.get_mut(&caller_id) {
-            if !user_liked_comments.contains(&comment_id) {
-                return "You haven't liked this comment".to_string();
-            }
+    #[test]
+    fn bookmarks_page_by_collection_and_stay_private() {
+        register(1, "alice");
+        let posts: Vec<u64> = (0..3).map(|n| create_post_v2(format!("post {}", n), Vec::new(), Vec::new()).unwrap()).collect();
 
-            user_liked_comments.remove(&comment_id);
-        } else {
-            return "You haven't liked this comment".to_string();
+        register(2, "bob");
+        for (n, post_id) in posts.iter().enumerate() {
+            let collection = (n > 0).then(|| " reading ".to_string());
+            bookmark_post(*post_id, collection).unwrap();
+            advance_clock(1);
         }
+        assert_eq!(bookmark_post(posts[1], Some("reading".to_string())), Err(ApiError::AlreadyExists { resource: "Bookmark".to_string() }));
+        assert!(matches!(bookmark_post(posts[1], Some(" ".to_string())), Err(ApiError::Validation { .. })));
+        assert!(is_bookmarked(posts[0]));
+
+        let first = get_bookmarks(None, None, 2).unwrap();
+        assert_eq!(post_ids(&first), vec![posts[2], posts[1]]);
+        let rest = get_bookmarks(None, first.next_cursor, 2).unwrap();
+        assert_eq!(post_ids(&rest), vec![posts[0]]);
+        assert_eq!(rest.next_cursor, None);
+        assert_eq!(post_ids(&get_bookmarks(Some("reading".to_string()), None, 10).unwrap()), vec![posts[2], posts[1]]);
+        assert!(matches!(get_bookmarks(Some("nope".to_string()), None, 10), Err(ApiError::NotFound { .. })));
+
+        // Moving a bookmark keeps its place in the list
+        bookmark_post(posts[1], None).unwrap();
+        assert_eq!(post_ids(&get_bookmarks(None, None, 10).unwrap()), vec![posts[2], posts[1], posts[0]]);
+        let collections: Vec<(String, u64)> = get_bookmark_collections().into_iter()
+            .map(|collection| (collection.name, collection.bookmark_count))
+            .collect();
+        assert_eq!(collections, vec![("reading".to_string(), 1)]);
+
+        // Deleting a collection keeps its bookmarks
+        assert_eq!(delete_bookmark_collection("reading".to_string()), Ok(()));
+        assert!(get_bookmark_collections().is_empty());
+        assert_eq!(get_bookmarks(None, None, 10).unwrap().items.len(), 3);
+
+        // Other users see none of it
+        call_as(1);
+        assert!(!is_bookmarked(posts[0]));
+        assert!(get_bookmarks(None, None, 10).unwrap().items.is_empty());
+
+        call_as(2);
+        assert_eq!(remove_bookmark(posts[0]), Ok(()));
+        assert_eq!(remove_bookmark(posts[0]), Err(ApiError::NotFound { resource: "Bookmark".to_string() }));
+        let export = export_my_data(Some((EXPORT_SECTIONS.len() - 1).to_string()), 10).unwrap();
+        assert_eq!(export.bookmarks.iter().map(|bookmark| bookmark.post_id).collect::<Vec<_>>(), vec![posts[1], posts[2]]);
     }
 
-    // Decrement like count
-    {
-        let mut comments = COMMENTS.lock().unwrap();
-        if let Some(comment) = comments.get_mut(&comment_id) {
-            if comment.likes > 0 {
-                comment.likes -= 1;
-            }
-        }
+    #[test]
+    fn bookmarks_go_with_purged_posts_and_deleted_users() {
+        register(1, "alice");
+        let post_id = create_post_v2("hello".to_string(), Vec::new(), Vec::new()).unwrap();
+        register(2, "bob");
+        bookmark_post(post_id, Some("later".to_string())).unwrap();
+        register(3, "carol");
+        bookmark_post(post_id, None).unwrap();
+
+        // A deleted post is hidden but keeps its bookmarks until it is purged
+        call_as(1);
+        delete_post_v2(post_id).unwrap();
+        call_as(3);
+        assert!(get_bookmarks(None, None, 10).unwrap().items.is_empty());
+        advance_clock(DEFAULT_TRASH_RETENTION_NS + 1);
+        purge_trash();
+        assert!(!is_bookmarked(post_id));
+        assert!(item_user_ids(&POST_BOOKMARKERS, post_id).is_empty());
+
+        call_as(2);
+        assert_eq!(get_bookmark_collections().len(), 1);
+        delete_user_v2().unwrap();
+        assert!(collections_of(principal(2)).is_empty());
     }
 
-    "Comment unliked successfully".to_string()
+    #[test]
+    fn edits_keep_revisions_in_order() {
+        register(1, "alice");
+        let post_id = create_post_v2("first".to_string(), Vec::new(), Vec::new()).unwrap();
+        let comment_id = create_comment_v2(post_id, "hi".to_string()).unwrap();
+        advance_clock(1);
+        update_post_v2(post_id, "second".to_string()).unwrap();
+        advance_clock(1);
+        update_post_v2(post_id, "third".to_string()).unwrap();
+        update_comment_v2(comment_id, "hello".to_string()).unwrap();
+
+        let revisions = get_post_revisions(post_id).unwrap();
+        let contents: Vec<&str> = revisions.iter().map(|revision| revision.content.as_str()).collect();
+        assert_eq!(contents, vec!["first", "second"]);
+        assert_eq!(revisions[1].written_at, revisions[0].replaced_at);
+        assert_eq!(get_post(post_id).unwrap().content, "third");
+        assert_eq!(get_comment_revisions(comment_id).unwrap()[0].content, "hi");
+
+        // Private history is left to the author and admins
+        admin_caller();
+        admin_set_edit_policy(EditPolicy { revisions_public: false, edit_window_ns: None }).unwrap();
+        register(2, "bob");
+        assert!(matches!(get_post_revisions(post_id), Err(ApiError::Unauthorized { .. })));
+        call_as(1);
+        assert_eq!(get_post_revisions(post_id).unwrap().len(), 2);
+
+        // Purging the post drops its history
+        delete_post_v2(post_id).unwrap();
+        advance_clock(DEFAULT_TRASH_RETENTION_NS + 1);
+        purge_trash();
+        assert!(revisions_of(&POST_REVISIONS, post_id).is_empty());
+        assert!(revisions_of(&COMMENT_REVISIONS, comment_id).is_empty());
+    }
+
+    #[test]
+    fn the_edit_window_applies_to_admins_too() {
+        admin_caller();
+        assert!(matches!(admin_set_edit_policy(EditPolicy { revisions_public: true, edit_window_ns: Some(0) }), Err(ApiError::Validation { .. })));
+        admin_set_edit_policy(EditPolicy { revisions_public: true, edit_window_ns: Some(10) }).unwrap();
+
+        register(1, "alice");
+        let post_id = create_post_v2("first".to_string(), Vec::new(), Vec::new()).unwrap();
+        advance_clock(10);
+        assert_eq!(update_post_v2(post_id, "in time".to_string()), Ok(()));
+        advance_clock(1);
+        assert!(matches!(update_post_v2(post_id, "too late".to_string()), Err(ApiError::Unauthorized { .. })));
+
+        admin_caller();
+        assert!(matches!(update_post_v2(post_id, "admin edit".to_string()), Err(ApiError::Unauthorized { .. })));
+        assert_eq!(get_post_revisions(post_id).unwrap().len(), 1);
+    }
 }
+